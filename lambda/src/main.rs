@@ -3,6 +3,8 @@ extern crate lambda_runtime;
 use std::error::Error;
 use scrappybotlib::bot;
 use scrappybotlib::bot::BotStats;
+use scrappybotlib::config::{self, Config};
+use scrappybotlib::http::HttpClients;
 
 use lambda_runtime::{error::HandlerError, lambda, Context};
 use serde::{Deserialize, Serialize};
@@ -10,12 +12,19 @@ use serde::{Deserialize, Serialize};
 
 #[derive(Serialize, Deserialize)]
 struct CustomEvent {
+    /// Send the due reminders instead of running a cycle, e.g. from a
+    /// separate hourly rule with the `{"remind": true}` input.
+    #[serde(default)]
+    remind: bool,
 }
 
 #[derive(Serialize, Deserialize)]
 struct CustomOutput {
     changed_records: usize,
     new_records: usize,
+    removed_records: usize,
+    #[serde(default)]
+    due_reminders: usize,
     message: String,
 }
 
@@ -39,12 +48,27 @@ pub fn block_on<F: std::future::Future>(future: F) -> F::Output {
 
 
 fn my_handler(e: CustomEvent, c: Context) -> Result<CustomOutput, HandlerError> {
-    let bot_results:  Result<BotStats, Box<dyn std::error::Error>>  = block_on(bot::run());
+    let config_path = std::env::var("SCRAPPYBOT_CONFIG").unwrap_or(config::DEFAULT_PATH.to_string());
+    let config = Config::load(&config_path).map_err(|e| HandlerError::from(&e.to_string()[..]))?;
+    if e.remind {
+        let due = block_on(bot::remind(&config, &HttpClients::new(&config.http), &bot::RunOptions::default()))
+            .map_err(|error| HandlerError::from(&error.to_string()[..]))?;
+        return Ok(CustomOutput {
+            changed_records: 0,
+            new_records: 0,
+            removed_records: 0,
+            due_reminders: due,
+            message: "Success".to_string(),
+        });
+    }
+    let bot_results:  Result<BotStats, Box<dyn std::error::Error>>  = block_on(bot::run(&config, &HttpClients::new(&config.http), &bot::RunOptions::default()));
     match bot_results {
         Ok(stats) => {
             Ok(CustomOutput {
                 changed_records: stats.changed,
                 new_records: stats.added,
+                removed_records: stats.removed,
+                due_reminders: 0,
                 message: "Success".to_string(),
             })
         },
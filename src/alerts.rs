@@ -0,0 +1,369 @@
+//! Alerts saying what happened to a new or changed listing, e.g. a price
+//! drop or bids opening, instead of a bare "Modified".
+
+use crate::filter::{Field, Filterable, Value};
+use crate::history::{format_price, HistoryItem, ListingTimeline};
+use crate::scrapes::hudhomestore::ListingItem;
+use crate::state::IdChecksum;
+use chrono::NaiveDate;
+use core::fmt::Display;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority {
+    Low,
+    Normal,
+    High,
+}
+
+impl Default for Priority {
+    fn default() -> Self {
+        Priority::Normal
+    }
+}
+
+impl Display for Priority {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Priority::Low => "low",
+            Priority::Normal => "normal",
+            Priority::High => "high",
+        };
+        write!(fmt, "{}", name)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum AlertKind {
+    /// Price fell by at least `percent` or at least `amount` dollars, by any
+    /// amount when neither is set.
+    PriceDrop {
+        #[serde(default)]
+        percent: Option<f64>,
+        #[serde(default)]
+        amount: Option<u64>,
+    },
+    /// Status changed to one of `to`, e.g. `Bids Open` or `Price Reduced`.
+    Status { to: Vec<String> },
+    /// Bids close within `within_days` days.
+    BidDeadline { within_days: i64 },
+    /// Listing came back after it was removed.
+    Relisted,
+}
+
+impl AlertKind {
+    fn default_template(&self) -> &'static str {
+        match self {
+            AlertKind::PriceDrop { .. } => "Price dropped by {drop} ({drop_percent}%) to {price}",
+            AlertKind::Status { .. } => "Status changed from {old_status} to {status}",
+            AlertKind::BidDeadline { .. } => "Bids close in {days} day(s), on {deadline}",
+            AlertKind::Relisted => "Relisted at {price}",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct AlertRule {
+    #[serde(flatten)]
+    pub kind: AlertKind,
+    #[serde(default)]
+    pub priority: Priority,
+    /// Message replacing the default one of the rule. Placeholders:
+    /// `{case_number}`, `{address}`, `{price}`, `{old_price}`, `{drop}`,
+    /// `{drop_percent}`, `{status}`, `{old_status}`, `{deadline}`, `{days}`.
+    #[serde(default)]
+    pub template: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Alert {
+    pub priority: Priority,
+    pub message: String,
+}
+
+impl Display for Alert {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(fmt, "[{}] {}", self.priority, self.message)
+    }
+}
+
+/// What the rules look at, the previous values are those of the version
+/// replaced in the latest scrape.
+struct Facts {
+    case_number: String,
+    address: Option<String>,
+    price: Option<u64>,
+    old_price: Option<u64>,
+    status: Option<String>,
+    old_status: Option<String>,
+    deadline: Option<NaiveDate>,
+    days: Option<i64>,
+    relisted: bool,
+}
+
+fn text(item: &ListingItem, field: Field) -> Option<String> {
+    match item.value(field) {
+        Some(Value::Text(text)) => Some(text),
+        _ => None,
+    }
+}
+
+impl Facts {
+    fn new(item: &ListingItem, timeline: Option<&ListingTimeline>, today: NaiveDate) -> Self {
+        let (_, checksum) = item.id_checksum();
+        let previous = timeline.and_then(|timeline| {
+            let latest = timeline.latest();
+            let changed_now = latest.checksum == checksum && latest.seen_at == timeline.last_seen;
+            if changed_now && timeline.versions.len() > 1 {
+                let previous = &timeline.versions[timeline.versions.len() - 2];
+                Some(ListingItem::from(previous.fields.clone()))
+            } else {
+                None
+            }
+        });
+        let deadline = item.bid_deadline().map(|deadline| deadline.date());
+
+        Facts {
+            case_number: item.id.clone(),
+            address: text(item, Field::Address),
+            price: item.price(),
+            old_price: previous.as_ref().and_then(|previous| previous.price()),
+            status: text(item, Field::Status),
+            old_status: previous
+                .as_ref()
+                .and_then(|previous| text(previous, Field::Status)),
+            deadline: deadline,
+            days: deadline.map(|deadline| (deadline - today).num_days()),
+            relisted: timeline.map_or(false, |timeline| {
+                timeline.relisted_at.last() == Some(&timeline.last_seen)
+            }),
+        }
+    }
+
+    fn price_drop(&self) -> Option<(u64, f64)> {
+        match (self.old_price, self.price) {
+            (Some(old), Some(new)) if old > new => {
+                let drop = old - new;
+                Some((drop, drop as f64 * 100.0 / old as f64))
+            }
+            _ => None,
+        }
+    }
+
+    fn triggers(&self, kind: &AlertKind) -> bool {
+        match kind {
+            AlertKind::PriceDrop { percent, amount } => match self.price_drop() {
+                Some((drop, drop_percent)) => match (percent, amount) {
+                    (None, None) => true,
+                    _ => {
+                        percent.map_or(false, |percent| drop_percent >= percent)
+                            || amount.map_or(false, |amount| drop >= amount)
+                    }
+                },
+                None => false,
+            },
+            AlertKind::Status { to } => match (&self.status, &self.old_status) {
+                (Some(status), Some(old_status)) => {
+                    !status.eq_ignore_ascii_case(old_status)
+                        && to.iter().any(|to| status.eq_ignore_ascii_case(to))
+                }
+                _ => false,
+            },
+            AlertKind::BidDeadline { within_days } => self
+                .days
+                .map_or(false, |days| days >= 0 && days <= *within_days),
+            AlertKind::Relisted => self.relisted,
+        }
+    }
+
+    fn render(&self, template: &str) -> String {
+        let unknown = || "n/a".to_string();
+        let price = |price: Option<u64>| price.map_or_else(unknown, format_price);
+        let drop = self.price_drop();
+        template
+            .replace("{case_number}", &self.case_number)
+            .replace("{address}", &self.address.clone().unwrap_or_else(unknown))
+            .replace("{price}", &price(self.price))
+            .replace("{old_price}", &price(self.old_price))
+            .replace("{drop}", &price(drop.map(|(drop, _)| drop)))
+            .replace(
+                "{drop_percent}",
+                &drop.map_or_else(unknown, |(_, percent)| format!("{:.1}", percent)),
+            )
+            .replace("{status}", &self.status.clone().unwrap_or_else(unknown))
+            .replace(
+                "{old_status}",
+                &self.old_status.clone().unwrap_or_else(unknown),
+            )
+            .replace(
+                "{deadline}",
+                &self
+                    .deadline
+                    .map_or_else(unknown, |deadline| deadline.format("%m/%d/%Y").to_string()),
+            )
+            .replace(
+                "{days}",
+                &self.days.map_or_else(unknown, |days| days.to_string()),
+            )
+    }
+}
+
+/// Alerts of the rules `item` triggers, highest priority first. `timeline`
+/// is its history with the latest scrape already recorded.
+pub fn evaluate(
+    rules: &[AlertRule],
+    item: &ListingItem,
+    timeline: Option<&ListingTimeline>,
+    today: NaiveDate,
+) -> Vec<Alert> {
+    let facts = Facts::new(item, timeline, today);
+    let mut alerts: Vec<Alert> = rules
+        .iter()
+        .filter(|rule| facts.triggers(&rule.kind))
+        .map(|rule| Alert {
+            priority: rule.priority,
+            message: facts.render(
+                rule.template
+                    .as_deref()
+                    .unwrap_or_else(|| rule.kind.default_template()),
+            ),
+        })
+        .collect();
+    alerts.sort_by(|a, b| b.priority.cmp(&a.priority));
+    alerts
+}
+
+/// Escapes every character Telegram's MarkdownV2 reserves.
+pub fn escape_markdown(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if "_*[]()~`>#+-=|{}.!\\".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::history::ListingHistory;
+
+    fn listing(price: &str, bid_open_date: &str, status: &str) -> ListingItem {
+        ListingItem::from(
+            vec![
+                "",
+                "105-123456",
+                "123 Main St Decatur, GA 30032 DeKalb County",
+                price,
+                "3",
+                "2",
+                "05/01/2020 - 05/10/2020",
+                bid_open_date,
+                status,
+            ]
+            .into_iter()
+            .map(str::to_string)
+            .collect(),
+        )
+    }
+
+    fn rules(json: &str) -> Vec<AlertRule> {
+        serde_json::from_str(json).unwrap()
+    }
+
+    fn today() -> NaiveDate {
+        NaiveDate::from_ymd(2020, 5, 8)
+    }
+
+    #[test]
+    fn test_price_drop_and_status() {
+        let rules = rules(
+            r#"[
+                {"type": "price_drop", "percent": 10, "priority": "high"},
+                {
+                    "type": "price_drop",
+                    "amount": 5000,
+                    "template": "{address}: {old_price} > {price}"
+                },
+                {"type": "status", "to": ["Bids Open", "Price Reduced"]}
+            ]"#,
+        );
+        let mut history = ListingHistory::default();
+        history.record(&[listing("$100,000.00", "", "New Listing")], 100);
+        let reduced = listing("$94,000.00", "", "Price Reduced");
+        history.record(&[reduced.clone()], 200);
+
+        let alerts = evaluate(&rules, &reduced, history.timeline(&reduced.id), today());
+        assert_eq!(
+            alerts,
+            vec![
+                Alert {
+                    priority: Priority::Normal,
+                    message: "123 Main St Decatur, GA 30032 DeKalb County: $100,000 > $94,000"
+                        .to_string(),
+                },
+                Alert {
+                    priority: Priority::Normal,
+                    message: "Status changed from New Listing to Price Reduced".to_string(),
+                },
+            ]
+        );
+
+        let bigger_drop = listing("$80,000.00", "", "Price Reduced");
+        history.record(&[bigger_drop.clone()], 300);
+        let alerts = evaluate(
+            &rules,
+            &bigger_drop,
+            history.timeline(&bigger_drop.id),
+            today(),
+        );
+        assert_eq!(alerts.len(), 2);
+        assert_eq!(
+            alerts[0].to_string(),
+            "[high] Price dropped by $14,000 (14.9%) to $80,000"
+        );
+    }
+
+    #[test]
+    fn test_deadline_and_relisting() {
+        let rules = rules(
+            r#"[
+                {"type": "bid_deadline", "within_days": 3, "priority": "high"},
+                {"type": "relisted", "priority": "low"}
+            ]"#,
+        );
+        let item = listing("$100,000.00", "05/10/2020 11:59 PM", "Bids Open");
+        let mut history = ListingHistory::default();
+        history.record(&[item.clone()], 100);
+        assert_eq!(
+            evaluate(&rules, &item, history.timeline(&item.id), today()),
+            vec![Alert {
+                priority: Priority::High,
+                message: "Bids close in 2 day(s), on 05/10/2020".to_string(),
+            }]
+        );
+
+        history.record::<ListingItem>(&[], 200);
+        history.record(&[item.clone()], 300);
+        let later = NaiveDate::from_ymd(2020, 5, 11);
+        assert_eq!(
+            evaluate(&rules, &item, history.timeline(&item.id), later),
+            vec![Alert {
+                priority: Priority::Low,
+                message: "Relisted at $100,000".to_string(),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_escape_markdown() {
+        assert_eq!(
+            escape_markdown("[high] Price dropped by $14,000 (14.9%)"),
+            "\\[high\\] Price dropped by $14,000 \\(14\\.9%\\)"
+        );
+    }
+}
@@ -1,2 +1,5 @@
 pub mod telegram_api;
+#[cfg(test)]
+pub(crate) mod test_support;
 pub mod yandex_disk_api;
+pub mod yandex_oauth;
@@ -1,3 +1,4 @@
+use crate::http::RetryPolicy;
 use anyhow::{Context, Result};
 use reqwest::Client;
 use serde::{Deserialize, Serialize};
@@ -41,27 +42,39 @@ pub struct SendMessage {
 pub struct TelegramClient {
     token: String,
     http_client: Client,
+    base_url: String,
+    retry: RetryPolicy,
 }
 
 impl TelegramClient {
-    const BASE_TELEGRAM_API_URL: &'static str = "https://api.telegram.org/bot";
+    const BASE_TELEGRAM_API_URL: &'static str = "https://api.telegram.org";
 
     fn api_url(&self, method: &str) -> String {
-        format!(
-            "{}{}/{}",
-            TelegramClient::BASE_TELEGRAM_API_URL,
-            self.token,
-            method
-        )
+        format!("{}/bot{}/{}", self.base_url, self.token, method)
     }
 
     pub fn new(token_value: String, http_client: Client) -> TelegramClient {
         TelegramClient {
             token: token_value,
             http_client,
+            base_url: TelegramClient::BASE_TELEGRAM_API_URL.to_string(),
+            retry: RetryPolicy::default(),
         }
     }
 
+    /// Messages are only resent when they couldn't reach Telegram at all,
+    /// anything else risks a duplicate.
+    pub fn with_retry(mut self, retry: RetryPolicy) -> TelegramClient {
+        self.retry = retry;
+        self
+    }
+
+    /// Talks to `base_url` instead of the public Bot API.
+    pub fn with_api_url(mut self, base_url: String) -> TelegramClient {
+        self.base_url = base_url.trim_end_matches('/').to_string();
+        self
+    }
+
     pub async fn send_message(&self, message: &SendMessage) -> Result<TelegramResponse<Message>> {
         let json_body = serde_json::to_string(message).with_context(|| {
             format!(
@@ -70,12 +83,15 @@ impl TelegramClient {
             )
         });
 
+        let json_body = json_body?;
         let response_str = self
-            .http_client
-            .post(&self.api_url("sendMessage"))
-            .body(json_body?.clone())
-            .header(reqwest::header::CONTENT_TYPE, "application/json")
-            .send()
+            .retry
+            .send_once(|| {
+                self.http_client
+                    .post(&self.api_url("sendMessage"))
+                    .body(json_body.clone())
+                    .header(reqwest::header::CONTENT_TYPE, "application/json")
+            })
             .await?
             .text()
             .await?;
@@ -86,3 +102,58 @@ impl TelegramClient {
         Ok(response)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::api::test_support::FakeTelegram;
+
+    fn message(chat_id: &str) -> SendMessage {
+        SendMessage {
+            chat_id: chat_id.to_string(),
+            text: "hello".to_string(),
+            parse_mode: Some("MarkdownV2".to_string()),
+            disable_web_page_preview: true,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_send_message() {
+        let server = FakeTelegram::start("token").await;
+        let client =
+            TelegramClient::new("token".to_string(), Client::new()).with_api_url(server.url());
+
+        let response = client.send_message(&message("42")).await.unwrap();
+        assert!(response.ok);
+        assert_eq!(response.result.chat.id, 42);
+        assert_eq!(response.result.text.as_deref(), Some("hello"));
+        assert_eq!(server.sent().len(), 1);
+        assert_eq!(server.sent()[0]["parse_mode"], "MarkdownV2");
+    }
+
+    #[tokio::test]
+    async fn test_send_message_is_not_repeated() {
+        let server = FakeTelegram::start("token").await;
+        let client =
+            TelegramClient::new("token".to_string(), Client::new()).with_api_url(server.url());
+
+        server.fail_next(hyper::StatusCode::BAD_GATEWAY);
+        assert!(client.send_message(&message("42")).await.is_err());
+        assert!(server.sent().is_empty());
+        assert!(client.send_message(&message("42")).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_send_message_errors() {
+        let server = FakeTelegram::start("token").await;
+
+        let client =
+            TelegramClient::new("wrong".to_string(), Client::new()).with_api_url(server.url());
+        assert!(client.send_message(&message("42")).await.is_err());
+
+        let client =
+            TelegramClient::new("token".to_string(), Client::new()).with_api_url(server.url());
+        assert!(client.send_message(&message("not a chat")).await.is_err());
+        assert!(server.sent().is_empty());
+    }
+}
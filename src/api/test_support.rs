@@ -0,0 +1,1131 @@
+//! In-process stand-ins for the Yandex Disk, Telegram Bot and S3 APIs, for
+//! webhook receivers and for scraped sites, so the clients can be tested
+//! without network access or real tokens.
+
+use hyper::header::{
+    HeaderMap, AUTHORIZATION, CONTENT_RANGE, ETAG, HOST, IF_NONE_MATCH, IF_RANGE, RANGE,
+    RETRY_AFTER,
+};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use reqwest::Url;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use tokio::sync::oneshot;
+
+const TIMESTAMP: &'static str = "2020-06-01T12:00:00+00:00";
+
+type Handler = Arc<dyn Fn(&Method, &Url, &HeaderMap, &[u8]) -> Response<Body> + Send + Sync>;
+
+/// Serves `handler` on a random local port until dropped.
+struct FakeServer {
+    addr: SocketAddr,
+    _shutdown: oneshot::Sender<()>,
+}
+
+impl FakeServer {
+    async fn start(handler: Handler) -> Self {
+        let make_service = make_service_fn(move |_| {
+            let handler = handler.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+                    let handler = handler.clone();
+                    async move {
+                        let (parts, body) = request.into_parts();
+                        let body = hyper::body::to_bytes(body).await?;
+                        let url = Url::parse(&format!("http://localhost{}", parts.uri))
+                            .expect("request uri should be a valid path");
+                        Ok::<_, hyper::Error>(handler(
+                            &parts.method,
+                            &url,
+                            &parts.headers,
+                            &body[..],
+                        ))
+                    }
+                }))
+            }
+        });
+
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+        let addr = server.local_addr();
+        let (shutdown, stopped) = oneshot::channel::<()>();
+        tokio::spawn(server.with_graceful_shutdown(async {
+            let _ = stopped.await;
+        }));
+
+        FakeServer {
+            addr: addr,
+            _shutdown: shutdown,
+        }
+    }
+
+    fn url(&self) -> String {
+        format!("http://{}", self.addr)
+    }
+}
+
+fn query(url: &Url, name: &str) -> Option<String> {
+    url.query_pairs()
+        .find(|(key, _)| key == name)
+        .map(|(_, value)| value.into_owned())
+}
+
+fn json_response(status: StatusCode, body: Value) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json")
+        .body(Body::from(body.to_string()))
+        .expect("response should be valid")
+}
+
+#[derive(Default)]
+struct DiskState {
+    files: BTreeMap<String, Vec<u8>>,
+    dirs: BTreeSet<String>,
+    /// File paths listings keep returning while frozen.
+    frozen_listing: Option<Vec<String>>,
+    /// Most items a listing page holds, whatever `limit` asks for.
+    page_size: Option<usize>,
+    /// Origin path and data of deleted files by trash path.
+    trash: BTreeMap<String, (String, Vec<u8>)>,
+    deleted: usize,
+    /// Whether moves, copies and restores answer with an operation to poll.
+    async_operations: bool,
+    /// Operations by id, with whether they were polled yet.
+    operations: BTreeMap<String, bool>,
+    failures: VecDeque<StatusCode>,
+}
+
+/// Yandex Disk API with the resources, trash, operations, upload and download
+/// endpoints, files kept in memory under their full paths, e.g.
+/// `/estatebot/first`.
+pub struct FakeDisk {
+    server: FakeServer,
+    state: Arc<Mutex<DiskState>>,
+}
+
+impl FakeDisk {
+    /// Accepts only requests authorized with `token`.
+    pub async fn start(token: &str) -> Self {
+        let state = Arc::new(Mutex::new(DiskState::default()));
+        let handler_state = state.clone();
+        let token = token.to_string();
+        let server = FakeServer::start(Arc::new(
+            move |method: &Method, url: &Url, headers: &HeaderMap, body: &[u8]| {
+                handle_disk(&handler_state, &token, method, url, headers, body)
+            },
+        ))
+        .await;
+
+        FakeDisk {
+            server: server,
+            state: state,
+        }
+    }
+
+    /// Base URL to pass to `DiskClient::with_api_url`.
+    pub fn api_url(&self) -> String {
+        format!("{}/v1/disk", self.server.url())
+    }
+
+    pub fn put_file(&self, path: &str, data: &[u8]) {
+        let mut state = self.state.lock().unwrap();
+        state.files.insert(path.to_string(), data.to_vec());
+    }
+
+    pub fn file(&self, path: &str) -> Option<Vec<u8>> {
+        self.state.lock().unwrap().files.get(path).cloned()
+    }
+
+    /// The ETag the file at `path` is downloaded with.
+    pub fn etag(&self, path: &str) -> Option<String> {
+        self.file(path).map(|data| disk_etag(&data))
+    }
+
+    pub fn mkdir(&self, path: &str) {
+        let mut state = self.state.lock().unwrap();
+        state.dirs.insert(path.trim_end_matches('/').to_string());
+    }
+
+    /// Keeps listings at the current files, as another client that listed
+    /// before later writes would see them.
+    pub fn freeze_listing(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.frozen_listing = Some(state.files.keys().cloned().collect());
+    }
+
+    /// Returns at most `page_size` items per listing request.
+    pub fn limit_page_size(&self, page_size: usize) {
+        self.state.lock().unwrap().page_size = Some(page_size);
+    }
+
+    /// Answers moves, copies and restores with an operation that's still in
+    /// progress when first polled.
+    pub fn run_operations_async(&self) {
+        self.state.lock().unwrap().async_operations = true;
+    }
+
+    /// Answers the next API call with `status`.
+    pub fn fail_next(&self, status: StatusCode) {
+        self.state.lock().unwrap().failures.push_back(status);
+    }
+}
+
+fn disk_error(status: StatusCode, error: &str, description: &str) -> Response<Body> {
+    json_response(
+        status,
+        json!({ "message": description, "description": description, "error": error }),
+    )
+}
+
+fn disk_link(host: &str, endpoint: &str, path: &str, method: &str) -> Response<Body> {
+    let mut href =
+        Url::parse(&format!("http://{}{}", host, endpoint)).expect("host should be valid");
+    href.query_pairs_mut().append_pair("path", path);
+    json_response(
+        StatusCode::OK,
+        json!({ "href": href.as_str(), "method": method, "templated": false }),
+    )
+}
+
+fn disk_file(host: &str, file: &str, size: Option<usize>) -> Value {
+    let mut href = Url::parse(&format!("http://{}/download", host)).expect("host should be valid");
+    href.query_pairs_mut().append_pair("path", file);
+    json!({
+        "type": "file",
+        "name": file.rsplit('/').next().unwrap_or_default(),
+        "path": format!("disk:{}", file),
+        "file": href.as_str(),
+        "size": size,
+        "created": TIMESTAMP,
+        "modified": TIMESTAMP,
+    })
+}
+
+/// Answer to a finished move, copy or restore to `path`, or the link to an
+/// operation to poll when they run asynchronously.
+fn disk_operation(state: &mut DiskState, host: &str, path: &str) -> Response<Body> {
+    if !state.async_operations {
+        let mut href = Url::parse(&format!("http://{}/v1/disk/resources", host))
+            .expect("host should be valid");
+        href.query_pairs_mut().append_pair("path", path);
+        return json_response(
+            StatusCode::CREATED,
+            json!({ "href": href.as_str(), "method": "GET", "templated": false }),
+        );
+    }
+
+    let operation_id = format!("operation_{}", state.operations.len());
+    state.operations.insert(operation_id.clone(), false);
+    json_response(
+        StatusCode::ACCEPTED,
+        json!({
+            "href": format!("http://{}/v1/disk/operations/{}", host, operation_id),
+            "method": "GET",
+            "templated": false,
+        }),
+    )
+}
+
+fn disk_etag(data: &[u8]) -> String {
+    format!("\"{}\"", hex::encode(Sha256::digest(data)))
+}
+
+/// Serves `data`, or its tail for `Range: bytes={offset}-` requests. The
+/// ETag is a digest of `data`, a range with a stale `If-Range` gets it all.
+fn download(data: &[u8], headers: &HeaderMap) -> Response<Body> {
+    let etag = disk_etag(data);
+    let unchanged = headers
+        .get(IF_RANGE)
+        .map_or(true, |value| value.as_bytes() == etag.as_bytes());
+    let offset = headers
+        .get(RANGE)
+        .and_then(|range| range.to_str().ok())
+        .and_then(|range| range.strip_prefix("bytes="))
+        .and_then(|range| range.strip_suffix("-"))
+        .and_then(|offset| offset.parse::<usize>().ok())
+        .filter(|_| unchanged);
+    let response = Response::builder().header(ETAG, etag.as_str());
+    let response = match offset {
+        None => response
+            .status(StatusCode::OK)
+            .body(Body::from(data.to_vec())),
+        Some(offset) if offset >= data.len() => response
+            .status(StatusCode::RANGE_NOT_SATISFIABLE)
+            .header(CONTENT_RANGE, format!("bytes */{}", data.len()))
+            .body(Body::empty()),
+        Some(offset) => response
+            .status(StatusCode::PARTIAL_CONTENT)
+            .header(
+                CONTENT_RANGE,
+                format!("bytes {}-{}/{}", offset, data.len() - 1, data.len()),
+            )
+            .body(Body::from(data[offset..].to_vec())),
+    };
+    response.expect("response should be valid")
+}
+
+fn handle_disk(
+    state: &Mutex<DiskState>,
+    token: &str,
+    method: &Method,
+    url: &Url,
+    headers: &HeaderMap,
+    body: &[u8],
+) -> Response<Body> {
+    let mut state = state.lock().unwrap();
+    let path = query(url, "path").unwrap_or_default();
+    let host = headers
+        .get(HOST)
+        .and_then(|h| h.to_str().ok())
+        .unwrap_or("localhost")
+        .to_string();
+
+    if let Some(status) = state.failures.pop_front() {
+        return disk_error(status, "InjectedError", "injected failure");
+    }
+
+    // Upload and download links are pre-signed, like the real ones.
+    match (method, url.path()) {
+        (&Method::PUT, "/upload") => {
+            state.files.insert(path, body.to_vec());
+            return json_response(StatusCode::CREATED, json!({}));
+        }
+        (&Method::GET, "/download") => {
+            return match state.files.get(&path) {
+                Some(data) => download(data, headers),
+                None => disk_error(
+                    StatusCode::NOT_FOUND,
+                    "DiskNotFoundError",
+                    "Resource not found.",
+                ),
+            };
+        }
+        _ => {}
+    }
+
+    let authorized = headers.get(AUTHORIZATION).map_or(false, |value| {
+        value.as_bytes() == format!("OAuth {}", token).as_bytes()
+    });
+    if !authorized {
+        return disk_error(
+            StatusCode::UNAUTHORIZED,
+            "UnauthorizedError",
+            "Unauthorized",
+        );
+    }
+
+    match (method, url.path()) {
+        (&Method::GET, "/v1/disk/resources/upload") => {
+            let overwrite = query(url, "overwrite").map_or(false, |v| v == "true");
+            if state.files.contains_key(&path) && !overwrite {
+                return disk_error(
+                    StatusCode::CONFLICT,
+                    "DiskResourceAlreadyExistsError",
+                    "Resource already exists",
+                );
+            }
+            disk_link(&host, "/upload", &path, "PUT")
+        }
+        (&Method::GET, "/v1/disk/resources/download") => {
+            if !state.files.contains_key(&path) {
+                return disk_error(
+                    StatusCode::NOT_FOUND,
+                    "DiskNotFoundError",
+                    "Resource not found.",
+                );
+            }
+            disk_link(&host, "/download", &path, "GET")
+        }
+        (&Method::GET, "/v1/disk/resources") if state.files.contains_key(&path) => {
+            let size = state.files[&path].len();
+            json_response(StatusCode::OK, disk_file(&host, &path, Some(size)))
+        }
+        (&Method::GET, "/v1/disk/resources") => {
+            let folder = path.trim_end_matches('/').to_string();
+            let prefix = format!("{}/", folder);
+            let exists = state.dirs.contains(&folder)
+                || state.files.keys().any(|file| file.starts_with(&prefix));
+            if !exists {
+                return disk_error(
+                    StatusCode::NOT_FOUND,
+                    "DiskNotFoundError",
+                    "Resource not found.",
+                );
+            }
+
+            let listed = match &state.frozen_listing {
+                Some(paths) => paths.clone(),
+                None => state.files.keys().cloned().collect(),
+            };
+            let limit = query(url, "limit")
+                .and_then(|limit| limit.parse::<usize>().ok())
+                .unwrap_or(20)
+                .min(state.page_size.unwrap_or(usize::MAX));
+            let offset = query(url, "offset")
+                .and_then(|offset| offset.parse::<usize>().ok())
+                .unwrap_or(0);
+            let children: Vec<&String> = listed
+                .iter()
+                .filter(|file| file.starts_with(&prefix) && !file[prefix.len()..].contains('/'))
+                .collect();
+            let items: Vec<Value> = children
+                .iter()
+                .skip(offset)
+                .take(limit)
+                .map(|file| disk_file(&host, file, state.files.get(*file).map(Vec::len)))
+                .collect();
+
+            json_response(
+                StatusCode::OK,
+                json!({
+                    "type": "dir",
+                    "name": folder.rsplit('/').next().unwrap_or_default(),
+                    "path": format!("disk:{}", folder),
+                    "created": TIMESTAMP,
+                    "modified": TIMESTAMP,
+                    "_embedded": {
+                        "path": format!("disk:{}", folder),
+                        "limit": limit,
+                        "offset": offset,
+                        "total": children.len(),
+                        "items": items,
+                    },
+                }),
+            )
+        }
+        (&Method::PUT, "/v1/disk/resources") => {
+            let folder = path.trim_end_matches('/').to_string();
+            let parent = folder
+                .rsplitn(2, '/')
+                .nth(1)
+                .unwrap_or_default()
+                .to_string();
+            if !parent.is_empty() && !state.dirs.contains(&parent) {
+                return disk_error(
+                    StatusCode::CONFLICT,
+                    "DiskPathDoesntExistsError",
+                    "Specified path doesn't exist.",
+                );
+            }
+            if !state.dirs.insert(folder) {
+                return disk_error(
+                    StatusCode::CONFLICT,
+                    "DiskResourceAlreadyExistsError",
+                    "Resource already exists",
+                );
+            }
+            json_response(
+                StatusCode::CREATED,
+                json!({ "href": "", "method": "GET", "templated": false }),
+            )
+        }
+        (&Method::DELETE, "/v1/disk/resources") => {
+            let data = match state.files.remove(&path) {
+                Some(data) => data,
+                None => {
+                    return disk_error(
+                        StatusCode::NOT_FOUND,
+                        "DiskNotFoundError",
+                        "Resource not found.",
+                    )
+                }
+            };
+            if query(url, "permanently").as_deref() != Some("true") {
+                let name = path.rsplit('/').next().unwrap_or_default();
+                let trash_path = format!("trash:/{}_{}", name, state.deleted);
+                state.deleted += 1;
+                state.trash.insert(trash_path, (path, data));
+            }
+            Response::builder()
+                .status(StatusCode::NO_CONTENT)
+                .body(Body::empty())
+                .expect("response should be valid")
+        }
+        (&Method::POST, "/v1/disk/resources/move") | (&Method::POST, "/v1/disk/resources/copy") => {
+            let from = query(url, "from").unwrap_or_default();
+            let overwrite = query(url, "overwrite").map_or(false, |v| v == "true");
+            let copy = url.path().ends_with("/copy");
+            let inside = format!("{}/", from);
+            let files: Vec<String> = state
+                .files
+                .keys()
+                .filter(|file| **file == from || file.starts_with(&inside))
+                .cloned()
+                .collect();
+            let dirs: Vec<String> = state
+                .dirs
+                .iter()
+                .filter(|dir| **dir == from || dir.starts_with(&inside))
+                .cloned()
+                .collect();
+            if files.is_empty() && dirs.is_empty() {
+                return disk_error(
+                    StatusCode::NOT_FOUND,
+                    "DiskNotFoundError",
+                    "Resource not found.",
+                );
+            }
+            if !overwrite && (state.files.contains_key(&path) || state.dirs.contains(&path)) {
+                return disk_error(
+                    StatusCode::CONFLICT,
+                    "DiskResourceAlreadyExistsError",
+                    "Resource already exists",
+                );
+            }
+
+            for file in files {
+                let target = format!("{}{}", path, &file[from.len()..]);
+                let data = if copy {
+                    state.files[&file].clone()
+                } else {
+                    state.files.remove(&file).unwrap_or_default()
+                };
+                state.files.insert(target, data);
+            }
+            for dir in dirs {
+                if !copy {
+                    state.dirs.remove(&dir);
+                }
+                state.dirs.insert(format!("{}{}", path, &dir[from.len()..]));
+            }
+            disk_operation(&mut state, &host, &path)
+        }
+        (&Method::GET, "/v1/disk/trash/resources") => {
+            let items: Vec<Value> = state
+                .trash
+                .iter()
+                .map(|(trash_path, (origin, data))| {
+                    json!({
+                        "type": "file",
+                        "name": origin.rsplit('/').next().unwrap_or_default(),
+                        "path": trash_path,
+                        "origin_path": format!("disk:{}", origin),
+                        "size": data.len(),
+                        "created": TIMESTAMP,
+                        "modified": TIMESTAMP,
+                    })
+                })
+                .collect();
+            json_response(
+                StatusCode::OK,
+                json!({
+                    "type": "dir",
+                    "name": "trash",
+                    "path": "trash:/",
+                    "created": TIMESTAMP,
+                    "modified": TIMESTAMP,
+                    "_embedded": {
+                        "path": "trash:/",
+                        "limit": items.len(),
+                        "offset": 0,
+                        "total": items.len(),
+                        "items": items,
+                    },
+                }),
+            )
+        }
+        (&Method::PUT, "/v1/disk/trash/resources/restore") => {
+            let origin = match state.trash.get(&path) {
+                Some((origin, _)) => origin.clone(),
+                None => {
+                    return disk_error(
+                        StatusCode::NOT_FOUND,
+                        "DiskNotFoundError",
+                        "Resource not found.",
+                    )
+                }
+            };
+            let overwrite = query(url, "overwrite").map_or(false, |v| v == "true");
+            if !overwrite && state.files.contains_key(&origin) {
+                return disk_error(
+                    StatusCode::CONFLICT,
+                    "DiskResourceAlreadyExistsError",
+                    "Resource already exists",
+                );
+            }
+            if let Some((_, data)) = state.trash.remove(&path) {
+                state.files.insert(origin.clone(), data);
+            }
+            disk_operation(&mut state, &host, &origin)
+        }
+        (&Method::GET, endpoint) if endpoint.starts_with("/v1/disk/operations/") => {
+            let operation_id = &endpoint["/v1/disk/operations/".len()..];
+            match state.operations.get_mut(operation_id) {
+                Some(polled) => {
+                    let status = if *polled { "success" } else { "in-progress" };
+                    *polled = true;
+                    json_response(StatusCode::OK, json!({ "status": status }))
+                }
+                None => disk_error(
+                    StatusCode::NOT_FOUND,
+                    "DiskNotFoundError",
+                    "Operation not found.",
+                ),
+            }
+        }
+        _ => disk_error(StatusCode::NOT_FOUND, "NotFoundError", "Unknown endpoint"),
+    }
+}
+
+#[derive(Default)]
+struct TelegramState {
+    sent: Vec<Value>,
+    failures: VecDeque<StatusCode>,
+}
+
+/// Telegram Bot API with `sendMessage`. Messages to chat ids that aren't
+/// numbers fail with "chat not found".
+pub struct FakeTelegram {
+    server: FakeServer,
+    state: Arc<Mutex<TelegramState>>,
+}
+
+impl FakeTelegram {
+    pub async fn start(token: &str) -> Self {
+        let state = Arc::new(Mutex::new(TelegramState::default()));
+        let handler_state = state.clone();
+        let token = token.to_string();
+        let server = FakeServer::start(Arc::new(
+            move |method: &Method, url: &Url, _: &HeaderMap, body: &[u8]| {
+                handle_telegram(&handler_state, &token, method, url, body)
+            },
+        ))
+        .await;
+
+        FakeTelegram {
+            server: server,
+            state: state,
+        }
+    }
+
+    /// Base URL to pass to `TelegramClient::with_api_url`.
+    pub fn url(&self) -> String {
+        self.server.url()
+    }
+
+    /// Bodies of the messages sent so far.
+    pub fn sent(&self) -> Vec<Value> {
+        self.state.lock().unwrap().sent.clone()
+    }
+
+    /// Answers the next API call with `status`.
+    pub fn fail_next(&self, status: StatusCode) {
+        self.state.lock().unwrap().failures.push_back(status);
+    }
+}
+
+fn telegram_error(status: StatusCode, description: &str) -> Response<Body> {
+    json_response(
+        status,
+        json!({ "ok": false, "error_code": status.as_u16(), "description": description }),
+    )
+}
+
+fn handle_telegram(
+    state: &Mutex<TelegramState>,
+    token: &str,
+    method: &Method,
+    url: &Url,
+    body: &[u8],
+) -> Response<Body> {
+    let mut state = state.lock().unwrap();
+    if let Some(status) = state.failures.pop_front() {
+        return telegram_error(status, "injected failure");
+    }
+
+    let bot_prefix = format!("/bot{}/", token);
+    let api_method = match url.path().strip_prefix(&bot_prefix) {
+        Some(api_method) => api_method,
+        None => return telegram_error(StatusCode::UNAUTHORIZED, "Unauthorized"),
+    };
+
+    match (method, api_method) {
+        (&Method::POST, "sendMessage") => {
+            let message: Value = match serde_json::from_slice(body) {
+                Ok(message) => message,
+                Err(_) => {
+                    return telegram_error(StatusCode::BAD_REQUEST, "Bad Request: invalid json")
+                }
+            };
+            let chat_id = match message["chat_id"]
+                .as_str()
+                .and_then(|id| id.parse::<i64>().ok())
+            {
+                Some(chat_id) => chat_id,
+                None => {
+                    return telegram_error(StatusCode::BAD_REQUEST, "Bad Request: chat not found")
+                }
+            };
+            if message["text"].as_str().map_or(true, str::is_empty) {
+                return telegram_error(
+                    StatusCode::BAD_REQUEST,
+                    "Bad Request: message text is empty",
+                );
+            }
+
+            state.sent.push(message.clone());
+            json_response(
+                StatusCode::OK,
+                json!({
+                    "ok": true,
+                    "result": {
+                        "message_id": state.sent.len(),
+                        "chat": { "id": chat_id },
+                        "text": message["text"],
+                    },
+                }),
+            )
+        }
+        _ => telegram_error(StatusCode::NOT_FOUND, "Not Found"),
+    }
+}
+
+#[derive(Default)]
+struct OAuthState {
+    client_id: String,
+    refresh_token: String,
+    access_token: String,
+    device_confirmed: bool,
+}
+
+const DEVICE_CODE: &'static str = "device-code";
+
+/// Yandex OAuth server with the device-code and refresh-token grants. Every
+/// grant hands out the same access token.
+pub struct FakeOAuth {
+    server: FakeServer,
+    state: Arc<Mutex<OAuthState>>,
+}
+
+impl FakeOAuth {
+    pub async fn start(client_id: &str, refresh_token: &str, access_token: &str) -> Self {
+        let state = Arc::new(Mutex::new(OAuthState {
+            client_id: client_id.to_string(),
+            refresh_token: refresh_token.to_string(),
+            access_token: access_token.to_string(),
+            device_confirmed: false,
+        }));
+        let handler_state = state.clone();
+        let server = FakeServer::start(Arc::new(
+            move |method: &Method, url: &Url, _: &HeaderMap, body: &[u8]| {
+                handle_oauth(&handler_state, method, url, body)
+            },
+        ))
+        .await;
+
+        FakeOAuth {
+            server: server,
+            state: state,
+        }
+    }
+
+    /// Base URL to pass to `OAuthClient::with_api_url`.
+    pub fn url(&self) -> String {
+        self.server.url()
+    }
+
+    /// The user entering the code in a browser.
+    pub fn confirm_device_code(&self) {
+        self.state.lock().unwrap().device_confirmed = true;
+    }
+}
+
+fn oauth_error(error: &str, description: &str) -> Response<Body> {
+    json_response(
+        StatusCode::BAD_REQUEST,
+        json!({ "error": error, "error_description": description }),
+    )
+}
+
+fn handle_oauth(
+    state: &Mutex<OAuthState>,
+    method: &Method,
+    url: &Url,
+    body: &[u8],
+) -> Response<Body> {
+    let state = state.lock().unwrap();
+    let form = Url::parse(&format!(
+        "http://localhost/?{}",
+        String::from_utf8_lossy(body)
+    ))
+    .expect("form should be urlencoded");
+    let field = |name: &str| query(&form, name).unwrap_or_default();
+
+    if *method != Method::POST {
+        return oauth_error("invalid_request", "POST expected");
+    }
+    if field("client_id") != state.client_id {
+        return oauth_error("invalid_client", "Client not found");
+    }
+
+    match url.path() {
+        "/device/code" => json_response(
+            StatusCode::OK,
+            json!({
+                "device_code": DEVICE_CODE,
+                "user_code": "ABCD1234",
+                "verification_url": "https://ya.ru/device",
+                "interval": 1,
+                "expires_in": 300,
+            }),
+        ),
+        "/token" => match field("grant_type").as_str() {
+            "device_code" if field("code") != DEVICE_CODE => {
+                oauth_error("invalid_grant", "Code has expired")
+            }
+            "device_code" if !state.device_confirmed => oauth_error(
+                "authorization_pending",
+                "User has not yet authorized your application",
+            ),
+            "device_code" => json_response(
+                StatusCode::OK,
+                json!({
+                    "token_type": "bearer",
+                    "access_token": state.access_token,
+                    "refresh_token": state.refresh_token,
+                    "expires_in": 31536000,
+                }),
+            ),
+            "refresh_token" if field("refresh_token") != state.refresh_token => {
+                oauth_error("invalid_grant", "Token has expired or revoked")
+            }
+            "refresh_token" => json_response(
+                StatusCode::OK,
+                json!({
+                    "token_type": "bearer",
+                    "access_token": state.access_token,
+                    "expires_in": 31536000,
+                }),
+            ),
+            _ => oauth_error("unsupported_grant_type", "Unknown grant type"),
+        },
+        _ => json_response(StatusCode::NOT_FOUND, json!({ "error": "not_found" })),
+    }
+}
+
+#[derive(Default)]
+struct S3State {
+    /// Object data by bucket and key.
+    objects: BTreeMap<(String, String), Vec<u8>>,
+    /// Most keys a listing page holds.
+    page_size: Option<usize>,
+}
+
+/// S3 API with path-style put, get, delete and ListObjectsV2 requests,
+/// objects kept in memory. Requests aren't checked for signatures.
+pub struct FakeS3 {
+    server: FakeServer,
+    state: Arc<Mutex<S3State>>,
+}
+
+impl FakeS3 {
+    pub async fn start() -> Self {
+        let state = Arc::new(Mutex::new(S3State::default()));
+        let handler_state = state.clone();
+        let server = FakeServer::start(Arc::new(
+            move |method: &Method, url: &Url, _: &HeaderMap, body: &[u8]| {
+                handle_s3(&handler_state, method, url, body)
+            },
+        ))
+        .await;
+
+        FakeS3 {
+            server: server,
+            state: state,
+        }
+    }
+
+    /// URL to use as `S3Config::endpoint`.
+    pub fn endpoint(&self) -> String {
+        self.server.url()
+    }
+
+    pub fn object(&self, bucket: &str, key: &str) -> Option<Vec<u8>> {
+        let state = self.state.lock().unwrap();
+        state
+            .objects
+            .get(&(bucket.to_string(), key.to_string()))
+            .cloned()
+    }
+
+    /// Returns at most `page_size` keys per listing request.
+    pub fn limit_page_size(&self, page_size: usize) {
+        self.state.lock().unwrap().page_size = Some(page_size);
+    }
+}
+
+fn s3_response(status: StatusCode, xml: String) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header("Content-Type", "application/xml")
+        .body(Body::from(xml))
+        .expect("response should be valid")
+}
+
+fn s3_error(status: StatusCode, code: &str, message: &str) -> Response<Body> {
+    s3_response(
+        status,
+        format!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+             <Error><Code>{}</Code><Message>{}</Message></Error>",
+            code, message
+        ),
+    )
+}
+
+fn handle_s3(state: &Mutex<S3State>, method: &Method, url: &Url, body: &[u8]) -> Response<Body> {
+    let mut state = state.lock().unwrap();
+    let mut segments = url.path().trim_start_matches('/').splitn(2, '/');
+    let bucket = segments.next().unwrap_or_default().to_string();
+    let key = segments.next().unwrap_or_default().to_string();
+    let object = (bucket.clone(), key.clone());
+
+    match (method, key.is_empty()) {
+        (&Method::GET, true) => {
+            let prefix = query(url, "prefix").unwrap_or_default();
+            let after = query(url, "continuation-token").unwrap_or_default();
+            let page_size = state.page_size.unwrap_or(1000);
+            let keys: Vec<&String> = state
+                .objects
+                .keys()
+                .filter(|(object_bucket, key)| {
+                    *object_bucket == bucket && key.starts_with(&prefix) && *key > after
+                })
+                .map(|(_, key)| key)
+                .collect();
+            let page = &keys[..keys.len().min(page_size)];
+            let contents: String = page
+                .iter()
+                .map(|key| format!("<Contents><Key>{}</Key></Contents>", key))
+                .collect();
+            let next = match (keys.len() > page.len(), page.last()) {
+                (true, Some(last)) => format!(
+                    "<IsTruncated>true</IsTruncated>\
+                     <NextContinuationToken>{}</NextContinuationToken>",
+                    last
+                ),
+                _ => "<IsTruncated>false</IsTruncated>".to_string(),
+            };
+            s3_response(
+                StatusCode::OK,
+                format!(
+                    "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+                     <ListBucketResult><Name>{}</Name><Prefix>{}</Prefix>\
+                     <KeyCount>{}</KeyCount>{}{}</ListBucketResult>",
+                    bucket,
+                    prefix,
+                    page.len(),
+                    next,
+                    contents
+                ),
+            )
+        }
+        (&Method::GET, false) => match state.objects.get(&object) {
+            Some(data) => Response::new(Body::from(data.clone())),
+            None => s3_error(
+                StatusCode::NOT_FOUND,
+                "NoSuchKey",
+                "The specified key does not exist.",
+            ),
+        },
+        (&Method::PUT, false) => {
+            state.objects.insert(object, body.to_vec());
+            Response::new(Body::empty())
+        }
+        (&Method::DELETE, false) => {
+            state.objects.remove(&object);
+            Response::builder()
+                .status(StatusCode::NO_CONTENT)
+                .body(Body::empty())
+                .expect("response should be valid")
+        }
+        _ => s3_error(
+            StatusCode::METHOD_NOT_ALLOWED,
+            "MethodNotAllowed",
+            "The specified method is not allowed.",
+        ),
+    }
+}
+
+#[derive(Default)]
+struct WebhookState {
+    received: Vec<Value>,
+    /// Posts accepted before every following one is answered with the status.
+    failing_after: Option<(usize, StatusCode)>,
+    /// Posts still to turn away with a 429, and its `Retry-After` seconds.
+    throttled: usize,
+    retry_after_secs: u64,
+}
+
+/// Webhook receiver accepting JSON posts on any path.
+pub struct FakeWebhook {
+    server: FakeServer,
+    state: Arc<Mutex<WebhookState>>,
+}
+
+impl FakeWebhook {
+    pub async fn start() -> Self {
+        let state = Arc::new(Mutex::new(WebhookState::default()));
+        let handler_state = state.clone();
+        let server = FakeServer::start(Arc::new(
+            move |_: &Method, _: &Url, _: &HeaderMap, body: &[u8]| {
+                handle_webhook(&handler_state, body)
+            },
+        ))
+        .await;
+
+        FakeWebhook {
+            server: server,
+            state: state,
+        }
+    }
+
+    pub fn url(&self) -> String {
+        format!("{}/hook", self.server.url())
+    }
+
+    /// Bodies of the posts accepted so far.
+    pub fn received(&self) -> Vec<Value> {
+        self.state.lock().unwrap().received.clone()
+    }
+
+    /// Answers every post after the next `count` with `status`.
+    pub fn fail_after(&self, count: usize, status: StatusCode) {
+        let mut state = self.state.lock().unwrap();
+        let accepted = state.received.len();
+        state.failing_after = Some((accepted + count, status));
+    }
+
+    /// Answers the next `count` posts with a 429 asking to retry after
+    /// `retry_after_secs`.
+    pub fn throttle(&self, count: usize, retry_after_secs: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.throttled = count;
+        state.retry_after_secs = retry_after_secs;
+    }
+}
+
+fn handle_webhook(state: &Mutex<WebhookState>, body: &[u8]) -> Response<Body> {
+    let mut state = state.lock().unwrap();
+    if state.throttled > 0 {
+        state.throttled -= 1;
+        return Response::builder()
+            .status(StatusCode::TOO_MANY_REQUESTS)
+            .header(RETRY_AFTER, state.retry_after_secs)
+            .body(Body::empty())
+            .expect("response should be valid");
+    }
+    if let Some((accepted, status)) = state.failing_after {
+        if state.received.len() >= accepted {
+            return json_response(status, json!({ "error": "injected failure" }));
+        }
+    }
+    match serde_json::from_slice(body) {
+        Ok(body) => {
+            state.received.push(body);
+            json_response(StatusCode::OK, json!({}))
+        }
+        Err(_) => json_response(StatusCode::BAD_REQUEST, json!({ "error": "invalid json" })),
+    }
+}
+
+#[derive(Default)]
+struct SiteState {
+    robots_txt: Option<String>,
+    /// Body and ETag by path.
+    pages: BTreeMap<String, (String, String)>,
+    requests: Vec<String>,
+    failures: VecDeque<StatusCode>,
+}
+
+/// Website serving pages with ETags, answering 304 to a matching
+/// `If-None-Match`, and an optional robots.txt.
+pub struct FakeSite {
+    server: FakeServer,
+    state: Arc<Mutex<SiteState>>,
+}
+
+impl FakeSite {
+    pub async fn start(robots_txt: Option<&str>) -> Self {
+        let state = Arc::new(Mutex::new(SiteState {
+            robots_txt: robots_txt.map(str::to_string),
+            ..SiteState::default()
+        }));
+        let handler_state = state.clone();
+        let server = FakeServer::start(Arc::new(
+            move |_: &Method, url: &Url, headers: &HeaderMap, _: &[u8]| {
+                handle_site(&handler_state, url, headers)
+            },
+        ))
+        .await;
+
+        FakeSite {
+            server: server,
+            state: state,
+        }
+    }
+
+    pub fn url(&self, path: &str) -> Url {
+        Url::parse(&format!("{}{}", self.server.url(), path)).expect("path should be valid")
+    }
+
+    pub fn put_page(&self, path: &str, body: &str, etag: &str) {
+        let mut state = self.state.lock().unwrap();
+        state
+            .pages
+            .insert(path.to_string(), (body.to_string(), etag.to_string()));
+    }
+
+    /// Paths requested so far, robots.txt included.
+    pub fn requests(&self) -> Vec<String> {
+        self.state.lock().unwrap().requests.clone()
+    }
+
+    /// Answers the next request with `status`.
+    pub fn fail_next(&self, status: StatusCode) {
+        self.state.lock().unwrap().failures.push_back(status);
+    }
+}
+
+fn handle_site(state: &Mutex<SiteState>, url: &Url, headers: &HeaderMap) -> Response<Body> {
+    let mut state = state.lock().unwrap();
+    state.requests.push(url.path().to_string());
+
+    let response = Response::builder();
+    if let Some(status) = state.failures.pop_front() {
+        return response
+            .status(status)
+            .body(Body::empty())
+            .expect("response should be valid");
+    }
+    if url.path() == "/robots.txt" {
+        return match &state.robots_txt {
+            Some(robots_txt) => response.body(Body::from(robots_txt.clone())),
+            None => response.status(StatusCode::NOT_FOUND).body(Body::empty()),
+        }
+        .expect("response should be valid");
+    }
+
+    match state.pages.get(url.path()) {
+        Some((_, etag))
+            if headers
+                .get(IF_NONE_MATCH)
+                .map_or(false, |value| value == etag.as_str()) =>
+        {
+            response
+                .status(StatusCode::NOT_MODIFIED)
+                .header(ETAG, etag.as_str())
+                .body(Body::empty())
+        }
+        Some((body, etag)) => response
+            .header(ETAG, etag.as_str())
+            .body(Body::from(body.clone())),
+        None => response.status(StatusCode::NOT_FOUND).body(Body::empty()),
+    }
+    .expect("response should be valid")
+}
@@ -1,7 +1,20 @@
-use anyhow::{Context, Result};
-use reqwest::Client;
+use super::yandex_oauth::TokenRefresher;
+use crate::http::RetryPolicy;
+use anyhow::{bail, Context, Result};
+use bytes::Bytes;
+use futures::stream::{self, BoxStream, StreamExt, TryStreamExt};
+use reqwest::{Body, Client, RequestBuilder, Response, StatusCode};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use serde_json;
+use std::fmt;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::RwLock;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncSeekExt, AsyncWriteExt};
+use tokio_util::codec::{BytesCodec, FramedRead};
 
 #[derive(Serialize, Deserialize, Debug)]
 pub enum ResourceType {
@@ -14,7 +27,13 @@ pub struct Resource {
     pub _embedded: Option<ResourceList>,
     pub r#type: ResourceType,
     pub name: String,
+    /// Full path, e.g. `disk:/estatebot/first` or `trash:/first_0a1b`.
+    #[serde(default)]
+    pub path: String,
     pub file: Option<String>,
+    pub size: Option<u64>,
+    /// Where a resource in the trash was deleted from.
+    pub origin_path: Option<String>,
     pub created: String,
     pub modified: String,
 }
@@ -37,81 +56,436 @@ struct ResourceURLResponse {
     templated: bool,
 }
 
+/// Returned by `DiskClient::creat_new_file` when something already exists
+/// at the path, nothing is overwritten.
+#[derive(Debug)]
+pub struct ResourceExists {
+    pub path: String,
+}
+
+impl fmt::Display for ResourceExists {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} already exists", self.path)
+    }
+}
+
+impl std::error::Error for ResourceExists {}
+
+/// Returned when there's nothing at the path.
+#[derive(Debug)]
+pub struct ResourceNotFound {
+    pub path: String,
+}
+
+impl fmt::Display for ResourceNotFound {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} not found", self.path)
+    }
+}
+
+impl std::error::Error for ResourceNotFound {}
+
+const ALREADY_EXISTS: &'static str = "DiskResourceAlreadyExistsError";
+const NOT_FOUND: &'static str = "DiskNotFoundError";
+
+/// Longest wait between two polls of an asynchronous operation.
+const MAX_POLL_INTERVAL: Duration = Duration::from_secs(5);
+/// Polls before an operation still in progress is given up on.
+const MAX_POLLS: usize = 60;
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum OperationStatus {
+    Success,
+    Failed,
+    InProgress,
+}
+
+#[derive(Deserialize)]
+struct OperationResponse {
+    status: OperationStatus,
+}
+
+/// Items asked for per listing request, the API may return fewer.
+const LIST_PAGE_SIZE: &'static str = "1000";
+
+#[derive(Deserialize)]
+struct ErrorResponse {
+    error: String,
+}
+
+/// The `error` field of an API error response, e.g. `DiskNotFoundError`.
+fn error_code(response: &str) -> Option<String> {
+    serde_json::from_str::<ErrorResponse>(response)
+        .ok()
+        .map(|e| e.error)
+}
+
+/// Reads a response as `T`, a missing resource as `ResourceNotFound`.
+async fn parse_response<T: DeserializeOwned>(response: Response, path: &str) -> Result<T> {
+    let status = response.status();
+    let response_str = response.text().await?;
+    if status == StatusCode::NOT_FOUND && error_code(&response_str).as_deref() == Some(NOT_FOUND) {
+        return Err(ResourceNotFound {
+            path: path.to_string(),
+        }
+        .into());
+    }
+    serde_json::from_str::<T>(&response_str)
+        .with_context(|| format!("Actual server response: `{}`", &response_str))
+}
+
+/// Called with the bytes transferred so far and the total size when known.
+pub type Progress = Arc<dyn Fn(u64, Option<u64>) + Send + Sync>;
+
+/// The body of a download starting at byte `offset`, reporting progress
+/// against the full size.
+fn download_chunks(
+    response: Response,
+    offset: u64,
+    progress: Option<Progress>,
+) -> BoxStream<'static, Result<Bytes>> {
+    let total = response.content_length().map(|length| length + offset);
+    let mut received = offset;
+    response
+        .bytes_stream()
+        .map(move |chunk| -> Result<Bytes> {
+            let chunk = chunk?;
+            received += chunk.len() as u64;
+            if let Some(progress) = &progress {
+                progress(received, total);
+            }
+            Ok(chunk)
+        })
+        .boxed()
+}
+
+fn etag_path(local: &Path) -> PathBuf {
+    let mut path = local.as_os_str().to_owned();
+    path.push(".etag");
+    PathBuf::from(path)
+}
+
+async fn remove_if_exists(path: &Path) -> Result<()> {
+    match tokio::fs::remove_file(path).await {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        result => Ok(result?),
+    }
+}
+
 pub struct DiskClient {
-    token: String,
+    token: RwLock<String>,
     http_client: Client,
+    base_url: String,
+    refresher: Option<TokenRefresher>,
+    retry: RetryPolicy,
 }
 
 impl DiskClient {
     const API_URL: &'static str = "https://cloud-api.yandex.net/v1/disk";
 
     fn api_url(&self, method: &str) -> String {
-        format!("{}/{}", DiskClient::API_URL, method)
+        format!("{}/{}", self.base_url, method)
     }
 
     pub fn new(token_value: String, http_client: Client) -> Self {
         DiskClient {
-            token: token_value,
+            token: RwLock::new(token_value),
             http_client,
+            base_url: DiskClient::API_URL.to_string(),
+            refresher: None,
+            retry: RetryPolicy::default(),
         }
     }
 
-    pub async fn creat_new_file(&self, path: &str, data: Vec<u8>) -> Result<()> {
+    /// Retries for the API calls and single-request transfers, streamed
+    /// uploads are never repeated.
+    pub fn with_retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Refreshes the token with `refresher` when the API rejects it.
+    pub fn with_refresher(mut self, refresher: TokenRefresher) -> Self {
+        self.refresher = Some(refresher);
+        self
+    }
+
+    fn authorization(&self) -> String {
+        format!("OAuth {}", self.token.read().unwrap())
+    }
+
+    /// Sends the request built by `request` with the current token. A
+    /// rejected token is refreshed once and the request repeated.
+    async fn send_api<F>(&self, request: F) -> Result<Response>
+    where
+        F: Fn() -> RequestBuilder,
+    {
+        let response = self
+            .retry
+            .send(|| request().header(reqwest::header::AUTHORIZATION, self.authorization()))
+            .await?;
+        if response.status() != StatusCode::UNAUTHORIZED {
+            return Ok(response);
+        }
+
+        let refresher = match &self.refresher {
+            Some(refresher) => refresher,
+            None => bail!("Yandex Disk rejected the OAuth token, it's invalid or expired"),
+        };
+        let token = refresher
+            .refresh()
+            .await
+            .context("failed to refresh the Yandex Disk OAuth token")?;
+        *self.token.write().unwrap() = token;
+
+        let response = self
+            .retry
+            .send(|| request().header(reqwest::header::AUTHORIZATION, self.authorization()))
+            .await?;
+        Ok(response)
+    }
+
+    /// Talks to `base_url` instead of the public Disk API.
+    pub fn with_api_url(mut self, base_url: String) -> Self {
+        self.base_url = base_url.trim_end_matches('/').to_string();
+        self
+    }
+
+    async fn upload_link(&self, path: &str) -> Result<ResourceURLResponse> {
+        let response = self
+            .send_api(|| {
+                self.http_client
+                    .get(&self.api_url("resources/upload"))
+                    .header(reqwest::header::CONTENT_TYPE, "application/json")
+                    .query(&[("path", path), ("overwrite", "false")])
+            })
+            .await?;
+        let conflict = response.status() == StatusCode::CONFLICT;
+        let response_str = response.text().await?;
+        // 409 is also returned when the parent folder is missing
+        if conflict && error_code(&response_str).as_deref() == Some(ALREADY_EXISTS) {
+            return Err(ResourceExists {
+                path: path.to_string(),
+            }
+            .into());
+        }
+
+        let response = serde_json::from_str::<ResourceURLResponse>(&response_str)
+            .with_context(|| format!("Actual server response: `{}`", &response_str))?;
+        Ok(response)
+    }
+
+    async fn download_link(&self, path: &str) -> Result<ResourceURLResponse> {
         let response_str = self
-            .http_client
-            .get(&self.api_url("resources/upload"))
-            .header(reqwest::header::CONTENT_TYPE, "application/json")
-            .header(
-                reqwest::header::AUTHORIZATION,
-                format!("OAuth {}", self.token),
-            )
-            .query(&[("path", path)])
-            .send()
+            .send_api(|| {
+                self.http_client
+                    .get(&self.api_url("resources/download"))
+                    .header(reqwest::header::CONTENT_TYPE, "application/json")
+                    .query(&[("path", path)])
+            })
             .await?
             .text()
             .await?;
 
         let response = serde_json::from_str::<ResourceURLResponse>(&response_str)
-            .with_context(|| format!("Actual server response: `{}`", &response_str))?;
+            .with_context(|| format!("Actual response: `{}`", &response_str))?;
+        Ok(response)
+    }
+
+    /// Fails with `ResourceExists` instead of overwriting an existing file.
+    pub async fn creat_new_file(&self, path: &str, data: Vec<u8>) -> Result<()> {
+        let response = self.upload_link(path).await?;
 
         let _response_str = self
-            .http_client
-            .put(&response.href)
-            .header(reqwest::header::CONTENT_TYPE, "application/json")
-            .header(
-                reqwest::header::AUTHORIZATION,
-                format!("OAuth {}", self.token),
-            )
-            .body(data)
-            .send()
+            .retry
+            .send(|| {
+                self.http_client
+                    .put(&response.href)
+                    .header(reqwest::header::CONTENT_TYPE, "application/json")
+                    .header(reqwest::header::AUTHORIZATION, self.authorization())
+                    .body(data.clone())
+            })
             .await?
+            .error_for_status()
+            .with_context(|| format!("failed to upload {}", path))?
             .text()
             .await?;
 
         Ok(())
     }
 
-    pub async fn read_file(&self, path: &str) -> Result<Vec<u8>> {
-        let response_str = self
+    /// Uploads everything `reader` yields without holding it in memory.
+    /// `size` is sent as the content length when known. Like
+    /// `creat_new_file`, fails with `ResourceExists` for an existing file.
+    pub async fn upload_stream<R>(
+        &self,
+        path: &str,
+        reader: R,
+        size: Option<u64>,
+        progress: Option<Progress>,
+    ) -> Result<()>
+    where
+        R: AsyncRead + Send + Sync + 'static,
+    {
+        let response = self.upload_link(path).await?;
+
+        let mut sent = 0;
+        let chunks = FramedRead::new(reader, BytesCodec::new()).map_ok(move |chunk| {
+            sent += chunk.len() as u64;
+            if let Some(progress) = &progress {
+                progress(sent, size);
+            }
+            chunk.freeze()
+        });
+
+        let mut request = self
             .http_client
-            .get(&self.api_url("resources/download"))
-            .header(reqwest::header::CONTENT_TYPE, "application/json")
-            .header(
-                reqwest::header::AUTHORIZATION,
-                format!("OAuth {}", self.token),
-            )
-            .query(&[("path", path)])
+            .put(&response.href)
+            .header(reqwest::header::CONTENT_TYPE, "application/octet-stream")
+            .header(reqwest::header::AUTHORIZATION, self.authorization())
+            .body(Body::wrap_stream(chunks));
+        if let Some(size) = size {
+            request = request.header(reqwest::header::CONTENT_LENGTH, size);
+        }
+        request
             .send()
             .await?
-            .text()
-            .await?;
+            .error_for_status()
+            .with_context(|| format!("failed to upload {}", path))?;
 
-        let response = serde_json::from_str::<ResourceURLResponse>(&response_str)
-            .with_context(|| format!("Actual response: `{}`", &response_str))?;
+        Ok(())
+    }
+
+    pub async fn read_file(&self, path: &str) -> Result<Vec<u8>> {
+        let response = self.download_link(path).await?;
 
         Ok(self.read_url(&response.href).await?)
     }
 
+    /// Streams the file starting at byte `offset`, so an interrupted
+    /// download can continue where it stopped.
+    pub async fn download_stream(
+        &self,
+        path: &str,
+        offset: u64,
+        progress: Option<Progress>,
+    ) -> Result<BoxStream<'static, Result<Bytes>>> {
+        let response = self.download_from(path, offset, None).await?;
+        match response.status() {
+            // nothing left after `offset`
+            StatusCode::RANGE_NOT_SATISFIABLE => return Ok(stream::empty().boxed()),
+            StatusCode::PARTIAL_CONTENT => {}
+            _ if offset > 0 => bail!("server ignored the range request for {}", path),
+            _ => {}
+        }
+        Ok(download_chunks(response, offset, progress))
+    }
+
+    /// Requests the file from byte `offset`. With `if_range` the server
+    /// answers with the whole file instead, unless it still has that ETag.
+    async fn download_from(
+        &self,
+        path: &str,
+        offset: u64,
+        if_range: Option<&str>,
+    ) -> Result<Response> {
+        let response = self.download_link(path).await?;
+
+        let response = self
+            .retry
+            .send(|| {
+                let mut request = self
+                    .http_client
+                    .get(&response.href)
+                    .header(reqwest::header::AUTHORIZATION, self.authorization());
+                if offset > 0 {
+                    request = request.header(reqwest::header::RANGE, format!("bytes={}-", offset));
+                    if let Some(etag) = if_range {
+                        request = request.header(reqwest::header::IF_RANGE, etag);
+                    }
+                }
+                request
+            })
+            .await?;
+        if offset > 0 && response.status() == StatusCode::RANGE_NOT_SATISFIABLE {
+            return Ok(response);
+        }
+        response
+            .error_for_status()
+            .with_context(|| format!("failed to download {}", path))
+    }
+
+    /// Downloads into `local`, resuming after whatever an interrupted
+    /// earlier call left there. The ETag of the download is kept next to
+    /// it in `{local}.etag`, a partial file is only resumed while the file
+    /// on the disk still has it. Returns the size of the complete file.
+    pub async fn download_to_file(
+        &self,
+        path: &str,
+        local: &Path,
+        progress: Option<Progress>,
+    ) -> Result<u64> {
+        let size = self
+            .get_meta(path)
+            .await?
+            .size
+            .with_context(|| format!("{} isn't a file", path))?;
+        let etag_file = etag_path(local);
+        let etag = tokio::fs::read_to_string(&etag_file).await.ok();
+
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .write(true)
+            .open(local)
+            .await
+            .with_context(|| format!("failed to open {}", local.display()))?;
+        let mut written = match etag {
+            Some(_) => file.metadata().await?.len(),
+            // the partial bytes can't be checked against anything
+            None => 0,
+        };
+
+        let response = self.download_from(path, written, etag.as_deref()).await?;
+        match response.status() {
+            // everything is already there, the size is checked below
+            StatusCode::RANGE_NOT_SATISFIABLE => {}
+            StatusCode::PARTIAL_CONTENT => {}
+            // the file changed since, or the range was ignored
+            _ => written = 0,
+        }
+        match response.headers().get(reqwest::header::ETAG) {
+            Some(etag) => tokio::fs::write(&etag_file, etag.as_bytes()).await?,
+            None => remove_if_exists(&etag_file).await?,
+        }
+        file.set_len(written).await?;
+        file.seek(SeekFrom::Start(written)).await?;
+
+        if response.status() != StatusCode::RANGE_NOT_SATISFIABLE {
+            let mut chunks = download_chunks(response, written, progress);
+            while let Some(chunk) = chunks.next().await {
+                let chunk = chunk?;
+                file.write_all(&chunk).await?;
+                written += chunk.len() as u64;
+            }
+        }
+        file.sync_all().await?;
+
+        if written != size {
+            // the next call starts over
+            remove_if_exists(&etag_file).await?;
+            bail!(
+                "downloaded {} bytes of {} into {}, expected {}",
+                written,
+                path,
+                local.display(),
+                size
+            );
+        }
+        remove_if_exists(&etag_file).await?;
+        Ok(written)
+    }
+
     pub async fn read_file_from_resource(&self, resource: Resource) -> Result<Vec<u8>> {
         let url = &resource.file.context(format!(
             "Resource doesn't have a link to a file. (file = None)"
@@ -121,117 +495,641 @@ impl DiskClient {
 
     pub async fn read_url(&self, url: &str) -> Result<Vec<u8>> {
         let response_str = self
-            .http_client
-            .get(url)
-            .header(reqwest::header::CONTENT_TYPE, "application/json")
-            .header(
-                reqwest::header::AUTHORIZATION,
-                format!("OAuth {}", self.token),
-            )
-            .send()
+            .retry
+            .send(|| {
+                self.http_client
+                    .get(url)
+                    .header(reqwest::header::CONTENT_TYPE, "application/json")
+                    .header(reqwest::header::AUTHORIZATION, self.authorization())
+            })
             .await?
+            .error_for_status()?
             .bytes()
             .await?;
 
         Ok(response_str.to_vec())
     }
 
+    /// Everything in `folder`, fetched a page at a time until the listing's
+    /// `total` is reached. `ResourceNotFound` when there's no such folder.
     pub async fn list_all_files(&self, folder: &str) -> Result<Vec<Resource>> {
-        let response_str = self
-            .http_client
-            .get(&self.api_url("resources"))
-            .header(reqwest::header::CONTENT_TYPE, "application/json")
-            .header(
-                reqwest::header::AUTHORIZATION,
-                format!("OAuth {}", self.token),
-            )
-            // .bearer_auth(&self.token)
-            .query(&[
-                ("limit", "1000"),
-                ("path", folder),
-                ("preview_crop", "true"),
-            ])
-            .send()
-            .await?
-            .text()
+        self.list_pages("resources", folder).await
+    }
+
+    /// Everything in the trash.
+    pub async fn list_trash(&self) -> Result<Vec<Resource>> {
+        self.list_pages("trash/resources", "trash:/").await
+    }
+
+    async fn list_pages(&self, endpoint: &str, folder: &str) -> Result<Vec<Resource>> {
+        let mut items = Vec::new();
+        loop {
+            let offset = items.len().to_string();
+            let response = self
+                .send_api(|| {
+                    self.http_client
+                        .get(&self.api_url(endpoint))
+                        .header(reqwest::header::CONTENT_TYPE, "application/json")
+                        .query(&[
+                            ("limit", LIST_PAGE_SIZE),
+                            ("offset", offset.as_str()),
+                            ("path", folder),
+                            ("preview_crop", "true"),
+                        ])
+                })
+                .await?;
+
+            let resource: Resource = parse_response(response, folder).await?;
+            let page = match resource._embedded {
+                Some(page) => page,
+                None => break,
+            };
+            let total = page.total;
+            if page.items.is_empty() {
+                break;
+            }
+            items.extend(page.items);
+            if total.map_or(true, |total| items.len() as u64 >= total) {
+                break;
+            }
+        }
+        Ok(items)
+    }
+
+    /// Metadata of the file or folder at `path`, `ResourceNotFound` when
+    /// there's nothing.
+    pub async fn get_meta(&self, path: &str) -> Result<Resource> {
+        let response = self
+            .send_api(|| {
+                self.http_client
+                    .get(&self.api_url("resources"))
+                    .header(reqwest::header::CONTENT_TYPE, "application/json")
+                    .query(&[("path", path)])
+            })
             .await?;
+        parse_response(response, path).await
+    }
 
-        let resource = serde_json::from_str::<Resource>(&response_str)
-            .with_context(|| format!("Actual server response: `{}`", &response_str))?;
+    /// Creates the folder at `path` along with any missing parents,
+    /// existing ones are left as is.
+    pub async fn create_folder(&self, path: &str) -> Result<()> {
+        let path = path.trim_end_matches('/');
+        // `disk:/` and `app:/` roots aren't folders to create
+        let root = path.find(":/").map_or(0, |i| i + 2);
+        for (end, _) in path.match_indices('/').filter(|(end, _)| *end > root) {
+            self.create_single_folder(&path[..end]).await?;
+        }
+        self.create_single_folder(path).await
+    }
+
+    async fn create_single_folder(&self, path: &str) -> Result<()> {
+        let response = self
+            .send_api(|| {
+                self.http_client
+                    .put(&self.api_url("resources"))
+                    .header(reqwest::header::CONTENT_TYPE, "application/json")
+                    .query(&[("path", path)])
+            })
+            .await?;
+        if response.status().is_success() {
+            return Ok(());
+        }
+        let status = response.status();
+        let response_str = response.text().await?;
+        if status == StatusCode::CONFLICT
+            && error_code(&response_str).as_deref() == Some(ALREADY_EXISTS)
+        {
+            return Ok(());
+        }
+        bail!(
+            "failed to create folder {}: {} `{}`",
+            path,
+            status,
+            response_str
+        )
+    }
+
+    /// Moves the file or folder `from` to `path`. Fails with
+    /// `ResourceExists` when something is there unless `overwrite` is set.
+    pub async fn move_resource(&self, from: &str, path: &str, overwrite: bool) -> Result<()> {
+        self.relocate("move", from, path, overwrite).await
+    }
+
+    /// Copies the file or folder `from` to `path`, like `move_resource`.
+    pub async fn copy_resource(&self, from: &str, path: &str, overwrite: bool) -> Result<()> {
+        self.relocate("copy", from, path, overwrite).await
+    }
 
-        Ok(resource._embedded.map_or(Vec::new(), |e| e.items))
+    async fn relocate(&self, action: &str, from: &str, path: &str, overwrite: bool) -> Result<()> {
+        let endpoint = format!("resources/{}", action);
+        let overwrite = overwrite.to_string();
+        let response = self
+            .send_api(|| {
+                self.http_client
+                    .post(&self.api_url(&endpoint))
+                    .header(reqwest::header::CONTENT_TYPE, "application/json")
+                    .query(&[
+                        ("from", from),
+                        ("path", path),
+                        ("overwrite", overwrite.as_str()),
+                    ])
+            })
+            .await?;
+        self.complete(response, path)
+            .await
+            .with_context(|| format!("failed to {} {} to {}", action, from, path))
+    }
+
+    /// Moves `path` in the trash, e.g. `trash:/first_0a1b`, back to where
+    /// it was deleted from.
+    pub async fn restore(&self, path: &str) -> Result<()> {
+        let response = self
+            .send_api(|| {
+                self.http_client
+                    .put(&self.api_url("trash/resources/restore"))
+                    .header(reqwest::header::CONTENT_TYPE, "application/json")
+                    .query(&[("path", path)])
+            })
+            .await?;
+        self.complete(response, path)
+            .await
+            .with_context(|| format!("failed to restore {}", path))
     }
 
+    /// Moves `path` to the trash.
     pub async fn delete(&self, path: &str) -> Result<()> {
-        let _response_str = self
-            .http_client
-            .delete(&self.api_url("resources"))
-            .header(reqwest::header::CONTENT_TYPE, "application/json")
-            .header(
-                reqwest::header::AUTHORIZATION,
-                format!("OAuth {}", self.token),
-            )
-            .query(&[("path", path)])
-            .send()
+        let response = self
+            .send_api(|| {
+                self.http_client
+                    .delete(&self.api_url("resources"))
+                    .header(reqwest::header::CONTENT_TYPE, "application/json")
+                    .query(&[("path", path)])
+            })
+            .await?;
+        self.complete(response, path)
+            .await
+            .with_context(|| format!("failed to delete {}", path))
+    }
+
+    /// Result of a request changing `path`. Disk answers 202 with a link to
+    /// the operation when it carries the change out asynchronously, that's
+    /// waited for.
+    async fn complete(&self, response: Response, path: &str) -> Result<()> {
+        let status = response.status();
+        let response_str = response.text().await?;
+        if status == StatusCode::ACCEPTED {
+            let link = serde_json::from_str::<ResourceURLResponse>(&response_str)
+                .with_context(|| format!("Actual server response: `{}`", &response_str))?;
+            // the operation link ends with its id
+            let operation_id = match link.operation_id {
+                Some(operation_id) => operation_id,
+                None => link
+                    .href
+                    .split('?')
+                    .next()
+                    .and_then(|href| href.trim_end_matches('/').rsplit('/').next())
+                    .unwrap_or_default()
+                    .to_string(),
+            };
+            return self.wait_for_operation(&operation_id).await;
+        }
+        if status.is_success() {
+            return Ok(());
+        }
+        if status == StatusCode::CONFLICT
+            && error_code(&response_str).as_deref() == Some(ALREADY_EXISTS)
+        {
+            return Err(ResourceExists {
+                path: path.to_string(),
+            }
+            .into());
+        }
+        bail!("{} `{}`", status, response_str)
+    }
+
+    pub async fn operation_status(&self, operation_id: &str) -> Result<OperationStatus> {
+        let endpoint = format!("operations/{}", operation_id);
+        let response_str = self
+            .send_api(|| {
+                self.http_client
+                    .get(&self.api_url(&endpoint))
+                    .header(reqwest::header::CONTENT_TYPE, "application/json")
+            })
             .await?
             .text()
             .await?;
 
-        Ok(())
+        let response = serde_json::from_str::<OperationResponse>(&response_str)
+            .with_context(|| format!("Actual server response: `{}`", &response_str))?;
+        Ok(response.status)
+    }
+
+    /// Polls the operation `operation_id`, e.g. the move of a big folder,
+    /// until it's finished.
+    pub async fn wait_for_operation(&self, operation_id: &str) -> Result<()> {
+        let mut interval = Duration::from_millis(100);
+        for _ in 0..MAX_POLLS {
+            match self.operation_status(operation_id).await? {
+                OperationStatus::Success => return Ok(()),
+                OperationStatus::Failed => bail!("operation {} failed", operation_id),
+                OperationStatus::InProgress => {
+                    tokio::time::delay_for(interval).await;
+                    interval = (interval * 2).min(MAX_POLL_INTERVAL);
+                }
+            }
+        }
+        bail!(
+            "operation {} still in progress after {} polls",
+            operation_id,
+            MAX_POLLS
+        )
     }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::api::test_support::{FakeDisk, FakeOAuth};
+    use crate::api::yandex_oauth::{OAuthClient, TokenFile};
+    use crate::http::HttpConfig;
+
+    const AUTH_KEY: &'static str = "test-token";
+
+    fn client(server: &FakeDisk) -> DiskClient {
+        DiskClient::new(AUTH_KEY.to_string(), Client::new())
+            .with_api_url(server.api_url())
+            .with_retry(RetryPolicy::from_config(&HttpConfig {
+                base_delay_ms: 1,
+                ..HttpConfig::default()
+            }))
+    }
 
-    const AUTH_KEY: &'static str = "TODO:<Yandex.API Key>";
+    #[tokio::test]
+    async fn test_list_files() {
+        let server = FakeDisk::start(AUTH_KEY).await;
+        server.put_file("/estatebot/first", b"1");
+        server.put_file("/estatebot/second", b"2");
+        server.put_file("/elsewhere/third", b"3");
 
-    // const INTEGRATION_TEST_PREFIX: &'static str = "INTEGRATION_TEST";
+        let names: Vec<String> = client(&server)
+            .list_all_files("/estatebot")
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|r| r.name)
+            .collect();
+        assert_eq!(names, vec!["first", "second"]);
+        assert!(client(&server).list_all_files("/missing").await.is_err());
+    }
 
-    // use rand::{thread_rng, Rng};
-    // use rand::distributions::Alphanumeric;
+    #[tokio::test]
+    async fn test_list_files_in_pages() {
+        let server = FakeDisk::start(AUTH_KEY).await;
+        for i in 0..5 {
+            server.put_file(&format!("/estatebot/file_{}", i), b"x");
+        }
+        server.limit_page_size(2);
 
-    // #[test]
-    // fn test_yandex_disk_integration() {
-    //     let client = reqwest::Client::new();
-    //     let disk = DiskClient::new(AUTH_KEY.to_string(), client);
-    //     let rand_string: String = thread_rng()
-    //         .sample_iter(&Alphanumeric)
-    //         .take(10)
-    //         .collect();
+        let names: Vec<String> = client(&server)
+            .list_all_files("/estatebot")
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|r| r.name)
+            .collect();
+        assert_eq!(
+            names,
+            vec!["file_0", "file_1", "file_2", "file_3", "file_4"]
+        );
+    }
 
-    // }
+    #[tokio::test]
+    async fn test_create_file() {
+        let server = FakeDisk::start(AUTH_KEY).await;
+        let disk = client(&server);
 
-    #[test]
-    fn test_list_files() {
-        let client = reqwest::Client::new();
-        let disk = DiskClient::new(AUTH_KEY.to_string(), client);
-        println!(
-            "{:?}",
-            tokio_test::block_on(disk.list_all_files("/estatebot"))
+        disk.creat_new_file("/estatebot/ololool2", b"jojojopappa".to_vec())
+            .await
+            .unwrap();
+        assert_eq!(
+            server.file("/estatebot/ololool2"),
+            Some(b"jojojopappa".to_vec())
+        );
+
+        let err = disk
+            .creat_new_file("/estatebot/ololool2", b"other".to_vec())
+            .await
+            .unwrap_err();
+        assert!(err.downcast_ref::<ResourceExists>().is_some());
+        assert_eq!(
+            server.file("/estatebot/ololool2"),
+            Some(b"jojojopappa".to_vec())
         );
     }
 
-    #[test]
-    fn test_create_file() {
-        let client = reqwest::Client::new();
-        let disk = DiskClient::new(AUTH_KEY.to_string(), client);
-        println!(
-            "{:?}",
-            tokio_test::block_on(
-                disk.creat_new_file("/estatebot/ololool2", "jojojopappa".as_bytes().to_vec())
-            )
+    #[tokio::test]
+    async fn test_read_file() {
+        let server = FakeDisk::start(AUTH_KEY).await;
+        server.put_file("/estatebot/first", b"content");
+        let disk = client(&server);
+
+        assert_eq!(
+            disk.read_file("/estatebot/first").await.unwrap(),
+            b"content".to_vec()
+        );
+        assert!(disk.read_file("/estatebot/missing").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_create_folder() {
+        let server = FakeDisk::start(AUTH_KEY).await;
+        let disk = client(&server);
+
+        disk.create_folder("/estatebot").await.unwrap();
+        disk.create_folder("/estatebot").await.unwrap();
+        assert!(disk.list_all_files("/estatebot").await.unwrap().is_empty());
+
+        disk.create_folder("/estatebot/photos/105-111111/")
+            .await
+            .unwrap();
+        assert!(disk
+            .list_all_files("/estatebot/photos/105-111111")
+            .await
+            .unwrap()
+            .is_empty());
+        let err = disk.list_all_files("/missing").await.unwrap_err();
+        assert!(err.downcast_ref::<ResourceNotFound>().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_get_meta() {
+        let server = FakeDisk::start(AUTH_KEY).await;
+        server.put_file("/estatebot/first", b"content");
+        let disk = client(&server);
+
+        let file = disk.get_meta("/estatebot/first").await.unwrap();
+        assert_eq!(file.name, "first");
+        assert_eq!(file.path, "disk:/estatebot/first");
+        assert_eq!(file.size, Some(7));
+        let folder = disk.get_meta("/estatebot").await.unwrap();
+        assert!(matches!(folder.r#type, ResourceType::dir));
+
+        let err = disk.get_meta("/estatebot/missing").await.unwrap_err();
+        assert!(err.downcast_ref::<ResourceNotFound>().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_move_and_copy() {
+        let server = FakeDisk::start(AUTH_KEY).await;
+        server.put_file("/estatebot/first", b"1");
+        server.put_file("/estatebot/second", b"2");
+        let disk = client(&server);
+
+        disk.copy_resource("/estatebot/first", "/estatebot/copy", false)
+            .await
+            .unwrap();
+        assert_eq!(server.file("/estatebot/first"), Some(b"1".to_vec()));
+        assert_eq!(server.file("/estatebot/copy"), Some(b"1".to_vec()));
+
+        let err = disk
+            .move_resource("/estatebot/second", "/estatebot/copy", false)
+            .await
+            .unwrap_err();
+        assert!(err.downcast_ref::<ResourceExists>().is_some());
+        disk.move_resource("/estatebot/second", "/estatebot/copy", true)
+            .await
+            .unwrap();
+        assert_eq!(server.file("/estatebot/second"), None);
+        assert_eq!(server.file("/estatebot/copy"), Some(b"2".to_vec()));
+
+        disk.move_resource("/estatebot", "/archive", false)
+            .await
+            .unwrap();
+        assert_eq!(server.file("/archive/first"), Some(b"1".to_vec()));
+        assert!(disk.get_meta("/estatebot").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_asynchronous_operations_are_waited_for() {
+        let server = FakeDisk::start(AUTH_KEY).await;
+        server.put_file("/estatebot/first", b"1");
+        server.run_operations_async();
+        let disk = client(&server);
+
+        disk.move_resource("/estatebot/first", "/estatebot/moved", false)
+            .await
+            .unwrap();
+        assert_eq!(server.file("/estatebot/moved"), Some(b"1".to_vec()));
+        assert!(disk.wait_for_operation("unknown").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_trash_and_restore() {
+        let server = FakeDisk::start(AUTH_KEY).await;
+        server.put_file("/estatebot/first", b"content");
+        let disk = client(&server);
+
+        disk.delete("/estatebot/first").await.unwrap();
+        let trash = disk.list_trash().await.unwrap();
+        assert_eq!(trash.len(), 1);
+        assert_eq!(
+            trash[0].origin_path.as_deref(),
+            Some("disk:/estatebot/first")
+        );
+
+        disk.restore(&trash[0].path).await.unwrap();
+        assert_eq!(server.file("/estatebot/first"), Some(b"content".to_vec()));
+        assert!(disk.list_trash().await.unwrap().is_empty());
+        assert!(disk.restore(&trash[0].path).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_server_errors_are_retried() {
+        let server = FakeDisk::start(AUTH_KEY).await;
+        server.put_file("/estatebot/first", b"content");
+        let disk = client(&server);
+
+        server.fail_next(StatusCode::SERVICE_UNAVAILABLE);
+        server.fail_next(StatusCode::BAD_GATEWAY);
+        assert_eq!(
+            disk.read_file("/estatebot/first").await.unwrap(),
+            b"content".to_vec()
+        );
+
+        for _ in 0..4 {
+            server.fail_next(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+        assert!(disk.list_all_files("/estatebot").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_delete() {
+        let server = FakeDisk::start(AUTH_KEY).await;
+        server.put_file("/estatebot/first", b"content");
+        let disk = client(&server);
+
+        disk.delete("/estatebot/first").await.unwrap();
+        assert_eq!(server.file("/estatebot/first"), None);
+        assert!(disk.delete("/estatebot/first").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_expired_token_is_refreshed() {
+        let server = FakeDisk::start(AUTH_KEY).await;
+        server.put_file("/estatebot/first", b"content");
+        let oauth_server = FakeOAuth::start("app", "refresh", AUTH_KEY).await;
+        let token_path =
+            std::env::temp_dir().join(format!("scrappybot_disk_token_{}.json", std::process::id()));
+        let token_path = token_path.display().to_string();
+        let refresher = TokenRefresher::new(
+            OAuthClient::new("app".to_string(), "secret".to_string(), Client::new())
+                .with_api_url(oauth_server.url()),
+            TokenFile::new(&token_path),
+            "refresh".to_string(),
+        );
+        let disk = DiskClient::new("expired".to_string(), Client::new())
+            .with_api_url(server.api_url())
+            .with_refresher(refresher);
+
+        assert_eq!(disk.list_all_files("/estatebot").await.unwrap().len(), 1);
+        assert_eq!(
+            disk.read_file("/estatebot/first").await.unwrap(),
+            b"content".to_vec()
         );
+        let saved = TokenFile::new(&token_path).load().unwrap().unwrap();
+        assert_eq!(saved.access_token, AUTH_KEY);
+        std::fs::remove_file(token_path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_wrong_token_is_rejected() {
+        let server = FakeDisk::start(AUTH_KEY).await;
+        server.put_file("/estatebot/first", b"content");
+        let disk =
+            DiskClient::new("wrong".to_string(), Client::new()).with_api_url(server.api_url());
+
+        assert!(disk.list_all_files("/estatebot").await.is_err());
+        assert!(disk.read_file("/estatebot/first").await.is_err());
+        assert!(disk
+            .creat_new_file("/estatebot/new", b"x".to_vec())
+            .await
+            .is_err());
+        assert_eq!(server.file("/estatebot/new"), None);
     }
 
-    #[test]
-    fn test_read_file() {
-        let client = reqwest::Client::new();
-        let disk = DiskClient::new(AUTH_KEY.to_string(), client);
-        println!(
-            "{:?}",
-            String::from_utf8(tokio_test::block_on(disk.read_file("/estatebot/first")).unwrap())
+    #[tokio::test]
+    async fn test_upload_stream() {
+        let server = FakeDisk::start(AUTH_KEY).await;
+        let data = b"0123456789".repeat(1000);
+        let reported = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let progress_reported = reported.clone();
+        let progress: Progress = Arc::new(move |sent, total| {
+            progress_reported.lock().unwrap().push((sent, total));
+        });
+
+        client(&server)
+            .upload_stream(
+                "/estatebot/export",
+                std::io::Cursor::new(data.clone()),
+                Some(data.len() as u64),
+                Some(progress),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(server.file("/estatebot/export"), Some(data.clone()));
+        let reported = reported.lock().unwrap();
+        assert_eq!(
+            reported.last(),
+            Some(&(data.len() as u64, Some(data.len() as u64)))
         );
     }
+
+    #[tokio::test]
+    async fn test_download_stream_from_offset() {
+        let server = FakeDisk::start(AUTH_KEY).await;
+        server.put_file("/estatebot/export", b"0123456789");
+        let disk = client(&server);
+
+        let chunks: Vec<Bytes> = disk
+            .download_stream("/estatebot/export", 4, None)
+            .await
+            .unwrap()
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(chunks.concat(), b"456789".to_vec());
+
+        let rest: Vec<Bytes> = disk
+            .download_stream("/estatebot/export", 10, None)
+            .await
+            .unwrap()
+            .try_collect()
+            .await
+            .unwrap();
+        assert!(rest.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_download_to_file_resumes() {
+        let server = FakeDisk::start(AUTH_KEY).await;
+        server.put_file("/estatebot/export", b"0123456789");
+        let local = std::env::temp_dir().join(format!("scrappybot_resume_{}", std::process::id()));
+        std::fs::write(&local, b"0123").unwrap();
+        std::fs::write(etag_path(&local), server.etag("/estatebot/export").unwrap()).unwrap();
+
+        let size = client(&server)
+            .download_to_file("/estatebot/export", &local, None)
+            .await
+            .unwrap();
+        assert_eq!(size, 10);
+        assert_eq!(std::fs::read(&local).unwrap(), b"0123456789".to_vec());
+        assert!(!etag_path(&local).exists());
+        std::fs::remove_file(local).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_download_to_file_restarts_when_the_file_changed() {
+        let server = FakeDisk::start(AUTH_KEY).await;
+        server.put_file("/estatebot/export", b"0123456789");
+        let local = std::env::temp_dir().join(format!("scrappybot_restart_{}", std::process::id()));
+        let disk = client(&server);
+
+        std::fs::write(&local, b"abcdefghijkl").unwrap();
+        std::fs::write(etag_path(&local), "\"stale\"").unwrap();
+        let size = disk
+            .download_to_file("/estatebot/export", &local, None)
+            .await
+            .unwrap();
+        assert_eq!(size, 10);
+        assert_eq!(std::fs::read(&local).unwrap(), b"0123456789".to_vec());
+
+        // without an ETag the partial file can't be trusted either
+        std::fs::write(&local, b"abcd").unwrap();
+        let size = disk
+            .download_to_file("/estatebot/export", &local, None)
+            .await
+            .unwrap();
+        assert_eq!(size, 10);
+        assert_eq!(std::fs::read(&local).unwrap(), b"0123456789".to_vec());
+        std::fs::remove_file(local).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_download_to_file_checks_the_size() {
+        let server = FakeDisk::start(AUTH_KEY).await;
+        server.put_file("/estatebot/export", b"0123456789");
+        let local = std::env::temp_dir().join(format!("scrappybot_size_{}", std::process::id()));
+        std::fs::write(&local, b"0123456789ab").unwrap();
+        std::fs::write(etag_path(&local), server.etag("/estatebot/export").unwrap()).unwrap();
+
+        // the range is past the end, which isn't the same as complete
+        let err = client(&server)
+            .download_to_file("/estatebot/export", &local, None)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("expected 10"), "{}", err);
+        assert!(!etag_path(&local).exists());
+        std::fs::remove_file(local).unwrap();
+    }
 }
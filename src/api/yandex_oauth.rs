@@ -0,0 +1,341 @@
+use crate::http::RetryPolicy;
+use anyhow::{bail, Context, Result};
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs;
+use std::io::Write;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::Mutex;
+
+const AUTHORIZATION_PENDING: &'static str = "authorization_pending";
+
+#[derive(Debug, Deserialize)]
+pub struct DeviceCode {
+    pub device_code: String,
+    /// Code the user enters at `verification_url`.
+    pub user_code: String,
+    pub verification_url: String,
+    /// Seconds to wait between polls.
+    pub interval: u64,
+    pub expires_in: u64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TokenResponse {
+    pub access_token: String,
+    #[serde(default)]
+    pub refresh_token: Option<String>,
+    #[serde(default)]
+    pub expires_in: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ErrorResponse {
+    error: String,
+    #[serde(default)]
+    error_description: Option<String>,
+}
+
+/// Error answer of the token endpoint, e.g. `authorization_pending` while
+/// the user hasn't confirmed the device code yet.
+#[derive(Debug)]
+pub struct OAuthError {
+    pub error: String,
+    pub description: String,
+}
+
+impl fmt::Display for OAuthError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.error, self.description)
+    }
+}
+
+impl std::error::Error for OAuthError {}
+
+/// Client of the Yandex OAuth server for an app registered at
+/// oauth.yandex.ru.
+pub struct OAuthClient {
+    client_id: String,
+    client_secret: String,
+    http_client: Client,
+    base_url: String,
+    retry: RetryPolicy,
+}
+
+impl OAuthClient {
+    const OAUTH_URL: &'static str = "https://oauth.yandex.ru";
+
+    pub fn new(client_id: String, client_secret: String, http_client: Client) -> Self {
+        OAuthClient {
+            client_id: client_id,
+            client_secret: client_secret,
+            http_client: http_client,
+            base_url: OAuthClient::OAUTH_URL.to_string(),
+            retry: RetryPolicy::default(),
+        }
+    }
+
+    /// Retries of failures to connect, the requests themselves are never
+    /// repeated as a device code or refresh token may be used up by them.
+    pub fn with_retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Talks to `base_url` instead of oauth.yandex.ru.
+    pub fn with_api_url(mut self, base_url: String) -> Self {
+        self.base_url = base_url.trim_end_matches('/').to_string();
+        self
+    }
+
+    /// Starts the device-code authorization, the user then confirms it in a
+    /// browser while `poll_device_token` waits.
+    pub async fn request_device_code(&self) -> Result<DeviceCode> {
+        let response_str = self
+            .retry
+            .send_once(|| {
+                self.http_client
+                    .post(&format!("{}/device/code", self.base_url))
+                    .form(&[("client_id", self.client_id.as_str())])
+            })
+            .await?
+            .text()
+            .await?;
+
+        let code = serde_json::from_str::<DeviceCode>(&response_str)
+            .with_context(|| format!("Actual server response: `{}`", &response_str))?;
+        Ok(code)
+    }
+
+    /// The tokens once the user confirmed, `None` while still pending.
+    pub async fn poll_device_token(&self, device_code: &str) -> Result<Option<TokenResponse>> {
+        match self
+            .token(&[("grant_type", "device_code"), ("code", device_code)])
+            .await
+        {
+            Ok(token) => Ok(Some(token)),
+            Err(e) => match e.downcast_ref::<OAuthError>() {
+                Some(error) if error.error == AUTHORIZATION_PENDING => Ok(None),
+                _ => Err(e),
+            },
+        }
+    }
+
+    pub async fn refresh(&self, refresh_token: &str) -> Result<TokenResponse> {
+        self.token(&[
+            ("grant_type", "refresh_token"),
+            ("refresh_token", refresh_token),
+        ])
+        .await
+    }
+
+    async fn token(&self, grant: &[(&str, &str)]) -> Result<TokenResponse> {
+        let mut form = grant.to_vec();
+        form.push(("client_id", self.client_id.as_str()));
+        form.push(("client_secret", self.client_secret.as_str()));
+
+        let response = self
+            .retry
+            .send_once(|| {
+                self.http_client
+                    .post(&format!("{}/token", self.base_url))
+                    .form(&form)
+            })
+            .await?;
+        let success = response.status().is_success();
+        let response_str = response.text().await?;
+        if !success {
+            match serde_json::from_str::<ErrorResponse>(&response_str) {
+                Ok(error) => {
+                    return Err(OAuthError {
+                        error: error.error,
+                        description: error.error_description.unwrap_or_default(),
+                    }
+                    .into())
+                }
+                Err(_) => bail!("token request failed: `{}`", response_str),
+            }
+        }
+
+        let token = serde_json::from_str::<TokenResponse>(&response_str)
+            .with_context(|| format!("Actual server response: `{}`", &response_str))?;
+        Ok(token)
+    }
+}
+
+/// Tokens kept between runs, the access token changes on every refresh.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredToken {
+    pub access_token: String,
+    #[serde(default)]
+    pub refresh_token: Option<String>,
+    /// Unix timestamp, when known.
+    #[serde(default)]
+    pub expires_at: Option<u64>,
+}
+
+impl StoredToken {
+    pub fn from_response(response: TokenResponse, previous_refresh: Option<String>) -> Self {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("System time should be after UNIX EPOCH")
+            .as_secs();
+        StoredToken {
+            access_token: response.access_token,
+            // Yandex may leave the refresh token out when it didn't change
+            refresh_token: response.refresh_token.or(previous_refresh),
+            expires_at: response.expires_in.map(|expires_in| now + expires_in),
+        }
+    }
+}
+
+pub struct TokenFile {
+    path: PathBuf,
+}
+
+impl TokenFile {
+    pub fn new(path: &str) -> Self {
+        TokenFile {
+            path: PathBuf::from(path),
+        }
+    }
+
+    pub fn load(&self) -> Result<Option<StoredToken>> {
+        if !self.path.exists() {
+            return Ok(None);
+        }
+        let data = fs::read(&self.path)
+            .with_context(|| format!("failed to read {}", self.path.display()))?;
+        let token = serde_json::from_slice(&data)
+            .with_context(|| format!("failed to parse {}", self.path.display()))?;
+        Ok(Some(token))
+    }
+
+    /// Replaces the file through a rename, so it's never left half written.
+    /// Only the owner can read it.
+    pub fn save(&self, token: &StoredToken) -> Result<()> {
+        let temp = self.path.with_extension("tmp");
+        let data = serde_json::to_vec_pretty(token)?;
+        // the mode only applies to new files
+        let _ = fs::remove_file(&temp);
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        options
+            .open(&temp)
+            .and_then(|mut file| file.write_all(&data))
+            .with_context(|| format!("failed to write {}", temp.display()))?;
+        fs::rename(&temp, &self.path)
+            .with_context(|| format!("failed to save {}", self.path.display()))?;
+        Ok(())
+    }
+}
+
+/// Gets a new access token when the current one is rejected and saves it
+/// for the following runs.
+pub struct TokenRefresher {
+    oauth: OAuthClient,
+    file: TokenFile,
+    refresh_token: Mutex<String>,
+}
+
+impl TokenRefresher {
+    pub fn new(oauth: OAuthClient, file: TokenFile, refresh_token: String) -> Self {
+        TokenRefresher {
+            oauth: oauth,
+            file: file,
+            refresh_token: Mutex::new(refresh_token),
+        }
+    }
+
+    /// Returns the new access token. Failing to save it only costs the
+    /// following runs a refresh, so that's a warning.
+    pub async fn refresh(&self) -> Result<String> {
+        let mut refresh_token = self.refresh_token.lock().await;
+        let response = self.oauth.refresh(&refresh_token).await?;
+        let token = StoredToken::from_response(response, Some(refresh_token.clone()));
+        if let Some(new_refresh) = &token.refresh_token {
+            *refresh_token = new_refresh.clone();
+        }
+        if let Err(error) = self.file.save(&token) {
+            eprintln!("failed to save the refreshed Yandex token: {:#}", error);
+        }
+        Ok(token.access_token)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::api::test_support::FakeOAuth;
+
+    fn oauth(server: &FakeOAuth) -> OAuthClient {
+        OAuthClient::new("app".to_string(), "secret".to_string(), Client::new())
+            .with_api_url(server.url())
+    }
+
+    #[tokio::test]
+    async fn test_device_code_flow() {
+        let server = FakeOAuth::start("app", "refresh-1", "access-1").await;
+        let oauth = oauth(&server);
+
+        let code = oauth.request_device_code().await.unwrap();
+        assert!(oauth
+            .poll_device_token(&code.device_code)
+            .await
+            .unwrap()
+            .is_none());
+        server.confirm_device_code();
+        let token = oauth
+            .poll_device_token(&code.device_code)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(token.access_token, "access-1");
+        assert_eq!(token.refresh_token.as_deref(), Some("refresh-1"));
+    }
+
+    #[tokio::test]
+    async fn test_refresher_saves_the_new_token() {
+        let server = FakeOAuth::start("app", "refresh-1", "access-2").await;
+        let path =
+            std::env::temp_dir().join(format!("scrappybot_token_{}.json", std::process::id()));
+        let path = path.display().to_string();
+        let refresher = TokenRefresher::new(
+            oauth(&server),
+            TokenFile::new(&path),
+            "refresh-1".to_string(),
+        );
+
+        assert_eq!(refresher.refresh().await.unwrap(), "access-2");
+        let saved = TokenFile::new(&path).load().unwrap().unwrap();
+        assert_eq!(saved.access_token, "access-2");
+        assert_eq!(saved.refresh_token.as_deref(), Some("refresh-1"));
+        assert!(saved.expires_at.is_some());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        fs::remove_file(&path).unwrap();
+
+        let unsaved = TokenRefresher::new(
+            oauth(&server),
+            TokenFile::new("/nonexistent/scrappybot_token.json"),
+            "refresh-1".to_string(),
+        );
+        assert_eq!(unsaved.refresh().await.unwrap(), "access-2");
+
+        let revoked =
+            TokenRefresher::new(oauth(&server), TokenFile::new(&path), "revoked".to_string());
+        let error = revoked.refresh().await.unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<OAuthError>().unwrap().error,
+            "invalid_grant"
+        );
+    }
+}
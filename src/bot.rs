@@ -1,75 +1,600 @@
+use super::alerts;
 use super::api::telegram_api::TelegramClient;
 use super::api::yandex_disk_api::DiskClient;
+use super::api::yandex_oauth::{OAuthClient, TokenFile, TokenRefresher};
+use super::config::{Config, StorageKind, YandexConfig};
+use super::history::{HistoryStore, ListingHistory};
+use super::http::{Destination, HttpClients};
+use super::journal::DeliveryJournal;
+use super::photos::{
+    self, FsPhotoArchive, PhotoArchive, PhotoIndex, S3PhotoArchive, YandexPhotoArchive,
+};
+use super::reminders;
+use super::runlog::{self, RunRecord};
 use super::state::Diff;
 use super::state::Snapshot;
 use super::storage::BlobStorage;
+use super::storage::{Codec, CodecStorage};
+use super::storage::FsSystem;
+use super::storage::S3Storage;
+use super::storage::SqliteDatabase;
 use super::storage::YandexDiskStorage;
 use super::scrapes;
-use super::notification;
+use super::scrapes::hudhomestore::ListingItem;
+use super::scrapes::polite::{PageCache, PoliteClient};
+use super::notification::{
+    ConsoleService, EmailService, FanOutNotifier, TelegramService, WebhookService,
+};
+use anyhow::Context;
+use chrono::Utc;
+use core::fmt::Display;
+use serde::Serialize;
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::time::Instant;
 
+/// Options for a single bot cycle.
+#[derive(Debug, Default)]
+pub struct RunOptions {
+    /// Scrape and diff against the stored snapshot, but print the would-be
+    /// messages to stdout instead of notifying and don't save the snapshot.
+    pub dry_run: bool,
+}
+
+/// Blob series kept in the storage backend.
+pub const SNAPSHOTS: &'static str = "snapshots";
+pub const DELIVERY_JOURNAL: &'static str = "delivery_journal";
+pub const RUNS: &'static str = "runs";
+pub const HISTORY: &'static str = "history";
+pub const HISTORY_LOG: &'static str = "history_log";
+pub const PHOTO_INDEX: &'static str = "photo_index";
+pub const PAGE_CACHE: &'static str = "page_cache";
+pub const REMINDER_JOURNAL: &'static str = "reminder_journal";
+
+const SOURCE: &'static str = "hudhomestore";
+
+#[derive(Debug, Default, Clone)]
 pub struct BotStats {
     pub changed: usize,
     pub added: usize,
+    pub removed: usize,
 }
 
 impl BotStats {
     fn from_diff<T>(diff: &Diff<T>) -> Self {
         BotStats {
             changed: diff.changed.len(),
-            added: diff.added.len()
+            added: diff.added.len(),
+            removed: diff.removed.len(),
         }
     }
 }
 
-pub async fn run() -> Result<BotStats, Box<dyn std::error::Error>> {
-    let telegram_token = "TODO:".to_string();
-    let yandex_token = "TODO:".to_string();
-    let chat_id = "TODO:";
+/// Yandex OAuth client for the app credentials in `yandex`, if any.
+pub fn yandex_oauth(
+    http: &HttpClients,
+    yandex: &YandexConfig,
+) -> anyhow::Result<Option<OAuthClient>> {
+    match (&yandex.client_id, &yandex.client_secret) {
+        (Some(client_id), Some(client_secret)) => Ok(Some(
+            OAuthClient::new(
+                client_id.clone(),
+                client_secret.clone(),
+                http.client(Destination::Yandex)?,
+            )
+            .with_retry(http.retry()),
+        )),
+        _ => Ok(None),
+    }
+}
+
+/// Disk client using the saved token when there is one. Expired tokens are
+/// refreshed if the app credentials and a refresh token, saved or from the
+/// config, are available.
+fn disk_client(http: &HttpClients, yandex: &YandexConfig) -> anyhow::Result<DiskClient> {
+    let token_file = TokenFile::new(&yandex.token_file);
+    let saved = token_file.load()?;
+    let token = match &saved {
+        Some(saved) => saved.access_token.clone(),
+        None if !yandex.token.is_empty() => yandex.token.clone(),
+        None => anyhow::bail!(
+            "no Yandex token, set `yandex.token` or authorize with `scrappybot yandex-auth`"
+        ),
+    };
+
+    let mut disk_client =
+        DiskClient::new(token, http.client(Destination::Yandex)?).with_retry(http.retry());
+    if let Some(api_url) = &yandex.api_url {
+        disk_client = disk_client.with_api_url(api_url.clone());
+    }
+    let refresh_token = saved
+        .and_then(|saved| saved.refresh_token)
+        .or_else(|| yandex.refresh_token.clone());
+    if let (Some(oauth), Some(refresh_token)) = (yandex_oauth(http, yandex)?, refresh_token) {
+        disk_client =
+            disk_client.with_refresher(TokenRefresher::new(oauth, token_file, refresh_token));
+    }
+    Ok(disk_client)
+}
+
+/// Storage for one blob `series`. Snapshots use the configured prefix,
+/// other series are prefixed with their own name. The SQLite backend keeps
+/// them in `database`, as opened by `sqlite_database`.
+pub fn build_storage(
+    config: &Config,
+    http: &HttpClients,
+    database: Option<&SqliteDatabase>,
+    series: &str,
+) -> anyhow::Result<Box<dyn BlobStorage>> {
+    let storage: Box<dyn BlobStorage> = match config.storage {
+        StorageKind::Fs => {
+            let prefix = if series == SNAPSHOTS {
+                config.fs.prefix.clone()
+            } else {
+                series.to_string()
+            };
+            Box::new(FsSystem::new(config.fs.folder.clone(), prefix))
+        }
+        StorageKind::Yandex => {
+            let yandex = config
+                .yandex
+                .as_ref()
+                .context("`yandex` section is required for yandex storage")?;
+            let disk_client = disk_client(http, yandex)?;
+            let prefix = if series == SNAPSHOTS {
+                yandex.prefix.clone()
+            } else {
+                series.to_string()
+            };
+            Box::new(YandexDiskStorage::new(
+                disk_client,
+                yandex.folder.clone(),
+                prefix,
+            ))
+        }
+        StorageKind::Sqlite => Box::new(
+            database
+                .context("the SQLite database isn't open")?
+                .blobs(series),
+        ),
+        StorageKind::S3 => {
+            let s3 = config
+                .s3
+                .as_ref()
+                .context("`s3` section is required for s3 storage")?;
+            let prefix = if series == SNAPSHOTS {
+                s3.snapshot_prefix.clone()
+            } else {
+                series.to_string()
+            };
+            Box::new(S3Storage::new(s3, prefix)?)
+        }
+    };
+    Ok(Box::new(CodecStorage::new(storage, Codec::new(&config.codec)?)))
+}
+
+/// Listing history, checkpoints in `HISTORY` and one entry per scrape since
+/// the latest checkpoint in `HISTORY_LOG`.
+fn history_store(
+    config: &Config,
+    http: &HttpClients,
+    database: Option<&SqliteDatabase>,
+) -> anyhow::Result<HistoryStore> {
+    Ok(HistoryStore::new(
+        build_storage(config, http, database, HISTORY)?,
+        build_storage(config, http, database, HISTORY_LOG)?,
+    ))
+}
 
-    let items = scrapes::hudhomestore::scrape().await?;
+/// The listing history. The SQLite backend keeps it in its tables rather
+/// than in the history store.
+pub async fn load_history(
+    config: &Config,
+    http: &HttpClients,
+    database: Option<&SqliteDatabase>,
+) -> anyhow::Result<ListingHistory> {
+    match database {
+        Some(database) => database.history().await,
+        None => history_store(config, http, database)?.load().await,
+    }
+}
 
-    // let mut storage = storage::FsSystem::new();
-    let http_client = reqwest::Client::new();
-    let disk_client = DiskClient::new(yandex_token, http_client);
+/// Photo archive in the storage backend. The SQLite backend keeps photos
+/// on the filesystem, in the `fs` folder.
+pub fn build_photo_archive(
+    config: &Config,
+    http: &HttpClients,
+) -> anyhow::Result<Box<dyn PhotoArchive>> {
+    let archive: Box<dyn PhotoArchive> = match config.storage {
+        StorageKind::Fs | StorageKind::Sqlite => {
+            Box::new(FsPhotoArchive::new(&config.fs.folder))
+        }
+        StorageKind::Yandex => {
+            let yandex = config
+                .yandex
+                .as_ref()
+                .context("`yandex` section is required for yandex storage")?;
+            Box::new(YandexPhotoArchive::new(disk_client(http, yandex)?, &yandex.folder))
+        }
+        StorageKind::S3 => {
+            let s3 = config
+                .s3
+                .as_ref()
+                .context("`s3` section is required for s3 storage")?;
+            Box::new(S3PhotoArchive::new(s3)?)
+        }
+    };
+    Ok(archive)
+}
 
-    let storage = YandexDiskStorage::new(
-        disk_client,
-        "estatebot".to_string(),
-        "hudhome_snapshot".to_string(),
+/// Archives the photos of `case_numbers` and of the listings earlier runs
+/// left pending. A listing whose photos fail is reported and stays pending
+/// for up to `photos::MAX_ATTEMPTS` runs, the run goes on.
+async fn archive_photos(
+    config: &Config,
+    http: &HttpClients,
+    database: Option<&SqliteDatabase>,
+    scraper: &PoliteClient,
+    case_numbers: &[String],
+) -> anyhow::Result<()> {
+    let index_storage = build_storage(config, http, database, PHOTO_INDEX)?;
+    let mut index = index_storage
+        .load::<PhotoIndex>()
+        .await?
+        .unwrap_or_default();
+    let mut queue: Vec<String> = index.pending.keys().cloned().collect();
+    queue.extend(
+        case_numbers
+            .iter()
+            .filter(|case_number| !index.pending.contains_key(*case_number))
+            .cloned(),
     );
+    if queue.is_empty() {
+        return Ok(());
+    }
+
+    let archive = build_photo_archive(config, http)?;
+    for (i, case_number) in queue.iter().enumerate() {
+        if scraper.budget_exhausted() {
+            eprintln!(
+                "request budget is used up, photos of {} listings left for the next run",
+                queue.len() - i
+            );
+            for case_number in queue[i..].iter() {
+                index.pending.entry(case_number.clone()).or_insert(0);
+            }
+            break;
+        }
+        let archived = photos::archive_listing(
+            scraper,
+            archive.as_ref(),
+            &mut index,
+            case_number,
+            config.photos.max_per_listing,
+        )
+        .await;
+        if let Err(error) = archived {
+            eprintln!("failed to archive photos of {}: {:#}", case_number, error);
+            let attempts = index.pending.entry(case_number.clone()).or_insert(0);
+            *attempts += 1;
+            if *attempts < photos::MAX_ATTEMPTS {
+                continue;
+            }
+            eprintln!("giving up on the photos of {}", case_number);
+        }
+        index.pending.remove(case_number);
+    }
+
+    index_storage.replace(&index).await?;
+    Ok(())
+}
+
+/// Opens the SQLite database when it's the configured backend. Listings,
+/// runs and notifications are then also kept in queryable tables. The
+/// caller holds on to it for every storage it builds.
+pub async fn sqlite_database(config: &Config) -> anyhow::Result<Option<SqliteDatabase>> {
+    match config.storage {
+        StorageKind::Sqlite => Ok(Some(SqliteDatabase::open(sqlite_path(config)?).await?)),
+        _ => Ok(None),
+    }
+}
+
+/// The codec only encrypts blobs, listings, runs and notifications would
+/// be kept in plain tables next to them.
+fn sqlite_path(config: &Config) -> anyhow::Result<&str> {
+    if config.codec.encryption_key.is_some() {
+        anyhow::bail!("`codec.encryption_key` isn't supported with sqlite storage");
+    }
+    Ok(&config.sqlite.path)
+}
+
+/// `sqlite_database` for runs that must leave storage as it is: a dry run
+/// neither creates nor migrates the database.
+async fn run_database(
+    config: &Config,
+    options: &RunOptions,
+) -> anyhow::Result<Option<SqliteDatabase>> {
+    match config.storage {
+        StorageKind::Sqlite if options.dry_run => Ok(Some(
+            SqliteDatabase::open_read_only(sqlite_path(config)?).await?,
+        )),
+        _ => sqlite_database(config).await,
+    }
+}
+
+/// Every channel configured in `config`, dispatched through one fan-out.
+pub fn build_notifier<T>(config: &Config, http: &HttpClients) -> anyhow::Result<FanOutNotifier<T>>
+where
+    T: Display + Serialize + Sync,
+{
+    let retry = http.retry();
+    let mut notifier = FanOutNotifier::new();
+    if let Some(telegram) = &config.telegram {
+        let mut telegram_client =
+            TelegramClient::new(telegram.token.clone(), http.client(Destination::Telegram)?)
+                .with_retry(retry.clone());
+        if let Some(api_url) = &telegram.api_url {
+            telegram_client = telegram_client.with_api_url(api_url.clone());
+        }
+        notifier.add_filtered(
+            "telegram",
+            Box::new(TelegramService::new(telegram_client, &telegram.chat_id)),
+            telegram.filter.clone(),
+        );
+    }
+    if let Some(email) = &config.email {
+        notifier.add_filtered(
+            "email",
+            Box::new(EmailService::new(email.clone())),
+            email.filter.clone(),
+        );
+    }
+    let webhook_client = http.client(Destination::Webhooks)?;
+    for webhook in config.webhooks.iter() {
+        notifier.add_filtered(
+            &webhook.channel_name(),
+            Box::new(
+                WebhookService::new(webhook.clone(), webhook_client.clone())
+                    .with_retry(retry.clone()),
+            ),
+            webhook.filter.clone(),
+        );
+    }
+    Ok(notifier)
+}
+
+/// Runs one cycle and records it in the run log, unless it's a dry run.
+/// Passing the same `http` to every run keeps connections alive between
+/// them.
+pub async fn run(
+    config: &Config,
+    http: &HttpClients,
+    options: &RunOptions,
+) -> Result<BotStats, Box<dyn std::error::Error>> {
+    let started_at = runlog::now();
+    let timer = Instant::now();
+    let database = run_database(config, options).await?;
+    let result = run_cycle(config, http, database.as_ref(), options).await;
+
+    if !options.dry_run {
+        let stats = match &result {
+            Ok(stats) => stats.clone(),
+            Err(_) => BotStats::default(),
+        };
+        let record = RunRecord {
+            source: SOURCE.to_string(),
+            started_at: started_at,
+            finished_at: runlog::now(),
+            duration_ms: timer.elapsed().as_millis() as u64,
+            added: stats.added,
+            changed: stats.changed,
+            removed: stats.removed,
+            error: result.as_ref().err().map(|e| e.to_string()),
+        };
+        if let Err(error) = save_run_record(config, http, database.as_ref(), &record).await {
+            eprintln!("failed to record the run: {:#}", error);
+        }
+    }
+    result
+}
+
+/// Sends the bid deadline and listing period reminders due now, worked out
+/// from the stored listing history. Returns how many were due, reminders
+/// the journal says a channel already got aren't sent to it again.
+pub async fn remind(
+    config: &Config,
+    http: &HttpClients,
+    options: &RunOptions,
+) -> Result<usize, Box<dyn std::error::Error>> {
+    let database = run_database(config, options).await?;
+    let history = load_history(config, http, database.as_ref()).await?;
+    let due = reminders::due(&config.reminders, &config.listing_time, &history, Utc::now());
+    if due.is_empty() {
+        return Ok(0);
+    }
+
+    let mut notifier = if options.dry_run {
+        let mut console = FanOutNotifier::new();
+        console.add("console", Box::new(ConsoleService));
+        console
+    } else {
+        build_notifier(config, http)?
+    };
+    let journal_storage = build_storage(config, http, database.as_ref(), REMINDER_JOURNAL)?;
+    let mut journal = journal_storage
+        .load::<DeliveryJournal>()
+        .await?
+        .unwrap_or_default();
+    reminders::prune(&mut journal, runlog::now());
+
+    let diff = Diff {
+        added: due,
+        changed: Vec::new(),
+        removed: Vec::new(),
+    };
+    let delivery = notifier
+        .notify_journaled(
+            &diff,
+            &HashMap::<String, ListingItem>::new(),
+            "hudhome reminder",
+            &mut journal,
+        )
+        .await;
+    if !options.dry_run {
+        journal_storage.replace(&journal).await?;
+    }
+    delivery?;
+    Ok(diff.added.len())
+}
+
+async fn save_run_record(
+    config: &Config,
+    http: &HttpClients,
+    database: Option<&SqliteDatabase>,
+    record: &RunRecord,
+) -> anyhow::Result<()> {
+    if let Some(database) = database {
+        return database.record_run(record).await;
+    }
+
+    let storage = build_storage(config, http, database, RUNS)?;
+    storage.save(record).await?;
+
+    let names = storage.list().await?;
+    let outdated = names.len().saturating_sub(runlog::RETENTION);
+    for name in names.iter().take(outdated) {
+        storage.remove(name).await?;
+    }
+    Ok(())
+}
+
+async fn run_cycle(
+    config: &Config,
+    http: &HttpClients,
+    database: Option<&SqliteDatabase>,
+    options: &RunOptions,
+) -> Result<BotStats, Box<dyn std::error::Error>> {
+    let page_cache_storage = build_storage(config, http, database, PAGE_CACHE)?;
+    let page_cache = page_cache_storage
+        .load::<PageCache>()
+        .await?
+        .unwrap_or_default();
+    let scraper = PoliteClient::new(
+        http.client(Destination::Hudhomestore)?,
+        http.retry(),
+        &config.politeness,
+        &config.http.user_agent,
+    )
+    .with_cache(page_cache);
+    let items = scrapes::hudhomestore::scrape(&scraper).await?;
+
+    let storage = build_storage(config, http, database, SNAPSHOTS)?;
     let old_snapshot = storage.load::<Snapshot>().await?;
 
-    let http_client = reqwest::Client::new();
-    let telegram_client = TelegramClient::new(telegram_token, http_client);
-    let mut telegram = notification::TelegramService::new(telegram_client, chat_id);
+    let mut notifier = if options.dry_run {
+        let mut console = FanOutNotifier::new();
+        console.add("console", Box::new(ConsoleService));
+        console
+    } else {
+        build_notifier(config, http)?
+    };
     let snapshot = Snapshot::new(items.to_vec());
 
-    let stats = match old_snapshot {
-        Some(old) => {
-            let diff = old.diff(items);
-            if !diff.changed.is_empty() || !diff.added.is_empty() {
-                telegram.notify(&diff, "hudhome listing").await?;
+    let history_store = history_store(config, http, database)?;
+    let mut history = match database {
+        Some(database) => database.history().await?,
+        None => history_store.load().await?,
+    };
+    let history_entry = history.record(&items, snapshot.update_timestamp);
+
+    let diff = match old_snapshot {
+        Some(old) => old.diff(items.to_vec()),
+        None => Diff {
+            added: items.to_vec(),
+            changed: Vec::new(),
+            removed: Vec::new(),
+        },
+    };
+    let today = config.listing_time.today(Utc::now());
+    let annotate = |item: ListingItem| {
+        let alerts = alerts::evaluate(&config.alerts, &item, history.timeline(&item.id), today);
+        history.annotate(item).with_alerts(alerts)
+    };
+    let mut diff = Diff {
+        added: diff.added.into_iter().map(&annotate).collect(),
+        changed: diff.changed.into_iter().map(&annotate).collect(),
+        removed: diff.removed,
+    };
+    // the most important alerts go out first
+    diff.added.sort_by_key(|item| Reverse(item.priority()));
+    diff.changed.sort_by_key(|item| Reverse(item.priority()));
+    // filtered channels judge removed listings by their last version
+    let removed: HashMap<String, ListingItem> = diff
+        .removed
+        .iter()
+        .filter_map(|id| {
+            let latest = history.timeline(id)?.latest();
+            Some((id.clone(), ListingItem::from(latest.fields.clone())))
+        })
+        .collect();
 
-                storage.save(&snapshot).await?
+    if !diff.changed.is_empty() || !diff.added.is_empty() || !diff.removed.is_empty() {
+        // Whatever was delivered is journaled before the snapshot is saved,
+        // so a failure at any point doesn't cause duplicates on the next run.
+        let journal_storage = build_storage(config, http, database, DELIVERY_JOURNAL)?;
+        let mut journal = journal_storage
+            .load::<DeliveryJournal>()
+            .await?
+            .unwrap_or_default();
+
+        let delivery = notifier
+            .notify_journaled(&diff, &removed, "hudhome listing", &mut journal)
+            .await;
+        if options.dry_run {
+            delivery?;
+        } else {
+            journal_storage.replace(&journal).await?;
+            delivery?;
+
+            storage.save(&snapshot).await?;
+            if let Some(database) = database {
+                let sent_at = runlog::now();
+                for (channel, delivered) in journal.channels.iter() {
+                    for (id, checksum) in delivered.delivered.iter() {
+                        database
+                            .record_notification(channel, id, *checksum, sent_at)
+                            .await?;
+                    }
+                }
             }
-            BotStats::from_diff(&diff)
+            journal_storage.replace(&DeliveryJournal::default()).await?;
         }
-        None => {
-            let diff = Diff {
-                added: items,
-                changed: Vec::new(),
-            };
+    }
 
-            telegram
-                .notify(
-                    &diff,
-                    "hudhome listing",
-                )
-                .await?;
+    // recorded only once the diff went out, so both backends keep the same
+    // history when delivery fails and the next run retries it
+    if !options.dry_run {
+        match database {
+            Some(database) => {
+                database
+                    .record_scrape(&items, snapshot.update_timestamp)
+                    .await?
+            }
+            None => history_store.append(&history, &history_entry).await?,
+        }
+    }
 
-            storage.save(&snapshot).await?;
-            BotStats::from_diff(&diff)
+    if config.photos.enabled && !options.dry_run {
+        let case_numbers: Vec<String> = diff.added.iter().map(|i| i.item.id.clone()).collect();
+        let archived = archive_photos(config, http, database, &scraper, &case_numbers).await;
+        if let Err(error) = archived {
+            eprintln!("failed to archive photos: {:#}", error);
         }
-    };
-    Ok(stats)
+    }
+
+    if !options.dry_run {
+        page_cache_storage.replace(&scraper.take_cache()).await?;
+    }
+
+    Ok(BotStats::from_diff(&diff))
 }
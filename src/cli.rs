@@ -0,0 +1,356 @@
+use crate::api::yandex_oauth::{StoredToken, TokenFile};
+use crate::bot;
+use crate::config::{self, Config, StorageKind};
+use crate::http::{Destination, HttpClients, HttpConfig};
+use crate::notification::NotificationService;
+use crate::runlog::{self, RunRecord};
+use crate::scheduler;
+use crate::scrapes;
+use crate::scrapes::hudhomestore::ListingItem;
+use crate::scrapes::polite::{PoliteClient, PolitenessConfig};
+use crate::state::{Diff, Snapshot};
+use anyhow::{bail, Result};
+use std::path::Path;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+use structopt::StructOpt;
+
+#[derive(Debug, StructOpt)]
+#[structopt(name = "scrappybot", about = "Watches HUD listings and notifies about changes")]
+pub struct Cli {
+    /// Path to the JSON config file
+    #[structopt(long, global = true, default_value = config::DEFAULT_PATH)]
+    pub config: String,
+    /// Storage backend overriding the one from config: fs, yandex, sqlite or s3
+    #[structopt(long, global = true)]
+    pub storage: Option<StorageKind>,
+    #[structopt(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Debug, StructOpt)]
+pub enum Command {
+    /// Run one scrape/diff/notify cycle
+    Run {
+        /// Print would-be messages instead of sending them, don't save the snapshot
+        #[structopt(long)]
+        dry_run: bool,
+    },
+    /// Run cycles on the schedules from config until SIGTERM
+    Daemon,
+    /// Send the bid deadline and listing period reminders due now
+    Remind {
+        /// Print would-be reminders instead of sending them
+        #[structopt(long)]
+        dry_run: bool,
+    },
+    /// Print current listings
+    Scrape {
+        /// Output format: table, json or csv
+        #[structopt(long, default_value = "table")]
+        format: OutputFormat,
+    },
+    /// Compare two stored snapshots
+    Diff { from: String, to: String },
+    /// Inspect stored snapshots
+    Snapshots(SnapshotsCommand),
+    /// Show the latest recorded run and snapshot
+    Status,
+    /// Show every recorded version of a listing
+    History {
+        /// Listing id, the case number for HUD listings
+        id: String,
+    },
+    /// Send a test message through every configured channel
+    NotifyTest {
+        #[structopt(long, default_value = "scrappybot notification test")]
+        message: String,
+    },
+    /// Authorize the bot on Yandex Disk with a device code and save the token
+    YandexAuth,
+}
+
+#[derive(Debug, StructOpt)]
+pub enum SnapshotsCommand {
+    /// List stored snapshots from the oldest to the latest
+    List,
+    /// Print a stored snapshot, the latest one by default
+    Show { name: Option<String> },
+    /// Remove all but the latest snapshots
+    Prune {
+        #[structopt(long, default_value = "10")]
+        keep: usize,
+    },
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum OutputFormat {
+    Table,
+    Json,
+    Csv,
+}
+
+impl FromStr for OutputFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value {
+            "table" => Ok(OutputFormat::Table),
+            "json" => Ok(OutputFormat::Json),
+            "csv" => Ok(OutputFormat::Csv),
+            other => bail!("unknown format `{}`, expected one of: table, json, csv", other),
+        }
+    }
+}
+
+impl Cli {
+    fn load_config(&self) -> Result<Config> {
+        let mut config = Config::load(&self.config)?;
+        if let Some(storage) = self.storage {
+            config.storage = storage;
+        }
+        Ok(config)
+    }
+}
+
+pub async fn execute(cli: Cli) -> Result<(), Box<dyn std::error::Error>> {
+    match &cli.command {
+        Command::Run { dry_run } => {
+            let config = cli.load_config()?;
+            let options = bot::RunOptions { dry_run: *dry_run };
+            let http = HttpClients::new(&config.http);
+            let stats = bot::run(&config, &http, &options).await?;
+            println!(
+                "added: {}, changed: {}, removed: {}",
+                stats.added, stats.changed, stats.removed
+            );
+        }
+        Command::Daemon => {
+            let config = cli.load_config()?;
+            scheduler::run_daemon(&config).await?;
+        }
+        Command::Remind { dry_run } => {
+            let config = cli.load_config()?;
+            let options = bot::RunOptions { dry_run: *dry_run };
+            let http = HttpClients::new(&config.http);
+            let due = bot::remind(&config, &http, &options).await?;
+            println!("reminders due: {}", due);
+        }
+        Command::Scrape { format } => {
+            // scraping works without a config file
+            let (http_config, politeness) = if Path::new(&cli.config).exists() {
+                let config = cli.load_config()?;
+                (config.http, config.politeness)
+            } else {
+                (HttpConfig::default(), PolitenessConfig::default())
+            };
+            let http = HttpClients::new(&http_config);
+            let scraper = PoliteClient::new(
+                http.client(Destination::Hudhomestore)?,
+                http.retry(),
+                &politeness,
+                &http_config.user_agent,
+            );
+            let items = scrapes::hudhomestore::scrape(&scraper).await?;
+            print_listings(&items, *format)?;
+        }
+        Command::Diff { from, to } => {
+            let config = cli.load_config()?;
+            let http = HttpClients::new(&config.http);
+            let database = bot::sqlite_database(&config).await?;
+            let storage = bot::build_storage(&config, &http, database.as_ref(), bot::SNAPSHOTS)?;
+            let old = storage.load_named::<Snapshot>(from).await?;
+            let new = storage.load_named::<Snapshot>(to).await?;
+            print_diff(&old.diff(new.state.into_iter().collect()));
+        }
+        Command::Snapshots(command) => {
+            let config = cli.load_config()?;
+            let http = HttpClients::new(&config.http);
+            let database = bot::sqlite_database(&config).await?;
+            let storage = bot::build_storage(&config, &http, database.as_ref(), bot::SNAPSHOTS)?;
+            match command {
+                SnapshotsCommand::List => {
+                    for name in storage.list().await? {
+                        println!("{}", name);
+                    }
+                }
+                SnapshotsCommand::Show { name } => {
+                    let name = match name {
+                        Some(name) => name.clone(),
+                        None => match storage.list().await?.pop() {
+                            Some(latest) => latest,
+                            None => return Err("no snapshots stored".into()),
+                        },
+                    };
+                    let snapshot = storage.load_named::<serde_json::Value>(&name).await?;
+                    println!("{}", serde_json::to_string_pretty(&snapshot)?);
+                }
+                SnapshotsCommand::Prune { keep } => {
+                    let names = storage.list().await?;
+                    let outdated = names.len().saturating_sub(*keep);
+                    for name in names.iter().take(outdated) {
+                        storage.remove(name).await?;
+                        println!("removed {}", name);
+                    }
+                }
+            }
+        }
+        Command::Status => {
+            let config = cli.load_config()?;
+            let http = HttpClients::new(&config.http);
+            let database = bot::sqlite_database(&config).await?;
+            let latest_run = match &database {
+                Some(database) => database.recent_runs(1).await?.pop(),
+                None => {
+                    bot::build_storage(&config, &http, None, bot::RUNS)?
+                        .load::<RunRecord>()
+                        .await?
+                }
+            };
+            match latest_run {
+                Some(run) => {
+                    println!(
+                        "last run: {} ({}s ago), took {}ms",
+                        run.source,
+                        runlog::now().saturating_sub(run.finished_at),
+                        run.duration_ms
+                    );
+                    match run.error {
+                        Some(error) => println!("failed: {}", error),
+                        None => println!(
+                            "added: {}, changed: {}, removed: {}",
+                            run.added, run.changed, run.removed
+                        ),
+                    }
+                }
+                None => println!("no runs recorded"),
+            }
+            let storage = bot::build_storage(&config, &http, database.as_ref(), bot::SNAPSHOTS)?;
+            if let Some(snapshot) = storage.load::<Snapshot>().await? {
+                println!(
+                    "last change: {}s ago, {} listings",
+                    runlog::now().saturating_sub(snapshot.update_timestamp),
+                    snapshot.state.len()
+                );
+            }
+        }
+        Command::History { id } => {
+            let config = cli.load_config()?;
+            let http = HttpClients::new(&config.http);
+            let database = bot::sqlite_database(&config).await?;
+            let history = bot::load_history(&config, &http, database.as_ref()).await?;
+            let timeline = match history.timeline(id) {
+                Some(timeline) => timeline,
+                None => return Err(format!("no history for {}", id).into()),
+            };
+            println!("{}", timeline.summary());
+            println!("active: {}", timeline.active);
+            for version in timeline.versions.iter() {
+                println!("{}: {}", version.seen_at, version.fields.join(" | "));
+            }
+            for removed_at in timeline.removed_at.iter() {
+                println!("{}: removed", removed_at);
+            }
+            for relisted_at in timeline.relisted_at.iter() {
+                println!("{}: relisted", relisted_at);
+            }
+        }
+        Command::YandexAuth => {
+            let config = cli.load_config()?;
+            let yandex = match &config.yandex {
+                Some(yandex) => yandex,
+                None => return Err("`yandex` section is required".into()),
+            };
+            let oauth = match bot::yandex_oauth(&HttpClients::new(&config.http), yandex)? {
+                Some(oauth) => oauth,
+                None => {
+                    return Err("`yandex.client_id` and `yandex.client_secret` are required".into())
+                }
+            };
+
+            let code = oauth.request_device_code().await?;
+            println!(
+                "Open {} and enter the code {}",
+                code.verification_url, code.user_code
+            );
+            let deadline = Instant::now() + Duration::from_secs(code.expires_in);
+            let token = loop {
+                tokio::time::delay_for(Duration::from_secs(code.interval.max(1))).await;
+                if let Some(token) = oauth.poll_device_token(&code.device_code).await? {
+                    break token;
+                }
+                if Instant::now() > deadline {
+                    return Err("the code expired before it was confirmed".into());
+                }
+            };
+            TokenFile::new(&yandex.token_file).save(&StoredToken::from_response(token, None))?;
+            println!("saved the token to {}", yandex.token_file);
+        }
+        Command::NotifyTest { message } => {
+            let config = cli.load_config()?;
+            let http = HttpClients::new(&config.http);
+            let mut notifier = bot::build_notifier::<String>(&config, &http)?;
+            if notifier.is_empty() {
+                return Err("no notification channels configured".into());
+            }
+            let diff = Diff {
+                added: vec![message.clone()],
+                changed: Vec::new(),
+                removed: Vec::new(),
+            };
+            notifier.notify(&diff, "test message").await?;
+        }
+    }
+    Ok(())
+}
+
+fn print_diff(diff: &Diff<(String, u64)>) {
+    for (id, _) in diff.added.iter() {
+        println!("+ {}", id);
+    }
+    for (id, _) in diff.changed.iter() {
+        println!("~ {}", id);
+    }
+    for id in diff.removed.iter() {
+        println!("- {}", id);
+    }
+}
+
+fn print_listings(items: &[ListingItem], format: OutputFormat) -> Result<()> {
+    match format {
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(items)?),
+        OutputFormat::Csv => {
+            for item in items.iter() {
+                let row: Vec<String> = item.csv.iter().map(|c| csv_field(c)).collect();
+                println!("{}", row.join(","));
+            }
+        }
+        OutputFormat::Table => {
+            let columns = items.iter().map(|i| i.csv.len()).max().unwrap_or(0);
+            let mut widths = vec![0; columns];
+            for item in items.iter() {
+                for (i, column) in item.csv.iter().enumerate() {
+                    widths[i] = widths[i].max(column.chars().count());
+                }
+            }
+            for item in items.iter() {
+                let row: Vec<String> = item
+                    .csv
+                    .iter()
+                    .enumerate()
+                    .map(|(i, c)| format!("{:width$}", c, width = widths[i]))
+                    .collect();
+                println!("{}", row.join(" | ").trim_end());
+            }
+        }
+    }
+    Ok(())
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains(',') || value.contains('"') || value.contains('\n') {
+        format!("\"{}\"", value.replace("\"", "\"\""))
+    } else {
+        value.to_string()
+    }
+}
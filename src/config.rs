@@ -0,0 +1,217 @@
+use crate::alerts::AlertRule;
+use crate::filter::Filter;
+use crate::http::HttpConfig;
+use crate::listing_time::ListingTimeConfig;
+use crate::notification::{SmtpConfig, WebhookConfig};
+use crate::photos::PhotosConfig;
+use crate::reminders::RemindersConfig;
+use crate::scrapes::polite::PolitenessConfig;
+use crate::storage::{CodecConfig, S3Config};
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use std::fs::File;
+use std::str::FromStr;
+
+pub const DEFAULT_PATH: &'static str = "scrappybot.json";
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageKind {
+    Fs,
+    Yandex,
+    Sqlite,
+    S3,
+}
+
+impl Default for StorageKind {
+    fn default() -> Self {
+        StorageKind::Yandex
+    }
+}
+
+impl FromStr for StorageKind {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value {
+            "fs" => Ok(StorageKind::Fs),
+            "yandex" => Ok(StorageKind::Yandex),
+            "sqlite" => Ok(StorageKind::Sqlite),
+            "s3" => Ok(StorageKind::S3),
+            other => bail!(
+                "unknown storage `{}`, expected one of: fs, yandex, sqlite, s3",
+                other
+            ),
+        }
+    }
+}
+
+fn default_fs_folder() -> String {
+    "./".to_string()
+}
+
+fn default_fs_prefix() -> String {
+    "estate_snapshot".to_string()
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct FsConfig {
+    #[serde(default = "default_fs_folder")]
+    pub folder: String,
+    #[serde(default = "default_fs_prefix")]
+    pub prefix: String,
+}
+
+impl Default for FsConfig {
+    fn default() -> Self {
+        FsConfig {
+            folder: default_fs_folder(),
+            prefix: default_fs_prefix(),
+        }
+    }
+}
+
+fn default_sqlite_path() -> String {
+    "scrappybot.db".to_string()
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SqliteConfig {
+    #[serde(default = "default_sqlite_path")]
+    pub path: String,
+}
+
+impl Default for SqliteConfig {
+    fn default() -> Self {
+        SqliteConfig {
+            path: default_sqlite_path(),
+        }
+    }
+}
+
+fn default_yandex_folder() -> String {
+    "estatebot".to_string()
+}
+
+pub fn default_snapshot_prefix() -> String {
+    "hudhome_snapshot".to_string()
+}
+
+fn default_yandex_token_file() -> String {
+    "yandex_token.json".to_string()
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct YandexConfig {
+    /// OAuth token, a token saved in `token_file` takes precedence.
+    #[serde(default)]
+    pub token: String,
+    /// Refresh token issued along with `token`, used until `token_file`
+    /// has one.
+    #[serde(default)]
+    pub refresh_token: Option<String>,
+    /// App credentials from oauth.yandex.ru. With these, expired tokens are
+    /// refreshed and `yandex-auth` can authorize the bot.
+    #[serde(default)]
+    pub client_id: Option<String>,
+    #[serde(default)]
+    pub client_secret: Option<String>,
+    /// Where tokens from `yandex-auth` and refreshes are kept.
+    #[serde(default = "default_yandex_token_file")]
+    pub token_file: String,
+    #[serde(default = "default_yandex_folder")]
+    pub folder: String,
+    #[serde(default = "default_snapshot_prefix")]
+    pub prefix: String,
+    /// Overrides `https://cloud-api.yandex.net/v1/disk`, e.g. for a local
+    /// stand-in.
+    #[serde(default)]
+    pub api_url: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct TelegramConfig {
+    pub token: String,
+    pub chat_id: String,
+    /// Overrides `https://api.telegram.org`.
+    #[serde(default)]
+    pub api_url: Option<String>,
+    /// Only listings matching it are sent to the chat.
+    #[serde(default)]
+    pub filter: Option<Filter>,
+}
+
+fn default_source() -> String {
+    "hudhomestore".to_string()
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ScheduleConfig {
+    #[serde(default = "default_source")]
+    pub source: String,
+    /// Cron expression with seconds, e.g. `0 0 */2 * * *` for every two hours.
+    pub cron: String,
+    /// Upper bound of a random delay added to every scheduled run.
+    #[serde(default)]
+    pub jitter_secs: u64,
+}
+
+fn default_schedules() -> Vec<ScheduleConfig> {
+    vec![ScheduleConfig {
+        source: default_source(),
+        cron: "0 0 * * * *".to_string(),
+        jitter_secs: 0,
+    }]
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Config {
+    #[serde(default)]
+    pub storage: StorageKind,
+    /// Compression and encryption of stored blobs, applies to every backend.
+    #[serde(default)]
+    pub codec: CodecConfig,
+    /// Timeouts and retries of outbound requests.
+    #[serde(default)]
+    pub http: HttpConfig,
+    /// Rate limits, robots.txt and request budget of the scraped sites.
+    #[serde(default)]
+    pub politeness: PolitenessConfig,
+    #[serde(default)]
+    pub fs: FsConfig,
+    #[serde(default)]
+    pub sqlite: SqliteConfig,
+    #[serde(default)]
+    pub yandex: Option<YandexConfig>,
+    #[serde(default)]
+    pub s3: Option<S3Config>,
+    #[serde(default)]
+    pub telegram: Option<TelegramConfig>,
+    #[serde(default)]
+    pub email: Option<SmtpConfig>,
+    #[serde(default)]
+    pub webhooks: Vec<WebhookConfig>,
+    /// Time zone of the listed dates and times, for alerts and reminders.
+    #[serde(default)]
+    pub listing_time: ListingTimeConfig,
+    /// Alerts added to new and changed listings, e.g. for price drops.
+    #[serde(default)]
+    pub alerts: Vec<AlertRule>,
+    /// Bid deadline and listing period reminders of watched listings.
+    #[serde(default)]
+    pub reminders: RemindersConfig,
+    /// Photo archiving of new listings, into the storage backend.
+    #[serde(default)]
+    pub photos: PhotosConfig,
+    #[serde(default = "default_schedules")]
+    pub schedules: Vec<ScheduleConfig>,
+}
+
+impl Config {
+    pub fn load(path: &str) -> Result<Self> {
+        let file = File::open(path).with_context(|| format!("failed to open config {}", path))?;
+        let config = serde_json::from_reader(file)
+            .with_context(|| format!("failed to parse config {}", path))?;
+        Ok(config)
+    }
+}
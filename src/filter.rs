@@ -0,0 +1,501 @@
+//! Listing filters written in config, e.g.
+//! `price < 150000 and beds >= 3 and county in ["Fulton", "DeKalb"]`.
+//!
+//! Comparisons combine with `and`, `or`, `not` and parentheses. Numeric
+//! fields take `<`, `<=`, `>`, `>=`, `==` and `!=`, text fields `==`, `!=`,
+//! `in [...]` and `contains`, ignoring case, with quoted values such as
+//! `zip == "30032"`. A comparison on a field the listing doesn't have is
+//! false.
+
+use anyhow::{anyhow, bail, Result};
+use core::fmt::Display;
+use serde::Deserialize;
+use std::convert::TryFrom;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Field {
+    CaseNumber,
+    Address,
+    State,
+    Zip,
+    County,
+    Price,
+    Beds,
+    Baths,
+    ListingPeriod,
+    BidOpenDate,
+    Status,
+}
+
+impl Field {
+    const ALL: &'static [(&'static str, Field)] = &[
+        ("case_number", Field::CaseNumber),
+        ("address", Field::Address),
+        ("state", Field::State),
+        ("zip", Field::Zip),
+        ("county", Field::County),
+        ("price", Field::Price),
+        ("beds", Field::Beds),
+        ("baths", Field::Baths),
+        ("listing_period", Field::ListingPeriod),
+        ("bid_open_date", Field::BidOpenDate),
+        ("status", Field::Status),
+    ];
+
+    fn from_name(name: &str) -> Option<Field> {
+        Field::ALL
+            .iter()
+            .find(|(known, _)| *known == name)
+            .map(|(_, field)| *field)
+    }
+
+    fn is_numeric(self) -> bool {
+        match self {
+            Field::Price | Field::Beds | Field::Baths => true,
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Number(f64),
+    Text(String),
+}
+
+/// Items filters are evaluated against.
+pub trait Filterable {
+    fn value(&self, field: Field) -> Option<Value>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Expr {
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    Compare(Field, Op, Value),
+    In(Field, Vec<Value>),
+    Contains(Field, String),
+}
+
+impl Expr {
+    fn matches(&self, item: &dyn Filterable) -> bool {
+        match self {
+            Expr::And(left, right) => left.matches(item) && right.matches(item),
+            Expr::Or(left, right) => left.matches(item) || right.matches(item),
+            Expr::Not(expr) => !expr.matches(item),
+            Expr::Compare(field, op, expected) => match item.value(*field) {
+                Some(actual) => compare(&actual, *op, expected),
+                None => false,
+            },
+            Expr::In(field, values) => match item.value(*field) {
+                Some(actual) => values
+                    .iter()
+                    .any(|expected| compare(&actual, Op::Eq, expected)),
+                None => false,
+            },
+            Expr::Contains(field, needle) => match item.value(*field) {
+                Some(Value::Text(text)) => text.to_lowercase().contains(&needle.to_lowercase()),
+                _ => false,
+            },
+        }
+    }
+}
+
+fn compare(actual: &Value, op: Op, expected: &Value) -> bool {
+    match (actual, expected) {
+        (Value::Number(actual), Value::Number(expected)) => match op {
+            Op::Eq => actual == expected,
+            Op::Ne => actual != expected,
+            Op::Lt => actual < expected,
+            Op::Le => actual <= expected,
+            Op::Gt => actual > expected,
+            Op::Ge => actual >= expected,
+        },
+        (Value::Text(actual), Value::Text(expected)) => {
+            let equal = actual.to_lowercase() == expected.to_lowercase();
+            match op {
+                Op::Eq => equal,
+                Op::Ne => !equal,
+                _ => false,
+            }
+        }
+        _ => false,
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Ident(String),
+    Number(f64),
+    Text(String),
+    Op(Op),
+    LeftParen,
+    RightParen,
+    LeftBracket,
+    RightBracket,
+    Comma,
+}
+
+/// Tokens with their character offsets, for error messages.
+fn tokenize(source: &str) -> Result<Vec<(usize, Token)>> {
+    let chars: Vec<char> = source.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let start = i;
+        let c = chars[i];
+        let token = match c {
+            _ if c.is_whitespace() => {
+                i += 1;
+                continue;
+            }
+            '(' => Token::LeftParen,
+            ')' => Token::RightParen,
+            '[' => Token::LeftBracket,
+            ']' => Token::RightBracket,
+            ',' => Token::Comma,
+            '<' | '>' | '=' | '!' => {
+                let with_eq = chars.get(i + 1) == Some(&'=');
+                let op = match (c, with_eq) {
+                    ('<', false) => Op::Lt,
+                    ('<', true) => Op::Le,
+                    ('>', false) => Op::Gt,
+                    ('>', true) => Op::Ge,
+                    ('=', _) => Op::Eq,
+                    ('!', true) => Op::Ne,
+                    _ => bail!("unexpected `{}` at {}", c, start),
+                };
+                if with_eq {
+                    i += 1;
+                }
+                Token::Op(op)
+            }
+            '"' => {
+                let mut text = String::new();
+                i += 1;
+                loop {
+                    match chars.get(i) {
+                        Some('"') => break,
+                        Some('\\') if i + 1 < chars.len() => {
+                            text.push(chars[i + 1]);
+                            i += 2;
+                        }
+                        Some(c) => {
+                            text.push(*c);
+                            i += 1;
+                        }
+                        None => bail!("unterminated string at {}", start),
+                    }
+                }
+                Token::Text(text)
+            }
+            _ if c.is_ascii_digit() || c == '-' => {
+                i += 1;
+                while i < chars.len() && (chars[i].is_ascii_digit() || chars[i] == '.') {
+                    i += 1;
+                }
+                let number: String = chars[start..i].iter().collect();
+                tokens.push((
+                    start,
+                    Token::Number(
+                        number
+                            .parse()
+                            .map_err(|_| anyhow!("invalid number `{}` at {}", number, start))?,
+                    ),
+                ));
+                continue;
+            }
+            _ if c.is_alphabetic() || c == '_' => {
+                while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+                    i += 1;
+                }
+                tokens.push((start, Token::Ident(chars[start..i].iter().collect())));
+                continue;
+            }
+            _ => bail!("unexpected `{}` at {}", c, start),
+        };
+        tokens.push((start, token));
+        i += 1;
+    }
+    Ok(tokens)
+}
+
+/// Recursive descent over `or` > `and` > `not` > comparisons.
+struct Parser {
+    tokens: Vec<(usize, Token)>,
+    position: usize,
+    length: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position).map(|(_, token)| token)
+    }
+
+    fn offset(&self) -> usize {
+        self.tokens
+            .get(self.position)
+            .map_or(self.length, |(offset, _)| *offset)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self
+            .tokens
+            .get(self.position)
+            .map(|(_, token)| token.clone());
+        self.position += 1;
+        token
+    }
+
+    fn keyword(&mut self, keyword: &str) -> bool {
+        match self.peek() {
+            Some(Token::Ident(ident)) if ident.eq_ignore_ascii_case(keyword) => {
+                self.position += 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn expect(&mut self, expected: Token, what: &str) -> Result<()> {
+        let offset = self.offset();
+        match self.next() {
+            Some(ref token) if *token == expected => Ok(()),
+            _ => bail!("expected {} at {}", what, offset),
+        }
+    }
+
+    fn or(&mut self) -> Result<Expr> {
+        let mut expr = self.and()?;
+        while self.keyword("or") {
+            expr = Expr::Or(Box::new(expr), Box::new(self.and()?));
+        }
+        Ok(expr)
+    }
+
+    fn and(&mut self) -> Result<Expr> {
+        let mut expr = self.unary()?;
+        while self.keyword("and") {
+            expr = Expr::And(Box::new(expr), Box::new(self.unary()?));
+        }
+        Ok(expr)
+    }
+
+    fn unary(&mut self) -> Result<Expr> {
+        if self.keyword("not") {
+            return Ok(Expr::Not(Box::new(self.unary()?)));
+        }
+        if self.peek() == Some(&Token::LeftParen) {
+            self.position += 1;
+            let expr = self.or()?;
+            self.expect(Token::RightParen, "`)`")?;
+            return Ok(expr);
+        }
+        self.comparison()
+    }
+
+    fn comparison(&mut self) -> Result<Expr> {
+        let offset = self.offset();
+        let field = match self.next() {
+            Some(Token::Ident(name)) => Field::from_name(&name).ok_or_else(|| {
+                let known: Vec<&str> = Field::ALL.iter().map(|(name, _)| *name).collect();
+                anyhow!(
+                    "unknown field `{}` at {}, expected one of: {}",
+                    name,
+                    offset,
+                    known.join(", ")
+                )
+            })?,
+            _ => bail!("expected a field at {}", offset),
+        };
+
+        let offset = self.offset();
+        if self.keyword("in") {
+            self.expect(Token::LeftBracket, "`[`")?;
+            let mut values = vec![self.value(field)?];
+            while self.peek() == Some(&Token::Comma) {
+                self.position += 1;
+                values.push(self.value(field)?);
+            }
+            self.expect(Token::RightBracket, "`]`")?;
+            return Ok(Expr::In(field, values));
+        }
+        if self.keyword("contains") {
+            return match self.value(field)? {
+                Value::Text(needle) => Ok(Expr::Contains(field, needle)),
+                Value::Number(_) => bail!("`contains` needs a text field at {}", offset),
+            };
+        }
+        let op = match self.next() {
+            Some(Token::Op(op)) => op,
+            _ => bail!("expected a comparison at {}", offset),
+        };
+        if !field.is_numeric() && op != Op::Eq && op != Op::Ne {
+            bail!(
+                "text fields only take ==, !=, in and contains, at {}",
+                offset
+            );
+        }
+        Ok(Expr::Compare(field, op, self.value(field)?))
+    }
+
+    /// Literal of the type of `field`.
+    fn value(&mut self, field: Field) -> Result<Value> {
+        let offset = self.offset();
+        match (self.next(), field.is_numeric()) {
+            (Some(Token::Number(number)), true) => Ok(Value::Number(number)),
+            (Some(Token::Text(text)), false) => Ok(Value::Text(text)),
+            (Some(Token::Text(_)), true) => bail!("expected a number at {}", offset),
+            (Some(Token::Number(_)), false) => bail!("expected a quoted text at {}", offset),
+            _ => bail!("expected a value at {}", offset),
+        }
+    }
+}
+
+/// Parsed filter, deserialized from its source text.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Filter {
+    source: String,
+    expr: Expr,
+}
+
+impl Filter {
+    pub fn parse(source: &str) -> Result<Self> {
+        let mut parser = Parser {
+            tokens: tokenize(source)?,
+            position: 0,
+            length: source.chars().count(),
+        };
+        let expr = parser.or()?;
+        if parser.position < parser.tokens.len() {
+            bail!("unexpected input at {}", parser.offset());
+        }
+        Ok(Filter {
+            source: source.to_string(),
+            expr: expr,
+        })
+    }
+
+    pub fn matches(&self, item: &dyn Filterable) -> bool {
+        self.expr.matches(item)
+    }
+}
+
+impl Display for Filter {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(fmt, "{}", self.source)
+    }
+}
+
+impl TryFrom<String> for Filter {
+    type Error = anyhow::Error;
+
+    fn try_from(source: String) -> Result<Self> {
+        Filter::parse(&source).map_err(|e| anyhow!("invalid filter `{}`: {}", source, e))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::HashMap;
+
+    struct Listing(HashMap<&'static str, Value>);
+
+    impl Filterable for Listing {
+        fn value(&self, field: Field) -> Option<Value> {
+            let name = Field::ALL.iter().find(|(_, f)| *f == field)?.0;
+            self.0.get(name).cloned()
+        }
+    }
+
+    fn listing(price: f64, beds: f64, county: &str) -> Listing {
+        let mut values = HashMap::new();
+        values.insert("price", Value::Number(price));
+        values.insert("beds", Value::Number(beds));
+        values.insert("county", Value::Text(county.to_string()));
+        values.insert("status", Value::Text("New Listing".to_string()));
+        Listing(values)
+    }
+
+    #[test]
+    fn test_parse() {
+        let filter =
+            Filter::parse(r#"price < 150000 and beds >= 3 and county in ["Fulton","DeKalb"]"#)
+                .unwrap();
+        assert_eq!(
+            filter.expr,
+            Expr::And(
+                Box::new(Expr::And(
+                    Box::new(Expr::Compare(Field::Price, Op::Lt, Value::Number(150000.0))),
+                    Box::new(Expr::Compare(Field::Beds, Op::Ge, Value::Number(3.0))),
+                )),
+                Box::new(Expr::In(
+                    Field::County,
+                    vec![
+                        Value::Text("Fulton".to_string()),
+                        Value::Text("DeKalb".to_string())
+                    ]
+                )),
+            )
+        );
+
+        // `and` binds tighter than `or`
+        let filter = Filter::parse("beds > 4 or beds > 2 and price <= 1").unwrap();
+        match filter.expr {
+            Expr::Or(_, right) => assert!(matches!(*right, Expr::And(_, _))),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let error = |source: &str| Filter::parse(source).unwrap_err().to_string();
+        assert_eq!(
+            error("prise < 1"),
+            "unknown field `prise` at 0, expected one of: case_number, address, state, zip, \
+             county, price, beds, baths, listing_period, bid_open_date, status"
+        );
+        assert_eq!(error("price < \"cheap\""), "expected a number at 8");
+        assert_eq!(error("zip == 30032"), "expected a quoted text at 7");
+        assert_eq!(
+            error("county > \"Fulton\""),
+            "text fields only take ==, !=, in and contains, at 7"
+        );
+        assert_eq!(error("(beds > 2"), "expected `)` at 9");
+        assert_eq!(error("beds > 2 beds"), "unexpected input at 9");
+        assert_eq!(error("county == \"Fulton"), "unterminated string at 10");
+        assert_eq!(error("beds"), "expected a comparison at 4");
+        assert!(serde_json::from_str::<Filter>(r#""beds >""#).is_err());
+    }
+
+    #[test]
+    fn test_matches() {
+        let filter =
+            Filter::parse(r#"price < 150000 and beds >= 3 and county in ["Fulton", "DeKalb"]"#)
+                .unwrap();
+        assert!(filter.matches(&listing(120000.0, 3.0, "DEKALB")));
+        assert!(!filter.matches(&listing(150000.0, 3.0, "Fulton")));
+        assert!(!filter.matches(&listing(120000.0, 2.0, "Fulton")));
+        assert!(!filter.matches(&listing(120000.0, 4.0, "Cobb")));
+
+        let filter = Filter::parse(r#"not (status contains "pending" or baths > 1)"#).unwrap();
+        assert!(filter.matches(&listing(1.0, 1.0, "Cobb")));
+        let filter = Filter::parse("baths > 1").unwrap();
+        assert!(!filter.matches(&listing(1.0, 1.0, "Cobb")));
+        let filter = Filter::parse(r#"status != "sold" and price == 99.5"#).unwrap();
+        assert!(filter.matches(&listing(99.5, 1.0, "Cobb")));
+    }
+}
@@ -0,0 +1,458 @@
+use super::alerts::{self, Alert, Priority};
+use super::filter::{Field, Filterable, Value};
+use super::state::IdChecksum;
+use super::storage::BlobStorage;
+use anyhow::Result;
+use chrono::{TimeZone, Utc};
+use core::fmt::Display;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Entries kept before they're folded into a new checkpoint.
+const COMPACT_AFTER: usize = 50;
+
+/// Items whose every version is kept in the listing history.
+pub trait HistoryItem: IdChecksum {
+    fn fields(&self) -> Vec<String>;
+    fn price(&self) -> Option<u64>;
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ListingVersion {
+    pub seen_at: u64,
+    pub checksum: u64,
+    pub price: Option<u64>,
+    pub fields: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ListingTimeline {
+    pub first_seen: u64,
+    pub last_seen: u64,
+    /// Whether the listing was present in the latest scrape.
+    pub active: bool,
+    pub versions: Vec<ListingVersion>,
+    pub removed_at: Vec<u64>,
+    pub relisted_at: Vec<u64>,
+}
+
+impl ListingTimeline {
+    pub fn latest(&self) -> &ListingVersion {
+        self.versions.last().expect("timeline always has a version")
+    }
+
+    pub fn relist_count(&self) -> usize {
+        self.relisted_at.len()
+    }
+
+    /// Price of every version where it differs from the previous one.
+    pub fn price_moves(&self) -> Vec<(u64, u64)> {
+        let mut moves: Vec<(u64, u64)> = Vec::new();
+        for version in self.versions.iter() {
+            if let Some(price) = version.price {
+                if moves.last().map(|(_, last)| *last) != Some(price) {
+                    moves.push((version.seen_at, price));
+                }
+            }
+        }
+        moves
+    }
+
+    /// One line description, e.g.
+    /// `first seen 2020-05-01, relisted 1x, price $120,000 > $99,000`.
+    pub fn summary(&self) -> String {
+        let mut summary = format!("first seen {}", format_date(self.first_seen));
+        if self.relist_count() > 0 {
+            summary.push_str(&format!(", relisted {}x", self.relist_count()));
+        }
+        let moves = self.price_moves();
+        if moves.len() > 1 {
+            let prices: Vec<String> = moves.iter().map(|(_, p)| format_price(*p)).collect();
+            summary.push_str(&format!(", price {}", prices.join(" > ")));
+        }
+        summary
+    }
+}
+
+/// Every listing version ever scraped, keyed by id (case number for HUD
+/// listings).
+#[derive(Serialize, Deserialize, Default)]
+pub struct ListingHistory {
+    pub listings: HashMap<String, ListingTimeline>,
+    /// `seen_at` of the latest scrape recorded.
+    #[serde(default)]
+    pub recorded_until: u64,
+}
+
+/// What one scrape changes in the history. Listings it doesn't name were
+/// seen again if active, and stay gone otherwise.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct HistoryEntry {
+    pub seen_at: u64,
+    /// Versions differing from the listing's previous one, including the
+    /// first versions of new listings.
+    pub versions: Vec<(String, ListingVersion)>,
+    /// Active listings missing from the scrape.
+    pub removed: Vec<String>,
+    /// Removed listings back in the scrape.
+    pub relisted: Vec<String>,
+}
+
+impl ListingHistory {
+    pub fn timeline(&self, id: &str) -> Option<&ListingTimeline> {
+        self.listings.get(id)
+    }
+
+    /// Attaches the timeline summary of listings that changed or were
+    /// relisted before.
+    pub fn annotate<T: HistoryItem>(&self, item: T) -> WithTimeline<T> {
+        let (id, _) = item.id_checksum();
+        let summary = self
+            .timeline(&id)
+            .filter(|t| t.versions.len() > 1 || t.relist_count() > 0)
+            .map(|t| t.summary());
+        WithTimeline {
+            item: item,
+            summary: summary,
+            alerts: Vec::new(),
+        }
+    }
+
+    /// Records the full result of one scrape made at `seen_at`. Listings
+    /// missing from `items` are marked as removed. Returns the entry to
+    /// append to the stored history.
+    pub fn record<T: HistoryItem>(&mut self, items: &[T], seen_at: u64) -> HistoryEntry {
+        let mut latest: HashMap<String, u64> = HashMap::new();
+        let mut entry = HistoryEntry {
+            seen_at: seen_at,
+            versions: Vec::new(),
+            removed: Vec::new(),
+            relisted: Vec::new(),
+        };
+        for item in items.iter() {
+            let (id, checksum) = item.id_checksum();
+            let previous = latest
+                .get(&id)
+                .cloned()
+                .or_else(|| self.timeline(&id).map(|t| t.latest().checksum));
+            if previous != Some(checksum) {
+                let version = ListingVersion {
+                    seen_at: seen_at,
+                    checksum: checksum,
+                    price: item.price(),
+                    fields: item.fields(),
+                };
+                entry.versions.push((id.clone(), version));
+            }
+            let relisted = self.timeline(&id).map_or(false, |t| !t.active);
+            if latest.insert(id.clone(), checksum).is_none() && relisted {
+                entry.relisted.push(id);
+            }
+        }
+        for (id, timeline) in self.listings.iter() {
+            if timeline.active && !latest.contains_key(id) {
+                entry.removed.push(id.clone());
+            }
+        }
+
+        self.apply(&entry);
+        entry
+    }
+
+    /// Replays an entry made by `record`.
+    pub fn apply(&mut self, entry: &HistoryEntry) {
+        let seen_at = entry.seen_at;
+        for (id, version) in entry.versions.iter() {
+            match self.listings.get_mut(id) {
+                Some(timeline) => timeline.versions.push(version.clone()),
+                None => {
+                    self.listings.insert(
+                        id.clone(),
+                        ListingTimeline {
+                            first_seen: seen_at,
+                            last_seen: seen_at,
+                            active: true,
+                            versions: vec![version.clone()],
+                            removed_at: Vec::new(),
+                            relisted_at: Vec::new(),
+                        },
+                    );
+                }
+            }
+        }
+
+        for id in entry.removed.iter() {
+            if let Some(timeline) = self.listings.get_mut(id) {
+                timeline.active = false;
+                timeline.removed_at.push(seen_at);
+            }
+        }
+        for id in entry.relisted.iter() {
+            if let Some(timeline) = self.listings.get_mut(id) {
+                timeline.active = true;
+                timeline.relisted_at.push(seen_at);
+            }
+        }
+        for timeline in self.listings.values_mut().filter(|t| t.active) {
+            timeline.last_seen = seen_at;
+        }
+        self.recorded_until = seen_at;
+    }
+}
+
+/// The history kept in blob storage as a checkpoint of the whole history
+/// plus one entry per scrape since. Every `COMPACT_AFTER` entries they're
+/// folded into a new checkpoint.
+pub struct HistoryStore {
+    checkpoints: Box<dyn BlobStorage>,
+    entries: Box<dyn BlobStorage>,
+}
+
+impl HistoryStore {
+    pub fn new(checkpoints: Box<dyn BlobStorage>, entries: Box<dyn BlobStorage>) -> Self {
+        HistoryStore {
+            checkpoints: checkpoints,
+            entries: entries,
+        }
+    }
+
+    pub async fn load(&self) -> Result<ListingHistory> {
+        let mut history = self
+            .checkpoints
+            .load::<ListingHistory>()
+            .await?
+            .unwrap_or_default();
+        for name in self.entries.list().await? {
+            let entry = self.entries.load_named::<HistoryEntry>(&name).await?;
+            // left over when compacting was interrupted
+            if entry.seen_at > history.recorded_until {
+                history.apply(&entry);
+            }
+        }
+        Ok(history)
+    }
+
+    /// Appends `entry`, `history` is the result of recording it.
+    pub async fn append(&self, history: &ListingHistory, entry: &HistoryEntry) -> Result<()> {
+        self.entries.save(entry).await?;
+        let names = self.entries.list().await?;
+        if names.len() >= COMPACT_AFTER {
+            self.checkpoints.replace(history).await?;
+            for name in names.iter() {
+                self.entries.remove(name).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A diff item shown together with its history, if there's anything
+/// interesting in it.
+#[derive(Clone)]
+pub struct WithTimeline<T> {
+    pub item: T,
+    pub summary: Option<String>,
+    /// Highest priority first.
+    pub alerts: Vec<Alert>,
+}
+
+impl<T> WithTimeline<T> {
+    pub fn with_alerts(mut self, alerts: Vec<Alert>) -> Self {
+        self.alerts = alerts;
+        self
+    }
+
+    /// Of the most important alert, if any.
+    pub fn priority(&self) -> Option<Priority> {
+        self.alerts.first().map(|alert| alert.priority)
+    }
+}
+
+impl<T: IdChecksum> IdChecksum for WithTimeline<T> {
+    fn id_checksum(&self) -> (String, u64) {
+        self.item.id_checksum()
+    }
+}
+
+impl<T: Filterable> Filterable for WithTimeline<T> {
+    fn value(&self, field: Field) -> Option<Value> {
+        self.item.value(field)
+    }
+}
+
+impl<T: Serialize> Serialize for WithTimeline<T> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        #[derive(Serialize)]
+        struct WithAlerts<'a, T> {
+            #[serde(flatten)]
+            item: &'a T,
+            #[serde(skip_serializing_if = "<[Alert]>::is_empty")]
+            alerts: &'a [Alert],
+        }
+
+        WithAlerts {
+            item: &self.item,
+            alerts: &self.alerts,
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<T: Display> Display for WithTimeline<T> {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for alert in self.alerts.iter() {
+            write!(fmt, "{}\n", alerts::escape_markdown(&alert.to_string()))?;
+        }
+        write!(fmt, "{}", self.item)?;
+        if let Some(summary) = &self.summary {
+            write!(fmt, "{}\n", alerts::escape_markdown(summary))?;
+        }
+        Ok(())
+    }
+}
+
+fn format_date(timestamp: u64) -> String {
+    Utc.timestamp(timestamp as i64, 0).format("%Y-%m-%d").to_string()
+}
+
+pub fn format_price(price: u64) -> String {
+    let digits = price.to_string();
+    let mut formatted = String::new();
+    for (i, digit) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i) % 3 == 0 {
+            formatted.push(',');
+        }
+        formatted.push(digit);
+    }
+    format!("${}", formatted)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::storage::FsSystem;
+
+    struct Item(&'static str, u64);
+
+    impl IdChecksum for Item {
+        fn id_checksum(&self) -> (String, u64) {
+            (self.0.to_string(), self.1)
+        }
+    }
+
+    impl HistoryItem for Item {
+        fn fields(&self) -> Vec<String> {
+            vec![self.0.to_string(), self.1.to_string()]
+        }
+
+        fn price(&self) -> Option<u64> {
+            Some(self.1)
+        }
+    }
+
+    #[test]
+    fn test_versions_and_relistings() {
+        let mut history = ListingHistory::default();
+        history.record(&[Item("a", 120000), Item("b", 50000)], 100);
+        history.record(&[Item("a", 120000)], 200);
+        history.record(&[Item("a", 99000), Item("b", 50000)], 300);
+
+        let a = history.timeline("a").unwrap();
+        assert_eq!(a.first_seen, 100);
+        assert_eq!(a.last_seen, 300);
+        assert_eq!(a.versions.len(), 2);
+        assert_eq!(a.price_moves(), vec![(100, 120000), (300, 99000)]);
+
+        let b = history.timeline("b").unwrap();
+        assert!(b.active);
+        assert_eq!(b.removed_at, vec![200]);
+        assert_eq!(b.relisted_at, vec![300]);
+        assert_eq!(b.versions.len(), 1);
+    }
+
+    #[test]
+    fn test_entries_replay_the_history() {
+        let mut history = ListingHistory::default();
+        let mut replayed = ListingHistory::default();
+        let scrapes = vec![
+            (100, vec![Item("a", 120000), Item("b", 50000)]),
+            (200, vec![Item("a", 120000)]),
+            (300, vec![Item("a", 99000), Item("b", 50000)]),
+        ];
+        for (seen_at, items) in scrapes.iter() {
+            replayed.apply(&history.record(items, *seen_at));
+        }
+
+        let entry = history.record(&[Item("a", 99000)], 400);
+        assert!(entry.versions.is_empty());
+        assert_eq!(entry.removed, vec!["b".to_string()]);
+        assert!(entry.relisted.is_empty());
+        replayed.apply(&entry);
+        let entry = history.record(&[Item("a", 99000), Item("b", 50000)], 500);
+        assert!(entry.versions.is_empty());
+        assert!(entry.removed.is_empty());
+        assert_eq!(entry.relisted, vec!["b".to_string()]);
+        replayed.apply(&entry);
+        assert_eq!(
+            serde_json::to_value(&replayed.listings).unwrap(),
+            serde_json::to_value(&history.listings).unwrap()
+        );
+        assert_eq!(replayed.recorded_until, 500);
+    }
+
+    #[tokio::test]
+    async fn test_store_compacts_entries() {
+        let folder =
+            std::env::temp_dir().join(format!("scrappybot_history_{}", std::process::id()));
+        std::fs::create_dir_all(&folder).unwrap();
+        let folder = folder.display().to_string();
+        let store = || {
+            HistoryStore::new(
+                Box::new(FsSystem::new(folder.clone(), "history".to_string())),
+                Box::new(FsSystem::new(folder.clone(), "history_log".to_string())),
+            )
+        };
+
+        let mut history = ListingHistory::default();
+        for i in 0..COMPACT_AFTER as u64 + 2 {
+            let entry = history.record(&[Item("a", 1000 + i)], 100 + i);
+            store().append(&history, &entry).await.unwrap();
+        }
+        assert_eq!(store().entries.list().await.unwrap().len(), 2);
+        assert_eq!(store().checkpoints.list().await.unwrap().len(), 1);
+
+        let loaded = store().load().await.unwrap();
+        assert_eq!(
+            loaded.timeline("a").unwrap().versions.len(),
+            COMPACT_AFTER + 2
+        );
+        assert_eq!(loaded.recorded_until, 100 + COMPACT_AFTER as u64 + 1);
+        std::fs::remove_dir_all(&folder).unwrap();
+    }
+
+    #[test]
+    fn test_summary() {
+        let mut history = ListingHistory::default();
+        history.record(&[Item("a", 120000)], 86400);
+        history.record::<Item>(&[], 86400 * 2);
+        history.record(&[Item("a", 99000)], 86400 * 3);
+
+        assert_eq!(
+            history.timeline("a").unwrap().summary(),
+            "first seen 1970-01-02, relisted 1x, price $120,000 > $99,000"
+        );
+    }
+
+    #[test]
+    fn test_summary_is_escaped() {
+        let item = WithTimeline {
+            item: "a\n".to_string(),
+            summary: Some("first seen 2020-05-01 (relisted), price $1 > $2!".to_string()),
+            alerts: Vec::new(),
+        };
+        assert_eq!(
+            item.to_string(),
+            "a\nfirst seen 2020\\-05\\-01 \\(relisted\\), price $1 \\> $2\\!\n"
+        );
+    }
+}
@@ -0,0 +1,119 @@
+use super::state::{Diff, IdChecksum};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct ChannelJournal {
+    /// Item id to the checksum of the version that was delivered.
+    pub delivered: HashMap<String, u64>,
+    pub removed: HashSet<String>,
+}
+
+/// Records what every notification channel already received for the diff
+/// against the stored snapshot. It is persisted before the new snapshot is
+/// saved, so a rerun after a failure resumes instead of starting over, and
+/// cleared once the new snapshot is saved.
+#[derive(Serialize, Deserialize, Default)]
+pub struct DeliveryJournal {
+    pub channels: HashMap<String, ChannelJournal>,
+}
+
+impl DeliveryJournal {
+    pub fn is_empty(&self) -> bool {
+        self.channels.is_empty()
+    }
+
+    /// Part of `diff` the channel hasn't received yet.
+    pub fn pending<T: IdChecksum + Clone>(&self, channel: &str, diff: &Diff<T>) -> Diff<T> {
+        let journal = match self.channels.get(channel) {
+            Some(journal) => journal,
+            None => {
+                return Diff {
+                    added: diff.added.to_vec(),
+                    changed: diff.changed.to_vec(),
+                    removed: diff.removed.to_vec(),
+                }
+            }
+        };
+        let is_pending = |item: &&T| {
+            let (id, checksum) = item.id_checksum();
+            journal.delivered.get(&id) != Some(&checksum)
+        };
+
+        Diff {
+            added: diff.added.iter().filter(is_pending).cloned().collect(),
+            changed: diff.changed.iter().filter(is_pending).cloned().collect(),
+            removed: diff
+                .removed
+                .iter()
+                .filter(|id| !journal.removed.contains(*id))
+                .cloned()
+                .collect(),
+        }
+    }
+
+    /// Marks the first `count` items of `diff`, in the order channels send
+    /// them (added, changed, removed), as delivered.
+    pub fn record<T: IdChecksum>(&mut self, channel: &str, diff: &Diff<T>, count: usize) {
+        let journal = self
+            .channels
+            .entry(channel.to_string())
+            .or_insert_with(ChannelJournal::default);
+
+        let items = diff.added.iter().chain(diff.changed.iter());
+        let mut recorded = 0;
+        for item in items.take(count) {
+            let (id, checksum) = item.id_checksum();
+            journal.delivered.insert(id, checksum);
+            recorded += 1;
+        }
+        for id in diff.removed.iter().take(count - recorded) {
+            journal.removed.insert(id.clone());
+        }
+    }
+
+    pub fn record_all<T: IdChecksum>(&mut self, channel: &str, diff: &Diff<T>) {
+        let count = diff.added.len() + diff.changed.len() + diff.removed.len();
+        self.record(channel, diff, count);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn diff() -> Diff<(String, u64)> {
+        Diff {
+            added: vec![("a".to_string(), 1), ("b".to_string(), 2)],
+            changed: vec![("c".to_string(), 3)],
+            removed: vec!["d".to_string()],
+        }
+    }
+
+    #[test]
+    fn test_partial_delivery_resumes() {
+        let mut journal = DeliveryJournal::default();
+        journal.record("telegram", &diff(), 2);
+
+        let pending = journal.pending("telegram", &diff());
+        assert!(pending.added.is_empty());
+        assert_eq!(pending.changed, vec![("c".to_string(), 3)]);
+        assert_eq!(pending.removed, vec!["d".to_string()]);
+
+        let other = journal.pending("email", &diff());
+        assert_eq!(other.added.len(), 2);
+    }
+
+    #[test]
+    fn test_new_version_is_pending_again() {
+        let mut journal = DeliveryJournal::default();
+        journal.record_all("telegram", &diff());
+
+        let mut next = diff();
+        next.changed[0].1 = 4;
+        let pending = journal.pending("telegram", &next);
+        assert!(pending.added.is_empty());
+        assert_eq!(pending.changed, vec![("c".to_string(), 4)]);
+        assert!(pending.removed.is_empty());
+    }
+}
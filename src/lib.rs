@@ -1,15 +1,24 @@
 #[macro_use]
 extern crate anyhow;
 #[macro_use]
-extern crate glob;
-#[macro_use]
 extern crate select;
 #[macro_use]
 extern crate serde_json;
 
+mod alerts;
 mod api;
+mod filter;
+mod history;
+mod journal;
+mod listing_time;
 mod notification;
+mod photos;
+mod reminders;
+mod runlog;
 mod scrapes;
 mod state;
 mod storage;
-pub mod bot;
\ No newline at end of file
+pub mod bot;
+pub mod config;
+pub mod http;
+pub mod scheduler;
\ No newline at end of file
@@ -0,0 +1,117 @@
+//! The time zone of the listed dates and times, so alerts and reminders
+//! agree on what day it is and when a deadline passes.
+
+use anyhow::Result;
+use chrono::{DateTime, NaiveDate, NaiveDateTime, TimeZone, Utc};
+use chrono_tz::Tz;
+use serde::Deserialize;
+use std::convert::TryFrom;
+
+/// A time zone by its IANA name, e.g. `America/New_York`.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct ZoneName(pub Tz);
+
+impl Default for ZoneName {
+    fn default() -> Self {
+        ZoneName(Tz::UTC)
+    }
+}
+
+impl TryFrom<String> for ZoneName {
+    type Error = anyhow::Error;
+
+    fn try_from(name: String) -> Result<Self> {
+        name.parse::<Tz>().map(ZoneName).map_err(|_| {
+            anyhow!(
+                "unknown time zone `{}`, expected an IANA name like America/New_York",
+                name
+            )
+        })
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ListingTimeConfig {
+    /// Time zone of the listed dates and times, UTC unless set. Daylight
+    /// saving time is followed, e.g. `America/New_York` is 4 hours behind
+    /// UTC in summer and 5 in winter.
+    #[serde(default)]
+    pub time_zone: ZoneName,
+}
+
+impl ListingTimeConfig {
+    /// The date of the listings' time zone at `now`, to compare with the
+    /// listed ones.
+    pub fn today(&self, now: DateTime<Utc>) -> NaiveDate {
+        now.with_timezone(&self.time_zone.0).date().naive_local()
+    }
+
+    /// The moment a listed date and time stands for. A time repeated when
+    /// the clocks go back is taken the first time around, one skipped when
+    /// they go forward stands for nothing.
+    pub fn to_utc(&self, local: NaiveDateTime) -> Option<DateTime<Utc>> {
+        let time = self.time_zone.0.from_local_datetime(&local).earliest()?;
+        Some(time.with_timezone(&Utc))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn eastern() -> ListingTimeConfig {
+        serde_json::from_str(r#"{"time_zone": "America/New_York"}"#).unwrap()
+    }
+
+    #[test]
+    fn test_today_in_listed_time_zone() {
+        let winter = Utc.ymd(2020, 1, 11);
+        assert_eq!(
+            eastern().today(winter.and_hms(4, 0, 0)),
+            NaiveDate::from_ymd(2020, 1, 10)
+        );
+        assert_eq!(
+            eastern().today(winter.and_hms(5, 0, 0)),
+            NaiveDate::from_ymd(2020, 1, 11)
+        );
+
+        let summer = Utc.ymd(2020, 5, 11);
+        assert_eq!(
+            eastern().today(summer.and_hms(3, 0, 0)),
+            NaiveDate::from_ymd(2020, 5, 10)
+        );
+        assert_eq!(
+            eastern().today(summer.and_hms(4, 0, 0)),
+            NaiveDate::from_ymd(2020, 5, 11)
+        );
+        assert_eq!(
+            ListingTimeConfig::default().today(summer.and_hms(0, 0, 0)),
+            NaiveDate::from_ymd(2020, 5, 11)
+        );
+    }
+
+    #[test]
+    fn test_daylight_saving_transitions() {
+        // clocks go back at 2 AM on November 1st
+        let repeated = NaiveDate::from_ymd(2020, 11, 1).and_hms(1, 30, 0);
+        assert_eq!(
+            eastern().to_utc(repeated),
+            Some(Utc.ymd(2020, 11, 1).and_hms(5, 30, 0))
+        );
+        // and forward at 2 AM on March 8th
+        let skipped = NaiveDate::from_ymd(2020, 3, 8).and_hms(2, 30, 0);
+        assert_eq!(eastern().to_utc(skipped), None);
+    }
+
+    #[test]
+    fn test_unknown_time_zone() {
+        let error =
+            serde_json::from_str::<ListingTimeConfig>(r#"{"time_zone": "Eastern"}"#).unwrap_err();
+        assert!(
+            error.to_string().contains("unknown time zone `Eastern`"),
+            "{}",
+            error
+        );
+    }
+}
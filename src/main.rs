@@ -1,17 +1,29 @@
 extern crate anyhow;
-extern crate glob;
 extern crate select;
 extern crate serde_json;
 
+mod alerts;
 mod api;
+mod filter;
+mod history;
+mod http;
+mod journal;
 mod notification;
+mod photos;
+mod reminders;
+mod runlog;
 mod scrapes;
 mod state;
 mod storage;
 mod bot;
+mod config;
+mod cli;
+mod scheduler;
+
+use structopt::StructOpt;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    bot::run().await?;
+    cli::execute(cli::Cli::from_args()).await?;
     Ok(())
 }
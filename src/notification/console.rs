@@ -0,0 +1,27 @@
+use super::NotificationService;
+use crate::state::Diff;
+use anyhow::Result;
+use async_trait::async_trait;
+use core::fmt::Display;
+
+/// Prints the messages other channels would send. Used for dry runs.
+pub struct ConsoleService;
+
+#[async_trait]
+impl<T: Display + Sync> NotificationService<T> for ConsoleService {
+    async fn notify(&mut self, diff: &Diff<T>, desc: &str) -> Result<()> {
+        for item in diff.added.iter() {
+            println!("New {}:\n {}", desc, item);
+        }
+
+        for item in diff.changed.iter() {
+            println!("Modified {}:\n {}", desc, item);
+        }
+
+        for id in diff.removed.iter() {
+            println!("Removed {}: {}", desc, id);
+        }
+
+        Ok(())
+    }
+}
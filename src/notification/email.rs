@@ -0,0 +1,195 @@
+use super::{plain_text, NotificationService};
+use crate::filter::Filter;
+use crate::state::Diff;
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use core::fmt::Display;
+use lettre::smtp::authentication::Credentials;
+use lettre::smtp::{ClientSecurity, ClientTlsParameters, SmtpClient};
+use lettre::Transport;
+use lettre_email::EmailBuilder;
+use native_tls::TlsConnector;
+use serde::Deserialize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TlsMode {
+    /// Plain connection, only meant for local SMTP sinks.
+    None,
+    /// Plain connection upgraded with STARTTLS (usually port 587).
+    StartTls,
+    /// TLS from the first byte (usually port 465).
+    Tls,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SmtpConfig {
+    pub host: String,
+    pub port: u16,
+    pub tls: TlsMode,
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub password: Option<String>,
+    pub from: String,
+    pub recipients: Vec<String>,
+    /// Only listings matching it are emailed.
+    #[serde(default)]
+    pub filter: Option<Filter>,
+}
+
+/// Sends a single email per run summarizing everything in the diff.
+pub struct EmailService {
+    config: SmtpConfig,
+}
+
+impl EmailService {
+    pub fn new(config: SmtpConfig) -> Self {
+        EmailService { config: config }
+    }
+
+    fn send(&self, subject: String, html: String, text: String) -> Result<()> {
+        let config = &self.config;
+        let security = match config.tls {
+            TlsMode::None => ClientSecurity::None,
+            TlsMode::StartTls | TlsMode::Tls => {
+                let connector = TlsConnector::builder().build()?;
+                let params = ClientTlsParameters::new(config.host.clone(), connector);
+                if config.tls == TlsMode::Tls {
+                    ClientSecurity::Wrapper(params)
+                } else {
+                    ClientSecurity::Required(params)
+                }
+            }
+        };
+
+        let mut client = SmtpClient::new((config.host.as_str(), config.port), security)
+            .with_context(|| format!("failed to connect to {}:{}", config.host, config.port))?;
+        if let (Some(username), Some(password)) = (&config.username, &config.password) {
+            client = client.credentials(Credentials::new(username.clone(), password.clone()));
+        }
+
+        let mut builder = EmailBuilder::new()
+            .from(config.from.as_str())
+            .subject(subject)
+            .alternative(html, text);
+        for recipient in config.recipients.iter() {
+            builder = builder.to(recipient.as_str());
+        }
+        let email = builder.build()?;
+
+        client
+            .transport()
+            .send(email.into())
+            .with_context(|| format!("failed to send email via {}", config.host))?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl<T: Display + Sync> NotificationService<T> for EmailService {
+    async fn notify(&mut self, diff: &Diff<T>, desc: &str) -> Result<()> {
+        if diff.added.is_empty() && diff.changed.is_empty() {
+            return Ok(());
+        }
+
+        let added: Vec<String> = diff.added.iter().map(plain_text).collect();
+        let changed: Vec<String> = diff.changed.iter().map(plain_text).collect();
+
+        let subject = format!(
+            "{}: {} new, {} modified",
+            desc,
+            added.len(),
+            changed.len()
+        );
+        let text = render_text(desc, &added, &changed);
+        let html = render_html(desc, &added, &changed);
+
+        // lettre's smtp transport is blocking, keep it off the async workers
+        let service = EmailService::new(self.config.clone());
+        tokio::task::spawn_blocking(move || service.send(subject, html, text)).await?
+    }
+}
+
+fn render_text(desc: &str, added: &[String], changed: &[String]) -> String {
+    let mut text = String::new();
+    for (title, items) in [("New", added), ("Modified", changed)].iter() {
+        if items.is_empty() {
+            continue;
+        }
+        text.push_str(&format!("{} {} ({}):\n\n", title, desc, items.len()));
+        for item in items.iter() {
+            text.push_str(&format!("* {}\n", item));
+        }
+        text.push('\n');
+    }
+    text
+}
+
+fn render_html(desc: &str, added: &[String], changed: &[String]) -> String {
+    let mut html = String::from("<html><body>");
+    for (title, items) in [("New", added), ("Modified", changed)].iter() {
+        if items.is_empty() {
+            continue;
+        }
+        html.push_str(&format!(
+            "<h3>{} {} ({})</h3><ul>",
+            title,
+            escape_html(desc),
+            items.len()
+        ));
+        for item in items.iter() {
+            html.push_str(&format!("<li>{}</li>", escape_html(item)));
+        }
+        html.push_str("</ul>");
+    }
+    html.push_str("</body></html>");
+    html
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace("&", "&amp;")
+        .replace("<", "&lt;")
+        .replace(">", "&gt;")
+        .replace("\"", "&quot;")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_render_skips_empty_sections() {
+        let added = vec!["first".to_string(), "second".to_string()];
+        let text = render_text("listing", &added, &[]);
+        assert_eq!(text, "New listing (2):\n\n* first\n* second\n\n");
+
+        let html = render_html("listing", &[], &["<b>".to_string()]);
+        assert_eq!(
+            html,
+            "<html><body><h3>Modified listing (1)</h3><ul><li>&lt;b&gt;</li></ul></body></html>"
+        );
+    }
+
+    // Needs a local SMTP sink, e.g. `docker run -p 1025:1025 mailhog/mailhog`
+    #[test]
+    #[ignore]
+    fn test_send_to_local_sink() {
+        let mut service = EmailService::new(SmtpConfig {
+            host: "localhost".to_string(),
+            port: 1025,
+            tls: TlsMode::None,
+            username: None,
+            password: None,
+            from: "scrappybot@localhost".to_string(),
+            recipients: vec!["team@localhost".to_string()],
+            filter: None,
+        });
+        let diff = Diff {
+            added: vec!["123 Main St - $100\\,000".to_string()],
+            changed: Vec::new(),
+            removed: Vec::new(),
+        };
+        tokio_test::block_on(service.notify(&diff, "hudhome listing")).unwrap();
+    }
+}
@@ -0,0 +1,291 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use core::fmt::Display;
+use super::filter::{Filter, Filterable};
+use super::journal::DeliveryJournal;
+use super::state::{Diff, IdChecksum};
+use std::collections::HashMap;
+
+pub mod console;
+pub mod email;
+pub mod telegram;
+pub mod webhook;
+
+pub use console::ConsoleService;
+pub use email::{EmailService, SmtpConfig, TlsMode};
+pub use telegram::TelegramService;
+pub use webhook::{WebhookConfig, WebhookFormat, WebhookService};
+
+#[async_trait]
+pub trait NotificationService<T: Display + Sync>: Send {
+    async fn notify(&mut self, diff: &Diff<T>, desc: &str) -> Result<()>;
+}
+
+/// Error returned by `FanOutNotifier` when one or more channels failed.
+/// Channels that succeeded are not affected by failures of the others.
+#[derive(Debug)]
+pub struct ChannelFailures {
+    pub failures: Vec<(String, anyhow::Error)>,
+}
+
+impl Display for ChannelFailures {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(fmt, "{} notification channel(s) failed:", self.failures.len())?;
+        for (name, error) in self.failures.iter() {
+            write!(fmt, "\n  {}: {:#}", name, error)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ChannelFailures {}
+
+/// Returned by channels that send a diff item by item when they fail midway,
+/// so the delivery journal can record what already went out.
+#[derive(Debug)]
+pub struct PartialDelivery {
+    /// Number of items sent, counting added, then changed, then removed.
+    pub delivered: usize,
+    pub source: anyhow::Error,
+}
+
+impl Display for PartialDelivery {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(fmt, "failed after {} delivered item(s): {:#}", self.delivered, self.source)
+    }
+}
+
+impl std::error::Error for PartialDelivery {}
+
+/// Part of `diff` matching `filter`. Removed items are judged by their last
+/// version in `removed`, those without one are kept.
+fn select<T, R>(filter: &Filter, diff: Diff<T>, removed: &HashMap<String, R>) -> Diff<T>
+where
+    T: Filterable,
+    R: Filterable,
+{
+    Diff {
+        added: diff
+            .added
+            .into_iter()
+            .filter(|item| filter.matches(item))
+            .collect(),
+        changed: diff
+            .changed
+            .into_iter()
+            .filter(|item| filter.matches(item))
+            .collect(),
+        removed: diff
+            .removed
+            .into_iter()
+            .filter(|id| removed.get(id).map_or(true, |item| filter.matches(item)))
+            .collect(),
+    }
+}
+
+pub struct FanOutNotifier<T: Display + Sync> {
+    channels: Vec<(String, Box<dyn NotificationService<T>>, Option<Filter>)>,
+}
+
+impl<T: Display + Sync> FanOutNotifier<T> {
+    pub fn new() -> Self {
+        FanOutNotifier {
+            channels: Vec::new(),
+        }
+    }
+
+    pub fn add(&mut self, name: &str, channel: Box<dyn NotificationService<T>>) {
+        self.channels.push((name.to_string(), channel, None));
+    }
+
+    /// Adds a channel that, when `filter` is set, is only told about items
+    /// matching it by `notify_journaled`.
+    pub fn add_filtered(
+        &mut self,
+        name: &str,
+        channel: Box<dyn NotificationService<T>>,
+        filter: Option<Filter>,
+    ) {
+        self.channels.push((name.to_string(), channel, filter));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.channels.is_empty()
+    }
+
+    /// Like `notify`, but skips whatever `journal` says a channel already
+    /// received and records every successful delivery in it. Filtered
+    /// channels judge removed items by their last version in `removed`.
+    pub async fn notify_journaled<R>(
+        &mut self,
+        diff: &Diff<T>,
+        removed: &HashMap<String, R>,
+        desc: &str,
+        journal: &mut DeliveryJournal,
+    ) -> Result<()>
+    where
+        T: IdChecksum + Clone + Filterable,
+        R: Filterable + Sync,
+    {
+        let mut failures = Vec::new();
+        for (name, channel, filter) in self.channels.iter_mut() {
+            let mut pending = journal.pending(name, diff);
+            if let Some(filter) = filter {
+                pending = select(filter, pending, removed);
+            }
+            if pending.added.is_empty() && pending.changed.is_empty() && pending.removed.is_empty() {
+                continue;
+            }
+            match channel.notify(&pending, desc).await {
+                Ok(()) => journal.record_all(name, &pending),
+                Err(error) => {
+                    if let Some(partial) = error.downcast_ref::<PartialDelivery>() {
+                        journal.record(name, &pending, partial.delivered);
+                    }
+                    failures.push((name.clone(), error));
+                }
+            }
+        }
+
+        if failures.is_empty() {
+            Ok(())
+        } else {
+            Err(ChannelFailures { failures: failures }.into())
+        }
+    }
+}
+
+#[async_trait]
+impl<T: Display + Sync> NotificationService<T> for FanOutNotifier<T> {
+    async fn notify(&mut self, diff: &Diff<T>, desc: &str) -> Result<()> {
+        let mut failures = Vec::new();
+        for (name, channel, _) in self.channels.iter_mut() {
+            if let Err(error) = channel.notify(diff, desc).await {
+                failures.push((name.clone(), error));
+            }
+        }
+
+        if failures.is_empty() {
+            Ok(())
+        } else {
+            Err(ChannelFailures { failures: failures }.into())
+        }
+    }
+}
+
+/// Items are rendered for Telegram's MarkdownV2, which escapes punctuation
+/// with backslashes. Channels without markdown support strip them.
+pub(crate) fn plain_text<T: Display>(item: &T) -> String {
+    item.to_string().replace("\\", "").trim().to_string()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::filter::{Field, Value};
+    use anyhow::bail;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Debug, Clone, PartialEq)]
+    struct Item(&'static str, u64);
+
+    impl Display for Item {
+        fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(fmt, "{}", self.0)
+        }
+    }
+
+    impl IdChecksum for Item {
+        fn id_checksum(&self) -> (String, u64) {
+            (self.0.to_string(), self.1)
+        }
+    }
+
+    impl Filterable for Item {
+        fn value(&self, _field: Field) -> Option<Value> {
+            None
+        }
+    }
+
+    /// Records the ids it is told about, failing on every call if `fail`.
+    struct Recorder {
+        sent: Arc<Mutex<Vec<String>>>,
+        fail: bool,
+    }
+
+    #[async_trait]
+    impl NotificationService<Item> for Recorder {
+        async fn notify(&mut self, diff: &Diff<Item>, _desc: &str) -> Result<()> {
+            if self.fail {
+                bail!("unreachable");
+            }
+            let mut sent = self.sent.lock().unwrap();
+            sent.extend(diff.added.iter().map(|item| item.to_string()));
+            sent.extend(diff.removed.iter().cloned());
+            Ok(())
+        }
+    }
+
+    fn recorder(
+        notifier: &mut FanOutNotifier<Item>,
+        name: &str,
+        fail: bool,
+    ) -> Arc<Mutex<Vec<String>>> {
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        let channel = Recorder {
+            sent: sent.clone(),
+            fail: fail,
+        };
+        notifier.add(name, Box::new(channel));
+        sent
+    }
+
+    fn diff() -> Diff<Item> {
+        Diff {
+            added: vec![Item("a", 1), Item("b", 2)],
+            changed: Vec::new(),
+            removed: vec!["c".to_string()],
+        }
+    }
+
+    #[tokio::test]
+    async fn test_failing_channels_are_listed_and_dont_block_others() {
+        let mut notifier = FanOutNotifier::new();
+        recorder(&mut notifier, "telegram", true);
+        let email = recorder(&mut notifier, "email", false);
+        recorder(&mut notifier, "webhook", true);
+
+        let error = notifier.notify(&diff(), "test").await.unwrap_err();
+        assert_eq!(*email.lock().unwrap(), vec!["a", "b", "c"]);
+        let failures = error.downcast_ref::<ChannelFailures>().unwrap();
+        let names: Vec<&str> = failures
+            .failures
+            .iter()
+            .map(|(name, _)| name.as_str())
+            .collect();
+        assert_eq!(names, vec!["telegram", "webhook"]);
+        assert_eq!(
+            error.to_string(),
+            "2 notification channel(s) failed:\n  telegram: unreachable\n  webhook: unreachable"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_journaled_skips_delivered() {
+        let mut notifier = FanOutNotifier::new();
+        let telegram = recorder(&mut notifier, "telegram", false);
+        let email = recorder(&mut notifier, "email", false);
+        let mut journal = DeliveryJournal::default();
+        journal.record_all("telegram", &diff());
+        journal.record("email", &diff(), 1);
+
+        let removed: HashMap<String, Item> = HashMap::new();
+        notifier
+            .notify_journaled(&diff(), &removed, "test", &mut journal)
+            .await
+            .unwrap();
+        assert!(telegram.lock().unwrap().is_empty());
+        assert_eq!(*email.lock().unwrap(), vec!["b", "c"]);
+        assert!(journal.pending("email", &diff()).added.is_empty());
+    }
+}
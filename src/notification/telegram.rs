@@ -0,0 +1,59 @@
+use super::{NotificationService, PartialDelivery};
+use crate::api::telegram_api::{SendMessage, TelegramClient};
+use crate::state::Diff;
+use anyhow::Result;
+use async_trait::async_trait;
+use core::fmt::Display;
+
+pub struct TelegramService {
+    client: TelegramClient,
+    chat_id: String,
+}
+
+impl TelegramService {
+    pub fn new(client: TelegramClient, chat_id: &str) -> Self {
+        TelegramService {
+            client: client,
+            chat_id: chat_id.to_string(),
+        }
+    }
+
+    async fn send(&self, text: String) -> Result<()> {
+        let message = SendMessage {
+            chat_id: self.chat_id.clone(),
+            text: text,
+            parse_mode: Some("MarkdownV2".to_string()),
+            disable_web_page_preview: true,
+        };
+        self.client.send_message(&message).await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl<T: Display + Sync> NotificationService<T> for TelegramService {
+    async fn notify(&mut self, diff: &Diff<T>, desc: &str) -> Result<()> {
+        let messages = diff
+            .added
+            .iter()
+            .map(|item| format!("New {}:\n {}", desc, item))
+            .chain(
+                diff.changed
+                    .iter()
+                    .map(|item| format!("Modified {}:\n {}", desc, item)),
+            )
+            .collect::<Vec<_>>();
+
+        for (delivered, text) in messages.into_iter().enumerate() {
+            if let Err(error) = self.send(text).await {
+                return Err(PartialDelivery {
+                    delivered: delivered,
+                    source: error,
+                }
+                .into());
+            }
+        }
+
+        Ok(())
+    }
+}
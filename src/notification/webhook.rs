@@ -0,0 +1,361 @@
+use super::{plain_text, NotificationService, PartialDelivery};
+use crate::filter::Filter;
+use crate::http::RetryPolicy;
+use crate::state::Diff;
+use anyhow::{anyhow, bail, Result};
+use async_trait::async_trait;
+use core::fmt::Display;
+use hmac::{Hmac, Mac, NewMac};
+use reqwest::{Client, Url};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+
+const SIGNATURE_HEADER: &'static str = "X-Scrappybot-Signature";
+const DISCORD_MAX_EMBEDS: usize = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WebhookFormat {
+    /// The diff as is: `{"description", "added", "changed", "removed"}`.
+    Json,
+    /// Slack incoming-webhook message.
+    Slack,
+    /// Discord webhook message with one embed per listing.
+    Discord,
+}
+
+impl Default for WebhookFormat {
+    fn default() -> Self {
+        WebhookFormat::Json
+    }
+}
+
+fn default_max_retries() -> u32 {
+    3
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct WebhookConfig {
+    /// Names the channel in errors and the delivery journal, the URL's
+    /// host and a digest of the URL by default.
+    #[serde(default)]
+    pub name: Option<String>,
+    pub url: String,
+    #[serde(default)]
+    pub format: WebhookFormat,
+    /// When set, the body is signed with HMAC-SHA256 and the hex digest is
+    /// sent in the `X-Scrappybot-Signature: sha256=<digest>` header.
+    #[serde(default)]
+    pub secret: Option<String>,
+    /// Retries while the receiver can't be reached or answers 429. A
+    /// request it got is never repeated, not even after a 5xx, so nothing
+    /// is posted twice.
+    #[serde(default = "default_max_retries")]
+    pub max_retries: u32,
+    /// Only listings matching it are posted.
+    #[serde(default)]
+    pub filter: Option<Filter>,
+}
+
+impl WebhookConfig {
+    /// Name of the channel in errors and the delivery journal, e.g.
+    /// `webhook alerts` or `webhook hooks.slack.com#1a2b3c4d`. It stays the
+    /// same however the webhooks are reordered. Slack and Discord webhook
+    /// URLs hold their secret token, so only the host and a digest of the
+    /// URL are shown.
+    pub fn channel_name(&self) -> String {
+        if let Some(name) = &self.name {
+            return format!("webhook {}", name);
+        }
+        let digest = hex::encode(Sha256::digest(self.url.as_bytes()));
+        format!(
+            "webhook {}#{}",
+            host(&self.url).unwrap_or_default(),
+            &digest[..8]
+        )
+    }
+
+    /// `text` with the URL cut down to its host.
+    fn redact(&self, text: &str) -> String {
+        let host = host(&self.url).unwrap_or_default();
+        let text = text.replace(&self.url, &host);
+        match Url::parse(&self.url) {
+            // errors show the URL normalized
+            Ok(url) => text.replace(url.as_str(), &host),
+            Err(_) => text,
+        }
+    }
+}
+
+fn host(url: &str) -> Option<String> {
+    Url::parse(url).ok()?.host_str().map(str::to_string)
+}
+
+pub struct WebhookService {
+    config: WebhookConfig,
+    http_client: Client,
+    retry: RetryPolicy,
+}
+
+impl WebhookService {
+    pub fn new(config: WebhookConfig, http_client: Client) -> Self {
+        let retry = RetryPolicy::default().with_max_retries(config.max_retries);
+        WebhookService {
+            config: config,
+            http_client: http_client,
+            retry: retry,
+        }
+    }
+
+    /// Backoff from `retry`, the number of retries stays the webhook's own.
+    pub fn with_retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry.with_max_retries(self.config.max_retries);
+        self
+    }
+
+    /// Bodies to post in turn, each with the number of diff items it holds.
+    fn payloads<T: Display + Serialize>(&self, diff: &Diff<T>, desc: &str) -> Vec<(usize, Value)> {
+        let items = diff.added.len() + diff.changed.len() + diff.removed.len();
+        match self.config.format {
+            WebhookFormat::Json => vec![(
+                items,
+                json!({
+                    "description": desc,
+                    "added": diff.added,
+                    "changed": diff.changed,
+                    "removed": diff.removed,
+                }),
+            )],
+            WebhookFormat::Slack => vec![(items, json!({ "text": slack_text(diff, desc) }))],
+            WebhookFormat::Discord => discord_embeds(diff, desc)
+                .chunks(DISCORD_MAX_EMBEDS)
+                .map(|chunk| (chunk.len(), json!({ "embeds": chunk })))
+                .collect(),
+        }
+    }
+
+    async fn post(&self, body: Vec<u8>) -> Result<()> {
+        let signature = match &self.config.secret {
+            Some(secret) => Some(sign(secret, &body)?),
+            None => None,
+        };
+
+        let response = self
+            .retry
+            .send_once(|| {
+                let request = self
+                    .http_client
+                    .post(&self.config.url)
+                    .header(reqwest::header::CONTENT_TYPE, "application/json")
+                    .body(body.clone());
+                match &signature {
+                    Some(signature) => {
+                        request.header(SIGNATURE_HEADER, format!("sha256={}", signature))
+                    }
+                    None => request,
+                }
+            })
+            .await
+            .map_err(|error| {
+                let error = self.config.redact(&format!("{:#}", error));
+                anyhow!("webhook request failed: {}", error)
+            })?;
+
+        if !response.status().is_success() {
+            let status = response.status();
+            let text = response.text().await.unwrap_or_default();
+            bail!("webhook responded with {}: `{}`", status, text);
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl<T: Display + Serialize + Sync> NotificationService<T> for WebhookService {
+    async fn notify(&mut self, diff: &Diff<T>, desc: &str) -> Result<()> {
+        if diff.added.is_empty() && diff.changed.is_empty() && diff.removed.is_empty() {
+            return Ok(());
+        }
+
+        let mut delivered = 0;
+        for (items, payload) in self.payloads(diff, desc) {
+            let posted = match serde_json::to_vec(&payload) {
+                Ok(body) => self.post(body).await,
+                Err(error) => Err(error.into()),
+            };
+            if let Err(error) = posted {
+                return Err(PartialDelivery {
+                    delivered: delivered,
+                    source: error,
+                }
+                .into());
+            }
+            delivered += items;
+        }
+        Ok(())
+    }
+}
+
+fn sign(secret: &str, body: &[u8]) -> Result<String> {
+    let mut mac = Hmac::<Sha256>::new_varkey(secret.as_bytes())
+        .map_err(|_| anyhow!("invalid webhook secret"))?;
+    mac.update(body);
+    Ok(hex::encode(mac.finalize().into_bytes()))
+}
+
+fn slack_text<T: Display>(diff: &Diff<T>, desc: &str) -> String {
+    let mut lines = Vec::new();
+    for item in diff.added.iter() {
+        lines.push(format!("*New {}:* {}", desc, plain_text(item)));
+    }
+    for item in diff.changed.iter() {
+        lines.push(format!("*Modified {}:* {}", desc, plain_text(item)));
+    }
+    if !diff.removed.is_empty() {
+        lines.push(format!("*Removed {}:* {}", desc, diff.removed.join(", ")));
+    }
+    lines.join("\n")
+}
+
+fn discord_embeds<T: Display>(diff: &Diff<T>, desc: &str) -> Vec<Value> {
+    const GREEN: u32 = 0x2ecc71;
+    const YELLOW: u32 = 0xf1c40f;
+    const RED: u32 = 0xe74c3c;
+
+    let mut embeds = Vec::new();
+    for item in diff.added.iter() {
+        embeds.push(json!({
+            "title": format!("New {}", desc),
+            "description": plain_text(item),
+            "color": GREEN,
+        }));
+    }
+    for item in diff.changed.iter() {
+        embeds.push(json!({
+            "title": format!("Modified {}", desc),
+            "description": plain_text(item),
+            "color": YELLOW,
+        }));
+    }
+    for id in diff.removed.iter() {
+        embeds.push(json!({
+            "title": format!("Removed {}", desc),
+            "description": id,
+            "color": RED,
+        }));
+    }
+    embeds
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::api::test_support::FakeWebhook;
+    use reqwest::StatusCode;
+
+    fn diff() -> Diff<String> {
+        Diff {
+            added: vec!["a".to_string()],
+            changed: vec!["b".to_string()],
+            removed: vec!["c".to_string(), "d".to_string()],
+        }
+    }
+
+    #[test]
+    fn test_sign() {
+        // RFC 4231, test case 2
+        assert_eq!(
+            sign("Jefe", b"what do ya want for nothing?").unwrap(),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn test_slack_text() {
+        assert_eq!(
+            slack_text(&diff(), "listing"),
+            "*New listing:* a\n*Modified listing:* b\n*Removed listing:* c, d"
+        );
+    }
+
+    #[test]
+    fn test_channel_name_hides_the_url() {
+        let mut config = WebhookConfig {
+            name: None,
+            url: "https://hooks.slack.com/services/T000/B000/secret".to_string(),
+            format: WebhookFormat::Slack,
+            secret: None,
+            max_retries: 0,
+            filter: None,
+        };
+        let name = config.channel_name();
+        assert!(name.starts_with("webhook hooks.slack.com#"), "{}", name);
+        assert!(!name.contains("secret"));
+        assert_eq!(config.channel_name(), name);
+        config.url = "https://hooks.slack.com/services/T000/B000/other".to_string();
+        assert_ne!(config.channel_name(), name);
+        config.name = Some("alerts".to_string());
+        assert_eq!(config.channel_name(), "webhook alerts");
+        assert_eq!(
+            config.redact(
+                "error sending request for url \
+                 (https://hooks.slack.com/services/T000/B000/secret): timed out"
+            ),
+            "error sending request for url (hooks.slack.com): timed out"
+        );
+    }
+
+    #[test]
+    fn test_discord_embeds_are_chunked() {
+        let service = WebhookService::new(
+            WebhookConfig {
+                name: None,
+                url: "http://localhost".to_string(),
+                format: WebhookFormat::Discord,
+                secret: None,
+                max_retries: 0,
+                filter: None,
+            },
+            Client::new(),
+        );
+        let diff = Diff {
+            added: (0..25).map(|i| i.to_string()).collect(),
+            changed: Vec::new(),
+            removed: Vec::new(),
+        };
+        let payloads = service.payloads(&diff, "listing");
+        let sizes: Vec<(usize, usize)> = payloads
+            .iter()
+            .map(|(items, p)| (*items, p["embeds"].as_array().unwrap().len()))
+            .collect();
+        assert_eq!(sizes, vec![(10, 10), (10, 10), (5, 5)]);
+    }
+
+    #[tokio::test]
+    async fn test_partial_discord_delivery() {
+        let receiver = FakeWebhook::start().await;
+        receiver.fail_after(2, StatusCode::INTERNAL_SERVER_ERROR);
+        let mut service = WebhookService::new(
+            WebhookConfig {
+                name: None,
+                url: receiver.url(),
+                format: WebhookFormat::Discord,
+                secret: None,
+                max_retries: 2,
+                filter: None,
+            },
+            Client::new(),
+        );
+        let diff = Diff {
+            added: (0..25).map(|i| i.to_string()).collect(),
+            changed: Vec::new(),
+            removed: Vec::new(),
+        };
+
+        let error = service.notify(&diff, "listing").await.unwrap_err();
+        let partial = error.downcast_ref::<PartialDelivery>().unwrap();
+        assert_eq!(partial.delivered, 20);
+        assert_eq!(receiver.received().len(), 2);
+    }
+}
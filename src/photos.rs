@@ -0,0 +1,308 @@
+use crate::api::yandex_disk_api::{DiskClient, ResourceExists};
+use crate::scrapes::polite::PoliteClient;
+use crate::storage::s3::{self, S3Config};
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use reqwest::Url;
+use rusoto_s3::{PutObjectRequest, S3Client, S3};
+use select::document::Document;
+use select::predicate::Name;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs;
+use std::path::PathBuf;
+
+/// Folder under the storage location holding one folder per case number.
+pub const PHOTOS_FOLDER: &'static str = "photos";
+
+/// Runs that try a listing's photos before they're given up on.
+pub const MAX_ATTEMPTS: u32 = 3;
+
+fn default_max_per_listing() -> usize {
+    20
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct PhotosConfig {
+    /// Archive the photos of every new listing.
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default = "default_max_per_listing")]
+    pub max_per_listing: usize,
+}
+
+impl Default for PhotosConfig {
+    fn default() -> Self {
+        PhotosConfig {
+            enabled: false,
+            max_per_listing: default_max_per_listing(),
+        }
+    }
+}
+
+/// Content hashes of the photos already archived per case number.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct PhotoIndex {
+    pub listings: HashMap<String, BTreeSet<String>>,
+    /// Listings whose photos failed or were left out, with the failed
+    /// attempts so far. They're tried again by the next run.
+    #[serde(default)]
+    pub pending: BTreeMap<String, u32>,
+}
+
+impl PhotoIndex {
+    pub fn contains(&self, case_number: &str, hash: &str) -> bool {
+        self.listings
+            .get(case_number)
+            .map_or(false, |hashes| hashes.contains(hash))
+    }
+
+    pub fn insert(&mut self, case_number: &str, hash: String) {
+        self.listings
+            .entry(case_number.to_string())
+            .or_default()
+            .insert(hash);
+    }
+}
+
+/// Where photos end up, `{case_number}/{name}` under the photos folder.
+#[async_trait]
+pub trait PhotoArchive: Send + Sync {
+    /// Makes room for the photos of `case_number`, called once before the
+    /// first of them is put.
+    async fn prepare(&self, _case_number: &str) -> Result<()> {
+        Ok(())
+    }
+
+    /// Stores `data`, keeping a file that already exists under `name`.
+    async fn put(&self, case_number: &str, name: &str, data: Vec<u8>) -> Result<()>;
+}
+
+pub struct FsPhotoArchive {
+    folder: PathBuf,
+}
+
+impl FsPhotoArchive {
+    pub fn new(folder: &str) -> Self {
+        FsPhotoArchive {
+            folder: PathBuf::from(folder).join(PHOTOS_FOLDER),
+        }
+    }
+}
+
+#[async_trait]
+impl PhotoArchive for FsPhotoArchive {
+    async fn prepare(&self, case_number: &str) -> Result<()> {
+        let folder = self.folder.join(case_number);
+        fs::create_dir_all(&folder)
+            .with_context(|| format!("failed to create {}", folder.display()))?;
+        Ok(())
+    }
+
+    async fn put(&self, case_number: &str, name: &str, data: Vec<u8>) -> Result<()> {
+        let folder = self.folder.join(case_number);
+        let path = folder.join(name);
+        if path.exists() {
+            return Ok(());
+        }
+        let temp = folder.join(format!(".{}.tmp", name));
+        fs::write(&temp, data).with_context(|| format!("failed to write {}", temp.display()))?;
+        fs::rename(&temp, &path).with_context(|| {
+            format!("failed to rename {} to {}", temp.display(), path.display())
+        })?;
+        Ok(())
+    }
+}
+
+pub struct YandexPhotoArchive {
+    client: DiskClient,
+    folder: String,
+}
+
+impl YandexPhotoArchive {
+    pub fn new(client: DiskClient, disk_folder: &str) -> Self {
+        YandexPhotoArchive {
+            client: client,
+            folder: format!("{}/{}", disk_folder, PHOTOS_FOLDER),
+        }
+    }
+}
+
+#[async_trait]
+impl PhotoArchive for YandexPhotoArchive {
+    async fn prepare(&self, case_number: &str) -> Result<()> {
+        self.client
+            .create_folder(&format!("{}/{}", self.folder, case_number))
+            .await
+    }
+
+    async fn put(&self, case_number: &str, name: &str, data: Vec<u8>) -> Result<()> {
+        let path = format!("{}/{}/{}", self.folder, case_number, name);
+        match self.client.creat_new_file(&path, data).await {
+            Err(e) if e.downcast_ref::<ResourceExists>().is_none() => Err(e),
+            _ => Ok(()),
+        }
+    }
+}
+
+pub struct S3PhotoArchive {
+    client: S3Client,
+    bucket: String,
+    key_prefix: String,
+}
+
+impl S3PhotoArchive {
+    pub fn new(config: &S3Config) -> Result<Self> {
+        Ok(S3PhotoArchive {
+            client: s3::client(config)?,
+            bucket: config.bucket.clone(),
+            key_prefix: format!("{}{}/", config.prefix, PHOTOS_FOLDER),
+        })
+    }
+}
+
+#[async_trait]
+impl PhotoArchive for S3PhotoArchive {
+    /// Names are content hashes, so overwriting one changes nothing.
+    async fn put(&self, case_number: &str, name: &str, data: Vec<u8>) -> Result<()> {
+        let key = format!("{}{}/{}", self.key_prefix, case_number, name);
+        self.client
+            .put_object(PutObjectRequest {
+                bucket: self.bucket.clone(),
+                key: key.clone(),
+                body: Some(data.into()),
+                content_type: Some("image/jpeg".to_string()),
+                ..Default::default()
+            })
+            .await
+            .with_context(|| format!("failed to put {}", key))?;
+        Ok(())
+    }
+}
+
+pub fn details_url(case_number: &str) -> String {
+    format!(
+        "https://www.hudhomestore.com/Listing/PropertyDetails.aspx?caseNumber={}&sLanguage=ENGLISH",
+        case_number
+    )
+}
+
+/// Photo links on a listing details page. Listing photos are JPEGs, unlike
+/// the logos and icons around them.
+pub fn photo_urls(html: &str, page_url: &Url) -> Vec<Url> {
+    let document = Document::from(html);
+    let mut urls: Vec<Url> = Vec::new();
+    for src in document
+        .find(Name("img"))
+        .filter_map(|node| node.attr("src"))
+    {
+        let url = match page_url.join(src.trim()) {
+            Ok(url) => url,
+            Err(_) => continue,
+        };
+        let path = url.path().to_lowercase();
+        if (path.ends_with(".jpg") || path.ends_with(".jpeg")) && !urls.contains(&url) {
+            urls.push(url);
+        }
+    }
+    urls
+}
+
+/// Archived file name, the hex SHA-256 of the content.
+pub fn photo_name(data: &[u8]) -> String {
+    format!("{}.jpg", hex::encode(Sha256::digest(data)))
+}
+
+/// Downloads the photos of `case_number` not in `index` yet into `archive`
+/// and returns how many were stored.
+pub async fn archive_listing(
+    client: &PoliteClient,
+    archive: &dyn PhotoArchive,
+    index: &mut PhotoIndex,
+    case_number: &str,
+    max_photos: usize,
+) -> Result<usize> {
+    let page_url = Url::parse(&details_url(case_number))?;
+    let page = client.get_text(&page_url).await?;
+
+    let mut archived = 0;
+    for url in photo_urls(&page, &page_url).into_iter().take(max_photos) {
+        let data = client
+            .get_bytes(&url)
+            .await
+            .with_context(|| format!("failed to download {}", url))?;
+
+        let name = photo_name(&data);
+        if index.contains(case_number, &name) {
+            continue;
+        }
+        if archived == 0 {
+            archive.prepare(case_number).await?;
+        }
+        archive.put(case_number, &name, data).await?;
+        index.insert(case_number, name);
+        archived += 1;
+    }
+    Ok(archived)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_photo_urls() {
+        let html = r#"
+            <img src="/images/logo.png">
+            <img src="https://photos.example.com/101-123456/front.JPG">
+            <img src="Photos/101-123456/kitchen.jpeg?w=640">
+            <img src="https://photos.example.com/101-123456/front.JPG">
+        "#;
+        let page = Url::parse(&details_url("101-123456")).unwrap();
+        let urls: Vec<String> = photo_urls(html, &page)
+            .into_iter()
+            .map(|u| u.to_string())
+            .collect();
+        assert_eq!(
+            urls,
+            vec![
+                "https://photos.example.com/101-123456/front.JPG",
+                "https://www.hudhomestore.com/Listing/Photos/101-123456/kitchen.jpeg?w=640",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_fs_archive_dedupes_by_content() {
+        let folder = std::env::temp_dir().join(format!("scrappybot_photos_{}", std::process::id()));
+        let archive = FsPhotoArchive::new(&folder.display().to_string());
+        let name = photo_name(b"jpeg bytes");
+        assert_eq!(name, photo_name(b"jpeg bytes"));
+        assert_ne!(name, photo_name(b"other bytes"));
+
+        tokio_test::block_on(async {
+            archive.prepare("101-123456").await.unwrap();
+            archive
+                .put("101-123456", &name, b"jpeg bytes".to_vec())
+                .await
+                .unwrap();
+            archive
+                .put("101-123456", &name, b"jpeg bytes".to_vec())
+                .await
+                .unwrap();
+        });
+        let stored = folder.join(PHOTOS_FOLDER).join("101-123456");
+        assert_eq!(fs::read_dir(&stored).unwrap().count(), 1);
+        assert_eq!(
+            fs::read(stored.join(&name)).unwrap(),
+            b"jpeg bytes".to_vec()
+        );
+        fs::remove_dir_all(folder).unwrap();
+
+        let mut index = PhotoIndex::default();
+        index.insert("101-123456", name.clone());
+        assert!(index.contains("101-123456", &name));
+        assert!(!index.contains("101-654321", &name));
+    }
+}
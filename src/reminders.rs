@@ -0,0 +1,323 @@
+//! Reminders of bid deadlines and listing period ends of watched listings.
+//! They're worked out from the listing history, so sending them doesn't
+//! need a scrape.
+
+use crate::alerts::escape_markdown;
+use crate::filter::{Field, Filter, Filterable, Value};
+use crate::history::ListingHistory;
+use crate::journal::DeliveryJournal;
+use crate::listing_time::ListingTimeConfig;
+use crate::scrapes::hudhomestore::ListingItem;
+use crate::state::IdChecksum;
+use chrono::{DateTime, Duration, Utc};
+use core::fmt::Display;
+use serde::{Deserialize, Serialize};
+
+/// How long sent reminders stay in the journal after they were due.
+const JOURNAL_RETENTION_SECS: u64 = 7 * 24 * 60 * 60;
+
+fn default_hours_before() -> Vec<u32> {
+    vec![24]
+}
+
+fn default_period_ends() -> bool {
+    true
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct RemindersConfig {
+    /// Case numbers to remind about.
+    #[serde(default)]
+    pub watchlist: Vec<String>,
+    /// Listings matching it are reminded about as well.
+    #[serde(default)]
+    pub filter: Option<Filter>,
+    /// Hours before a bid deadline to remind at, e.g. `[48, 2]`.
+    #[serde(default = "default_hours_before")]
+    pub hours_before: Vec<u32>,
+    /// Remind when a listing period, such as the exclusive owner-occupant
+    /// one, is over.
+    #[serde(default = "default_period_ends")]
+    pub period_ends: bool,
+}
+
+impl Default for RemindersConfig {
+    fn default() -> Self {
+        RemindersConfig {
+            watchlist: Vec::new(),
+            filter: None,
+            hours_before: default_hours_before(),
+            period_ends: default_period_ends(),
+        }
+    }
+}
+
+impl RemindersConfig {
+    fn watches(&self, item: &ListingItem) -> bool {
+        self.watchlist.contains(&item.id)
+            || self
+                .filter
+                .as_ref()
+                .map_or(false, |filter| filter.matches(item))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ReminderKind {
+    BidDeadline { hours_before: u32 },
+    PeriodEnd,
+}
+
+#[derive(Clone, Serialize)]
+pub struct Reminder {
+    #[serde(flatten)]
+    pub kind: ReminderKind,
+    /// When the deadline is or the period ended, unix time.
+    pub due_at: u64,
+    pub message: String,
+    pub listing: ListingItem,
+}
+
+/// One reminder per listing and kind, a moved deadline makes a new one.
+impl IdChecksum for Reminder {
+    fn id_checksum(&self) -> (String, u64) {
+        let kind = match self.kind {
+            ReminderKind::BidDeadline { hours_before } => format!("bid_deadline_{}h", hours_before),
+            ReminderKind::PeriodEnd => "period_end".to_string(),
+        };
+        (format!("{}/{}", self.listing.id, kind), self.due_at)
+    }
+}
+
+impl Filterable for Reminder {
+    fn value(&self, field: Field) -> Option<Value> {
+        self.listing.value(field)
+    }
+}
+
+impl Display for Reminder {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(fmt, "{}\n{}", escape_markdown(&self.message), self.listing)
+    }
+}
+
+/// Reminders of the active watched listings in `history` that are due at
+/// `now`. Of several `hours_before` already passed only the closest to the
+/// deadline is due, period ends are due for a day after they happen.
+pub fn due(
+    config: &RemindersConfig,
+    listing_time: &ListingTimeConfig,
+    history: &ListingHistory,
+    now: DateTime<Utc>,
+) -> Vec<Reminder> {
+    let mut reminders = Vec::new();
+    for timeline in history.listings.values().filter(|timeline| timeline.active) {
+        let item = ListingItem::from(timeline.latest().fields.clone());
+        if !config.watches(&item) {
+            continue;
+        }
+
+        let deadline = item
+            .bid_deadline()
+            .and_then(|deadline| Some((deadline, listing_time.to_utc(deadline)?)));
+        if let Some((local, deadline)) = deadline.filter(|(_, deadline)| *deadline > now) {
+            let hours_before = config
+                .hours_before
+                .iter()
+                .filter(|hours| deadline - Duration::hours(**hours as i64) <= now)
+                .min();
+            if let Some(hours_before) = hours_before {
+                let hours_left = (deadline - now).num_hours();
+                reminders.push(Reminder {
+                    kind: ReminderKind::BidDeadline {
+                        hours_before: *hours_before,
+                    },
+                    due_at: deadline.timestamp() as u64,
+                    message: format!(
+                        "Bids are due in {} hour(s), by {}",
+                        hours_left,
+                        local.format("%m/%d/%Y %I:%M %p")
+                    ),
+                    listing: item.clone(),
+                });
+            }
+        }
+
+        if !config.period_ends {
+            continue;
+        }
+        let period = item.listing_period().and_then(|(name, last_day)| {
+            let ended = listing_time.to_utc(last_day.succ().and_hms(0, 0, 0))?;
+            Some((name, last_day, ended))
+        });
+        if let Some((name, last_day, ended)) = period {
+            if ended <= now && now < ended + Duration::days(1) {
+                let name = if name.is_empty() {
+                    "Listing".to_string()
+                } else {
+                    name
+                };
+                reminders.push(Reminder {
+                    kind: ReminderKind::PeriodEnd,
+                    due_at: ended.timestamp() as u64,
+                    message: format!("{} period ended on {}", name, last_day.format("%m/%d/%Y")),
+                    listing: item,
+                });
+            }
+        }
+    }
+    reminders.sort_by_key(|reminder| reminder.due_at);
+    reminders
+}
+
+/// Forgets reminders that were due long enough ago not to come up again.
+/// The reminder journal is never cleared otherwise, that's what keeps a
+/// reminder from going out twice.
+pub fn prune(journal: &mut DeliveryJournal, now: u64) {
+    for channel in journal.channels.values_mut() {
+        channel
+            .delivered
+            .retain(|_, due_at| *due_at + JOURNAL_RETENTION_SECS > now);
+    }
+    journal
+        .channels
+        .retain(|_, channel| !channel.delivered.is_empty());
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn listing(case_number: &str, period: &str, bid_open_date: &str) -> ListingItem {
+        ListingItem::from(
+            vec![
+                "",
+                case_number,
+                "123 Main St Decatur, GA 30032 DeKalb County",
+                "$100,000.00",
+                "3",
+                "2",
+                period,
+                bid_open_date,
+                "Bids Open",
+            ]
+            .into_iter()
+            .map(str::to_string)
+            .collect(),
+        )
+    }
+
+    fn config(json: &str) -> RemindersConfig {
+        serde_json::from_str(json).unwrap()
+    }
+
+    fn at(day: u32, hour: u32) -> DateTime<Utc> {
+        Utc.ymd(2020, 5, day).and_hms(hour, 0, 0)
+    }
+
+    fn kinds(reminders: &[Reminder]) -> Vec<(String, ReminderKind)> {
+        reminders
+            .iter()
+            .map(|reminder| (reminder.listing.id.clone(), reminder.kind))
+            .collect()
+    }
+
+    #[test]
+    fn test_bid_deadlines() {
+        let config = config(
+            r#"{"watchlist": ["105-111111"], "filter": "price < 50000", "hours_before": [48, 2]}"#,
+        );
+        let utc = ListingTimeConfig::default();
+        let mut history = ListingHistory::default();
+        history.record(
+            &[
+                listing("105-111111", "", "05/10/2020 11:00 PM"),
+                listing("105-222222", "", "05/10/2020 11:00 PM"),
+            ],
+            100,
+        );
+
+        assert!(due(&config, &utc, &history, at(8, 12)).is_empty());
+        let reminders = due(&config, &utc, &history, at(9, 12));
+        assert_eq!(
+            kinds(&reminders),
+            vec![(
+                "105-111111".to_string(),
+                ReminderKind::BidDeadline { hours_before: 48 }
+            )]
+        );
+        assert_eq!(
+            reminders[0].message,
+            "Bids are due in 35 hour(s), by 05/10/2020 11:00 PM"
+        );
+        assert_eq!(
+            kinds(&due(&config, &utc, &history, at(10, 22))),
+            vec![(
+                "105-111111".to_string(),
+                ReminderKind::BidDeadline { hours_before: 2 }
+            )]
+        );
+        assert!(due(&config, &utc, &history, at(11, 0)).is_empty());
+    }
+
+    #[test]
+    fn test_period_ends() {
+        let config = config(r#"{"filter": "price < 150000"}"#);
+        let eastern: ListingTimeConfig =
+            serde_json::from_str(r#"{"time_zone": "America/New_York"}"#).unwrap();
+        let mut history = ListingHistory::default();
+        history.record(
+            &[listing(
+                "105-111111",
+                "Exclusive 05/01/2020 - 05/10/2020",
+                "",
+            )],
+            100,
+        );
+
+        // midnight is 4 AM UTC in May, daylight saving time
+        assert!(due(&config, &eastern, &history, at(11, 3)).is_empty());
+        let reminders = due(&config, &eastern, &history, at(11, 4));
+        assert_eq!(
+            kinds(&reminders),
+            vec![("105-111111".to_string(), ReminderKind::PeriodEnd)]
+        );
+        assert_eq!(reminders[0].message, "Exclusive period ended on 05/10/2020");
+        assert!(due(&config, &eastern, &history, at(12, 5)).is_empty());
+
+        history.record::<ListingItem>(&[], 200);
+        assert!(due(&config, &eastern, &history, at(11, 5)).is_empty());
+    }
+
+    #[test]
+    fn test_prune() {
+        let mut history = ListingHistory::default();
+        history.record(&[listing("105-111111", "", "05/10/2020 11:00 PM")], 100);
+        let reminders = due(
+            &RemindersConfig {
+                watchlist: vec!["105-111111".to_string()],
+                ..RemindersConfig::default()
+            },
+            &ListingTimeConfig::default(),
+            &history,
+            at(10, 12),
+        );
+        let mut journal = DeliveryJournal::default();
+        journal.record_all(
+            "telegram",
+            &crate::state::Diff {
+                added: reminders.clone(),
+                changed: Vec::new(),
+                removed: Vec::new(),
+            },
+        );
+
+        let due_at = reminders[0].due_at;
+        prune(&mut journal, due_at + 60);
+        assert_eq!(journal.channels["telegram"].delivered.len(), 1);
+        prune(&mut journal, due_at + JOURNAL_RETENTION_SECS);
+        assert!(journal.is_empty());
+    }
+}
@@ -0,0 +1,26 @@
+use serde::{Deserialize, Serialize};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Number of run records kept in storage, older ones are pruned.
+pub const RETENTION: usize = 500;
+
+/// One bot cycle, successful or not. Recorded every run so that "nothing
+/// changed" can be told apart from "the bot didn't run".
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RunRecord {
+    pub source: String,
+    pub started_at: u64,
+    pub finished_at: u64,
+    pub duration_ms: u64,
+    pub added: usize,
+    pub changed: usize,
+    pub removed: usize,
+    pub error: Option<String>,
+}
+
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("System time should be after UNIX EPOCH")
+        .as_secs()
+}
@@ -0,0 +1,212 @@
+use crate::bot;
+use crate::config::Config;
+use crate::http::HttpClients;
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::Utc;
+use cron::Schedule;
+use futures::future::{join_all, Future};
+use rand::Rng;
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{watch, Mutex};
+
+/// `reminders` sends the due reminders from stored listings, no scrape.
+pub const SOURCES: &[&str] = &["hudhomestore", "reminders"];
+
+struct Job {
+    source: String,
+    schedule: Schedule,
+    jitter_secs: u64,
+    /// Shared between all jobs of the same source so their runs never overlap.
+    running: Arc<Mutex<()>>,
+}
+
+impl Job {
+    fn delay_until_next(&self) -> Option<Duration> {
+        let next = self.schedule.upcoming(Utc).next()?;
+        let until = (next - Utc::now()).to_std().unwrap_or(Duration::from_secs(0));
+        let jitter = if self.jitter_secs > 0 {
+            rand::thread_rng().gen_range(0, self.jitter_secs)
+        } else {
+            0
+        };
+        Some(until + Duration::from_secs(jitter))
+    }
+
+    /// Runs `cycle` unless a job of the same source is still running, then
+    /// it's skipped and `None` returned.
+    async fn run_exclusive<F: Future>(&self, cycle: F) -> Option<F::Output> {
+        let _guard = self.running.try_lock().ok()?;
+        Some(cycle.await)
+    }
+}
+
+/// Runs every configured schedule until SIGTERM or Ctrl-C. A cycle that is
+/// already running when the signal arrives is finished before returning.
+pub async fn run_daemon(config: &Config) -> Result<()> {
+    let jobs = jobs(config)?;
+    let http = HttpClients::new(&config.http);
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+
+    let mut terminate = signal(SignalKind::terminate())?;
+    let signals = async move {
+        tokio::select! {
+            _ = terminate.recv() => {},
+            _ = tokio::signal::ctrl_c() => {},
+        }
+        println!("shutting down after the current cycle");
+        let _ = shutdown_tx.broadcast(true);
+    };
+    let loops = join_all(
+        jobs.iter()
+            .map(|job| run_job(config, &http, job, shutdown_rx.clone())),
+    );
+    tokio::pin!(loops);
+
+    tokio::select! {
+        _ = &mut loops => return Ok(()),
+        _ = signals => {},
+    }
+    loops.await;
+    Ok(())
+}
+
+fn jobs(config: &Config) -> Result<Vec<Job>> {
+    let mut locks: HashMap<String, Arc<Mutex<()>>> = HashMap::new();
+    let mut jobs = Vec::new();
+    for schedule in config.schedules.iter() {
+        if !SOURCES.contains(&schedule.source.as_str()) {
+            bail!(
+                "unknown source `{}`, expected one of: {}",
+                schedule.source,
+                SOURCES.join(", ")
+            );
+        }
+        let running = locks
+            .entry(schedule.source.clone())
+            .or_insert_with(|| Arc::new(Mutex::new(())))
+            .clone();
+        jobs.push(Job {
+            source: schedule.source.clone(),
+            schedule: Schedule::from_str(&schedule.cron)
+                .map_err(|e| anyhow!("{:?}", e))
+                .with_context(|| format!("invalid cron expression `{}`", schedule.cron))?,
+            jitter_secs: schedule.jitter_secs,
+            running: running,
+        });
+    }
+    if jobs.is_empty() {
+        bail!("no schedules configured");
+    }
+    Ok(jobs)
+}
+
+async fn run_job(
+    config: &Config,
+    http: &HttpClients,
+    job: &Job,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    loop {
+        let delay = match job.delay_until_next() {
+            Some(delay) => delay,
+            None => return,
+        };
+        tokio::select! {
+            _ = tokio::time::delay_for(delay) => {},
+            _ = wait_for_shutdown(&mut shutdown) => return,
+        }
+
+        let cycle = run_cycle(config, http, job);
+        if job.run_exclusive(cycle).await.is_none() {
+            println!(
+                "{}: previous run is still in progress, skipping",
+                job.source
+            );
+        }
+    }
+}
+
+async fn run_cycle(config: &Config, http: &HttpClients, job: &Job) {
+    let options = bot::RunOptions::default();
+    if job.source == "reminders" {
+        match bot::remind(config, http, &options).await {
+            Ok(due) => println!("{}: due: {}", job.source, due),
+            Err(error) => eprintln!("{}: run failed: {}", job.source, error),
+        }
+        return;
+    }
+    match bot::run(config, http, &options).await {
+        Ok(stats) => println!(
+            "{}: added: {}, changed: {}, removed: {}",
+            job.source, stats.added, stats.changed, stats.removed
+        ),
+        Err(error) => eprintln!("{}: run failed: {}", job.source, error),
+    }
+}
+
+async fn wait_for_shutdown(shutdown: &mut watch::Receiver<bool>) {
+    while let Some(stop) = shutdown.recv().await {
+        if stop {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn config(schedules: &str) -> Config {
+        serde_json::from_str(&format!(r#"{{"schedules": {}}}"#, schedules)).unwrap()
+    }
+
+    #[test]
+    fn test_jobs() {
+        let jobs = jobs(&config(
+            r#"[
+                {"cron": "0 0 * * * *", "jitter_secs": 30},
+                {"source": "reminders", "cron": "0 30 * * * *"}
+            ]"#,
+        ))
+        .unwrap();
+        assert_eq!(jobs[0].source, "hudhomestore");
+        assert_eq!(jobs[0].jitter_secs, 30);
+        assert_eq!(jobs[1].source, "reminders");
+        let delay = jobs[1].delay_until_next().unwrap();
+        assert!(delay <= Duration::from_secs(3600));
+
+        assert!(jobs(&config("[]")).is_err());
+    }
+
+    #[test]
+    fn test_invalid_schedules() {
+        let error = jobs(&config(r#"[{"source": "zillow", "cron": "0 0 * * * *"}]"#))
+            .unwrap_err()
+            .to_string();
+        assert_eq!(
+            error,
+            "unknown source `zillow`, expected one of: hudhomestore, reminders"
+        );
+
+        let error = jobs(&config(r#"[{"cron": "every hour"}]"#)).unwrap_err();
+        assert_eq!(error.to_string(), "invalid cron expression `every hour`");
+    }
+
+    #[tokio::test]
+    async fn test_overlapping_runs_are_skipped() {
+        let jobs = jobs(&config(
+            r#"[{"cron": "0 0 * * * *"}, {"cron": "0 30 * * * *"}]"#,
+        ))
+        .unwrap();
+
+        let in_progress = jobs[0].running.try_lock().unwrap();
+        assert_eq!(jobs[1].run_exclusive(async { 1 }).await, None);
+        assert_eq!(jobs[0].run_exclusive(async { 2 }).await, None);
+        drop(in_progress);
+        assert_eq!(jobs[1].run_exclusive(async { 3 }).await, Some(3));
+    }
+}
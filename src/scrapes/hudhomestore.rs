@@ -1,20 +1,34 @@
+use crate::filter::{Field, Filterable, Value};
+use crate::history::HistoryItem;
+use crate::scrapes::polite::PoliteClient;
 use crate::state::IdChecksum;
+use chrono::{NaiveDate, NaiveDateTime};
 use core::fmt::Display;
 use std::hash::{Hash, Hasher};
 
-use reqwest::header::USER_AGENT;
+use reqwest::Url;
 use select::document::Document;
 use select::predicate::{Class, Name};
+use serde::Serialize;
 use std::collections::hash_map::DefaultHasher;
 
-#[derive(Clone)]
+const CASE_NUMBER_COLUMN: usize = 1;
+const ADDRESS_COLUMN: usize = 2;
+const PRICE_COLUMN: usize = 3;
+const BEDS_COLUMN: usize = 4;
+const BATHS_COLUMN: usize = 5;
+const LISTING_PERIOD_COLUMN: usize = 6;
+const BID_OPEN_DATE_COLUMN: usize = 7;
+const STATUS_COLUMN: usize = 8;
+
+#[derive(Clone, Serialize)]
 pub struct ListingItem {
     pub id: String,
     pub csv: Vec<String>,
     pub checksum: u64,
 }
 
-impl ListingItem {
+impl From<Vec<String>> for ListingItem {
     fn from(csv: Vec<String>) -> Self {
         let mut s = DefaultHasher::new();
         csv.hash(&mut s);
@@ -28,12 +42,123 @@ impl ListingItem {
     }
 }
 
+impl ListingItem {
+    /// Bid open date column, e.g. `05/11/2020` or `05/11/2020 11:59 PM`.
+    /// HUD opens the submitted bids then, so it is the last moment to bid.
+    /// Without a time bids are due by the end of the day.
+    pub fn bid_deadline(&self) -> Option<NaiveDateTime> {
+        let column = self.csv.get(BID_OPEN_DATE_COLUMN)?.trim();
+        if let Ok(deadline) = NaiveDateTime::parse_from_str(column, "%m/%d/%Y %I:%M %p") {
+            return Some(deadline);
+        }
+        dates(column).first().map(|date| date.and_hms(23, 59, 59))
+    }
+
+    /// Name and last day of the listing period in a column like
+    /// `Exclusive 05/01/2020 - 05/10/2020`, the name is empty when the
+    /// column has only dates.
+    pub fn listing_period(&self) -> Option<(String, NaiveDate)> {
+        let column = self.csv.get(LISTING_PERIOD_COLUMN)?;
+        let end = *dates(column).last()?;
+        let name: Vec<&str> = column
+            .split_whitespace()
+            .filter(|word| *word != "-" && parse_date(word).is_none())
+            .collect();
+        Some((name.join(" "), end))
+    }
+}
+
+fn parse_date(word: &str) -> Option<NaiveDate> {
+    NaiveDate::parse_from_str(word, "%m/%d/%Y").ok()
+}
+
+/// Every `mm/dd/yyyy` date in a column.
+fn dates(column: &str) -> Vec<NaiveDate> {
+    column.split_whitespace().filter_map(parse_date).collect()
+}
+
 impl IdChecksum for ListingItem {
     fn id_checksum(&self) -> (String, u64) {
         (self.id.clone(), self.checksum)
     }
 }
 
+impl HistoryItem for ListingItem {
+    fn fields(&self) -> Vec<String> {
+        self.csv.clone()
+    }
+
+    /// Price column looks like `$123,456.00`.
+    fn price(&self) -> Option<u64> {
+        let column = self.csv.get(PRICE_COLUMN)?;
+        let dollars = column.split('.').next().unwrap_or("");
+        let digits: String = dollars.chars().filter(|c| c.is_ascii_digit()).collect();
+        digits.parse().ok()
+    }
+}
+
+/// First number in a column such as `3` or `2.1`.
+fn number(column: &str) -> Option<f64> {
+    let number: String = column
+        .trim_start_matches(|c: char| !c.is_ascii_digit())
+        .chars()
+        .take_while(|c| c.is_ascii_digit() || *c == '.')
+        .collect();
+    number.parse().ok()
+}
+
+/// State, ZIP code and county of an address like
+/// `123 Main St Atlanta, GA 30303-1234 Fulton County`.
+fn address_parts(address: &str) -> (Option<String>, Option<String>, Option<String>) {
+    let words: Vec<&str> = address
+        .split_whitespace()
+        .map(|word| word.trim_matches(','))
+        .collect();
+    let is_zip = |word: &&str| word.len() >= 5 && word.chars().take(5).all(|c| c.is_ascii_digit());
+    let zip_at = match words.iter().rposition(|word| is_zip(word)) {
+        Some(zip_at) => zip_at,
+        None => return (None, None, None),
+    };
+    let zip = words[zip_at][..5].to_string();
+    let state = zip_at
+        .checked_sub(1)
+        .map(|at| words[at])
+        .filter(|word| word.len() == 2 && word.chars().all(|c| c.is_ascii_uppercase()))
+        .map(str::to_string);
+    let county = words[zip_at + 1..]
+        .iter()
+        .position(|word| word.eq_ignore_ascii_case("county"))
+        .filter(|length| *length > 0)
+        .map(|length| words[zip_at + 1..zip_at + 1 + length].join(" "));
+    (state, Some(zip), county)
+}
+
+impl Filterable for ListingItem {
+    fn value(&self, field: Field) -> Option<Value> {
+        let column = |index: usize| self.csv.get(index).filter(|column| !column.is_empty());
+        let text = |index: usize| column(index).map(|column| Value::Text(column.clone()));
+        let (state, zip, county) =
+            column(ADDRESS_COLUMN).map_or((None, None, None), |address| address_parts(address));
+        match field {
+            Field::CaseNumber => text(CASE_NUMBER_COLUMN),
+            Field::Address => text(ADDRESS_COLUMN),
+            Field::State => state.map(Value::Text),
+            Field::Zip => zip.map(Value::Text),
+            Field::County => county.map(Value::Text),
+            Field::Price => self.price().map(|price| Value::Number(price as f64)),
+            Field::Beds => column(BEDS_COLUMN)
+                .and_then(|c| number(c))
+                .map(Value::Number),
+            Field::Baths => column(BATHS_COLUMN)
+                .and_then(|c| number(c))
+                .map(Value::Number),
+            Field::ListingPeriod => text(LISTING_PERIOD_COLUMN),
+            Field::BidOpenDate => text(BID_OPEN_DATE_COLUMN),
+            Field::Status => text(STATUS_COLUMN),
+        }
+    }
+}
+
 impl Display for ListingItem {
     fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::result::Result<(), std::fmt::Error> {
         let url = format!("[{}](https://www.hudhomestore.com/Listing/PropertyDetails.aspx?caseNumber={}&sLanguage=ENGLISH)", self.csv[2], self.id);
@@ -47,15 +172,10 @@ impl Display for ListingItem {
     }
 }
 
-pub async fn scrape() -> Result<Vec<ListingItem>, Box<dyn std::error::Error>> {
-    let client = reqwest::Client::new();
+const SEARCH_URL: &'static str = "https://www.hudhomestore.com/Listing/PropertySearchResult.aspx?pageId=1&zipCode=&city=&county=&sState=GA&fromPrice=0&toPrice=0&fCaseNumber=&bed=0&bath=0&street=&buyerType=0&specialProgram=&Status=0&indoorAmenities=&outdoorAmenities=&housingType=&stories=&parking=&propertyAge=&OrderbyName=SCASENUMBER&OrderbyValue=ASC&sPageSize=100&sLanguage=ENGLISH";
 
-    let resp = client.get("https://www.hudhomestore.com/Listing/PropertySearchResult.aspx?pageId=1&zipCode=&city=&county=&sState=GA&fromPrice=0&toPrice=0&fCaseNumber=&bed=0&bath=0&street=&buyerType=0&specialProgram=&Status=0&indoorAmenities=&outdoorAmenities=&housingType=&stories=&parking=&propertyAge=&OrderbyName=SCASENUMBER&OrderbyValue=ASC&sPageSize=100&sLanguage=ENGLISH")
-        .header(USER_AGENT, "curl/7.54.0")
-        .send()
-        .await?
-        .text()
-        .await?;
+pub async fn scrape(client: &PoliteClient) -> Result<Vec<ListingItem>, Box<dyn std::error::Error>> {
+    let resp = client.get_text(&Url::parse(SEARCH_URL)?).await?;
 
     let document = Document::from(&resp[..]);
 
@@ -77,3 +197,68 @@ pub async fn scrape() -> Result<Vec<ListingItem>, Box<dyn std::error::Error>> {
     }
     Ok(items)
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_filterable_fields() {
+        let item = ListingItem::from(
+            vec![
+                "",
+                "105-123456",
+                "123 Main St Decatur, GA 30032-1234 De Kalb County",
+                "$125,000.00",
+                "3",
+                "2.1",
+                "05/01/2020 - 05/10/2020",
+                "05/11/2020",
+                "New Listing",
+            ]
+            .into_iter()
+            .map(str::to_string)
+            .collect(),
+        );
+        let text = |value: &str| Some(Value::Text(value.to_string()));
+        assert_eq!(item.value(Field::CaseNumber), text("105-123456"));
+        assert_eq!(item.value(Field::State), text("GA"));
+        assert_eq!(item.value(Field::Zip), text("30032"));
+        assert_eq!(item.value(Field::County), text("De Kalb"));
+        assert_eq!(item.value(Field::Price), Some(Value::Number(125000.0)));
+        assert_eq!(item.value(Field::Beds), Some(Value::Number(3.0)));
+        assert_eq!(item.value(Field::Baths), Some(Value::Number(2.1)));
+        assert_eq!(item.value(Field::Status), text("New Listing"));
+
+        let short = ListingItem::from(vec!["".to_string(), "105-654321".to_string()]);
+        assert_eq!(short.value(Field::County), None);
+        assert_eq!(short.value(Field::Beds), None);
+    }
+
+    #[test]
+    fn test_dates() {
+        let item = |period: &str, bid_open_date: &str| {
+            let mut csv = vec![String::new(); 9];
+            csv[1] = "105-123456".to_string();
+            csv[LISTING_PERIOD_COLUMN] = period.to_string();
+            csv[BID_OPEN_DATE_COLUMN] = bid_open_date.to_string();
+            ListingItem::from(csv)
+        };
+        let may = |day| NaiveDate::from_ymd(2020, 5, day);
+
+        let dated = item("Exclusive 05/01/2020 - 05/10/2020", "05/10/2020 11:30 AM");
+        assert_eq!(dated.bid_deadline(), Some(may(10).and_hms(11, 30, 0)));
+        assert_eq!(
+            dated.listing_period(),
+            Some(("Exclusive".to_string(), may(10)))
+        );
+
+        let undated = item("05/01/2020 - 05/10/2020", "05/11/2020");
+        assert_eq!(undated.bid_deadline(), Some(may(11).and_hms(23, 59, 59)));
+        assert_eq!(undated.listing_period(), Some((String::new(), may(10))));
+
+        let empty = item("Extended", "");
+        assert_eq!(empty.bid_deadline(), None);
+        assert_eq!(empty.listing_period(), None);
+    }
+}
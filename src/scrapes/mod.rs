@@ -1 +1,2 @@
 pub mod hudhomestore;
+pub mod polite;
@@ -0,0 +1,550 @@
+//! Politeness shared by every scraper: requests to a host are spaced out,
+//! robots.txt is honoured, pages seen in the previous run are revalidated
+//! instead of downloaded again and a run never exceeds its request budget.
+
+use crate::http::RetryPolicy;
+use anyhow::{bail, Result};
+use reqwest::header::{HeaderMap, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED};
+use reqwest::{Client, RequestBuilder, Response, StatusCode, Url};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+fn default_requests_per_minute() -> u32 {
+    30
+}
+
+fn default_respect_robots_txt() -> bool {
+    true
+}
+
+fn default_max_requests_per_run() -> u32 {
+    1000
+}
+
+/// Longest robots.txt Crawl-delay honoured, in seconds. Longer ones would
+/// stall a run for good.
+const MAX_CRAWL_DELAY_SECS: f64 = 60.0;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct PolitenessConfig {
+    /// Requests per minute to a single host, 0 for no limit.
+    #[serde(default = "default_requests_per_minute")]
+    pub requests_per_minute: u32,
+    /// `requests_per_minute` overrides by host name.
+    #[serde(default)]
+    pub hosts: HashMap<String, u32>,
+    #[serde(default = "default_respect_robots_txt")]
+    pub respect_robots_txt: bool,
+    /// Requests of a whole run, robots.txt included, 0 for no limit.
+    #[serde(default = "default_max_requests_per_run")]
+    pub max_requests_per_run: u32,
+}
+
+impl Default for PolitenessConfig {
+    fn default() -> Self {
+        PolitenessConfig {
+            requests_per_minute: default_requests_per_minute(),
+            hosts: HashMap::new(),
+            respect_robots_txt: default_respect_robots_txt(),
+            max_requests_per_run: default_max_requests_per_run(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CachedPage {
+    etag: Option<String>,
+    last_modified: Option<String>,
+    body: String,
+}
+
+/// Pages that came with validators, by URL.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct PageCache {
+    pages: HashMap<String, CachedPage>,
+}
+
+/// The robots.txt rules that apply to our user agent.
+#[derive(Debug, Default)]
+pub struct Robots {
+    /// Path patterns, allowed or not.
+    rules: Vec<(bool, String)>,
+    crawl_delay: Option<Duration>,
+}
+
+impl Robots {
+    /// Rules of the groups naming the product token of `user_agent`, e.g.
+    /// `scrappybot` for `scrappybot/0.1.0 (+...)`, or else of the `*` groups.
+    pub fn parse(text: &str, user_agent: &str) -> Self {
+        let product = user_agent
+            .split('/')
+            .next()
+            .unwrap_or("")
+            .trim()
+            .to_lowercase();
+        let mut groups: Vec<(Vec<String>, Robots)> = Vec::new();
+        let mut agents: Vec<String> = Vec::new();
+        let mut current = Robots::default();
+        let mut in_rules = false;
+        for line in text.lines() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            let colon = match line.find(':') {
+                Some(colon) => colon,
+                None => continue,
+            };
+            let key = line[..colon].trim().to_lowercase();
+            let value = line[colon + 1..].trim();
+            match key.as_str() {
+                "user-agent" => {
+                    if in_rules {
+                        groups.push((agents, current));
+                        agents = Vec::new();
+                        current = Robots::default();
+                        in_rules = false;
+                    }
+                    agents.push(value.to_lowercase());
+                }
+                "allow" | "disallow" => {
+                    in_rules = true;
+                    // an empty Disallow allows everything
+                    if !value.is_empty() {
+                        current.rules.push((key == "allow", value.to_string()));
+                    }
+                }
+                "crawl-delay" => {
+                    in_rules = true;
+                    current.crawl_delay = value
+                        .parse::<f64>()
+                        .ok()
+                        .filter(|secs| secs.is_finite() && *secs >= 0.0)
+                        .map(|secs| Duration::from_secs_f64(secs.min(MAX_CRAWL_DELAY_SECS)));
+                }
+                _ => {}
+            }
+        }
+        groups.push((agents, current));
+
+        let named = groups
+            .iter()
+            .any(|(agents, _)| agents.iter().any(|agent| *agent == product));
+        let mut robots = Robots::default();
+        for (agents, group) in groups {
+            let applies = if named {
+                agents.iter().any(|agent| *agent == product)
+            } else {
+                agents.iter().any(|agent| agent == "*")
+            };
+            if applies {
+                robots.rules.extend(group.rules);
+                robots.crawl_delay = robots.crawl_delay.max(group.crawl_delay);
+            }
+        }
+        robots
+    }
+
+    /// The longest matching rule wins, Allow on a tie.
+    pub fn allows(&self, path: &str) -> bool {
+        let mut verdict = (0, true);
+        for (allow, pattern) in self.rules.iter() {
+            if !rule_matches(pattern, path) {
+                continue;
+            }
+            let length = pattern.len();
+            if length > verdict.0 || (length == verdict.0 && *allow) {
+                verdict = (length, *allow);
+            }
+        }
+        verdict.1
+    }
+}
+
+/// Prefix match where `*` stands for any characters and a trailing `$`
+/// anchors the end.
+fn rule_matches(pattern: &str, path: &str) -> bool {
+    let (pattern, anchored) = match pattern.strip_suffix('$') {
+        Some(pattern) => (pattern, true),
+        None => (pattern, false),
+    };
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or("");
+    if !path.starts_with(first) {
+        return false;
+    }
+    let mut rest = &path[first.len()..];
+    let parts: Vec<&str> = parts.collect();
+    for (i, part) in parts.iter().enumerate() {
+        if anchored && i == parts.len() - 1 {
+            return rest.ends_with(part);
+        }
+        match rest.find(part) {
+            Some(at) => rest = &rest[at + part.len()..],
+            None => return false,
+        }
+    }
+    !anchored || rest.is_empty()
+}
+
+fn path_and_query(url: &Url) -> String {
+    match url.query() {
+        Some(query) => format!("{}?{}", url.path(), query),
+        None => url.path().to_string(),
+    }
+}
+
+fn header(headers: &HeaderMap, name: reqwest::header::HeaderName) -> Option<String> {
+    headers
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string)
+}
+
+/// Client every scraper fetches through, one per run so the budget and the
+/// revalidated pages are those of the run.
+pub struct PoliteClient {
+    client: Client,
+    retry: RetryPolicy,
+    config: PolitenessConfig,
+    user_agent: String,
+    robots: tokio::sync::Mutex<HashMap<String, Arc<Robots>>>,
+    next_request: Mutex<HashMap<String, Instant>>,
+    sent: Mutex<u32>,
+    /// Pages of the previous run.
+    cache: PageCache,
+    /// Pages of this run, saved for the next one.
+    fetched: Mutex<PageCache>,
+}
+
+impl PoliteClient {
+    pub fn new(
+        client: Client,
+        retry: RetryPolicy,
+        config: &PolitenessConfig,
+        user_agent: &str,
+    ) -> Self {
+        PoliteClient {
+            client: client,
+            retry: retry,
+            config: config.clone(),
+            user_agent: user_agent.to_string(),
+            robots: tokio::sync::Mutex::new(HashMap::new()),
+            next_request: Mutex::new(HashMap::new()),
+            sent: Mutex::new(0),
+            cache: PageCache::default(),
+            fetched: Mutex::new(PageCache::default()),
+        }
+    }
+
+    /// Revalidates the pages in `cache` with conditional requests.
+    pub fn with_cache(mut self, cache: PageCache) -> Self {
+        self.cache = cache;
+        self
+    }
+
+    /// Pages fetched so far that can be revalidated in the next run.
+    pub fn take_cache(&self) -> PageCache {
+        std::mem::take(&mut *self.fetched.lock().unwrap())
+    }
+
+    pub fn budget_exhausted(&self) -> bool {
+        let max = self.config.max_requests_per_run;
+        max > 0 && *self.sent.lock().unwrap() >= max
+    }
+
+    /// Page body, the cached one when the server says it didn't change.
+    pub async fn get_text(&self, url: &Url) -> Result<String> {
+        let robots = self.allowed(url).await?;
+        let cached = self.cache.pages.get(url.as_str());
+        let response = self
+            .send(url, &robots, || {
+                let mut request = self.client.get(url.clone());
+                if let Some(etag) = cached.and_then(|page| page.etag.as_ref()) {
+                    request = request.header(IF_NONE_MATCH, etag.as_str());
+                }
+                if let Some(modified) = cached.and_then(|page| page.last_modified.as_ref()) {
+                    request = request.header(IF_MODIFIED_SINCE, modified.as_str());
+                }
+                request
+            })
+            .await?;
+
+        let page = match (response.status(), cached) {
+            (StatusCode::NOT_MODIFIED, Some(cached)) => cached.clone(),
+            _ => {
+                let response = response.error_for_status()?;
+                let etag = header(response.headers(), ETAG);
+                let last_modified = header(response.headers(), LAST_MODIFIED);
+                let body = response.text().await?;
+                if etag.is_none() && last_modified.is_none() {
+                    return Ok(body);
+                }
+                CachedPage {
+                    etag: etag,
+                    last_modified: last_modified,
+                    body: body,
+                }
+            }
+        };
+        let body = page.body.clone();
+        self.fetched
+            .lock()
+            .unwrap()
+            .pages
+            .insert(url.to_string(), page);
+        Ok(body)
+    }
+
+    /// Binary content such as photos, which is never cached.
+    pub async fn get_bytes(&self, url: &Url) -> Result<Vec<u8>> {
+        let robots = self.allowed(url).await?;
+        let data = self
+            .send(url, &robots, || self.client.get(url.clone()))
+            .await?
+            .error_for_status()?
+            .bytes()
+            .await?;
+        Ok(data.to_vec())
+    }
+
+    /// Rules of the host of `url`, failing if they disallow it.
+    async fn allowed(&self, url: &Url) -> Result<Arc<Robots>> {
+        let robots = self.robots(url).await?;
+        if !robots.allows(&path_and_query(url)) {
+            bail!("{} is disallowed by robots.txt", url);
+        }
+        Ok(robots)
+    }
+
+    async fn robots(&self, url: &Url) -> Result<Arc<Robots>> {
+        if !self.config.respect_robots_txt {
+            return Ok(Arc::new(Robots::default()));
+        }
+        let origin = url.origin().ascii_serialization();
+        // held while fetching, so robots.txt is requested once per host
+        let mut known = self.robots.lock().await;
+        if let Some(robots) = known.get(&origin) {
+            return Ok(robots.clone());
+        }
+
+        let robots_url = url.join("/robots.txt")?;
+        let response = self
+            .send(&robots_url, &Robots::default(), || {
+                self.client.get(robots_url.clone())
+            })
+            .await?;
+        let status = response.status();
+        let robots = if status.is_success() {
+            Robots::parse(&response.text().await?, &self.user_agent)
+        } else if status.is_client_error() {
+            // no robots.txt, everything is allowed
+            Robots::default()
+        } else {
+            bail!("robots.txt of {} is unavailable: {}", origin, status);
+        };
+        let robots = Arc::new(robots);
+        known.insert(origin, robots.clone());
+        Ok(robots)
+    }
+
+    /// Sends once the host's turn comes and the budget allows it, every
+    /// retry the same.
+    async fn send<F>(&self, url: &Url, robots: &Robots, request: F) -> Result<Response>
+    where
+        F: Fn() -> RequestBuilder,
+    {
+        self.retry
+            .send_paced(request, || self.take_turn(url, robots))
+            .await
+    }
+
+    /// Counts a request against the budget and waits for the host's turn.
+    async fn take_turn(&self, url: &Url, robots: &Robots) -> Result<()> {
+        {
+            let mut sent = self.sent.lock().unwrap();
+            let max = self.config.max_requests_per_run;
+            if max > 0 && *sent >= max {
+                bail!("request budget of {} per run is used up", max);
+            }
+            *sent += 1;
+        }
+        let delay = self.reserve_turn(url, robots);
+        if delay > Duration::from_secs(0) {
+            tokio::time::delay_for(delay).await;
+        }
+        Ok(())
+    }
+
+    /// Books the next free slot of the host and returns how long to wait
+    /// for it.
+    fn reserve_turn(&self, url: &Url, robots: &Robots) -> Duration {
+        let host = url.host_str().unwrap_or("").to_string();
+        let per_minute = self
+            .config
+            .hosts
+            .get(&host)
+            .cloned()
+            .unwrap_or(self.config.requests_per_minute);
+        let mut interval = if per_minute > 0 {
+            Duration::from_secs(60) / per_minute
+        } else {
+            Duration::from_secs(0)
+        };
+        if let Some(crawl_delay) = robots.crawl_delay {
+            interval = interval.max(crawl_delay);
+        }
+
+        let now = Instant::now();
+        let mut next_request = self.next_request.lock().unwrap();
+        let turn = next_request
+            .get(&host)
+            .cloned()
+            .filter(|at| *at > now)
+            .unwrap_or(now);
+        next_request.insert(host, turn + interval);
+        turn - now
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::api::test_support::FakeSite;
+    use crate::http::HttpConfig;
+
+    fn config(requests_per_minute: u32, max_requests_per_run: u32) -> PolitenessConfig {
+        PolitenessConfig {
+            requests_per_minute: requests_per_minute,
+            max_requests_per_run: max_requests_per_run,
+            ..PolitenessConfig::default()
+        }
+    }
+
+    fn polite(config: &PolitenessConfig) -> PoliteClient {
+        PoliteClient::new(
+            Client::new(),
+            RetryPolicy::default().with_max_retries(0),
+            config,
+            "scrappybot/1.0",
+        )
+    }
+
+    #[test]
+    fn test_robots_rules() {
+        let robots = Robots::parse(
+            "User-agent: *\n\
+             Disallow: /\n\
+             \n\
+             User-agent: Googlebot\n\
+             User-agent: scrappybot\n\
+             Disallow: /Listing/ # no listings\n\
+             Allow: /Listing/PropertySearchResult.aspx\n\
+             Disallow: /*.pdf$\n\
+             Crawl-delay: 2\n",
+            "scrappybot/1.0",
+        );
+        assert!(robots.allows("/"));
+        assert!(!robots.allows("/Listing/PropertyDetails.aspx?caseNumber=1"));
+        assert!(robots.allows("/Listing/PropertySearchResult.aspx?pageId=1"));
+        assert!(!robots.allows("/docs/addendum.pdf"));
+        assert!(robots.allows("/docs/addendum.pdf?page=2"));
+        assert_eq!(robots.crawl_delay, Some(Duration::from_secs(2)));
+
+        let others = Robots::parse("User-agent: *\nDisallow: /\n", "curl/7.54.0");
+        assert!(!others.allows("/Listing/"));
+        assert!(Robots::parse("User-agent: *\nDisallow:\n", "curl/7.54.0").allows("/"));
+    }
+
+    #[test]
+    fn test_crawl_delay_is_finite_and_capped() {
+        let crawl_delay = |value: &str| {
+            Robots::parse(
+                &format!("User-agent: *\nCrawl-delay: {}\n", value),
+                "scrappybot",
+            )
+            .crawl_delay
+        };
+        assert_eq!(crawl_delay("0.5"), Some(Duration::from_millis(500)));
+        assert_eq!(crawl_delay("86400"), Some(Duration::from_secs(60)));
+        assert_eq!(crawl_delay("1e30"), Some(Duration::from_secs(60)));
+        assert_eq!(crawl_delay("inf"), None);
+        assert_eq!(crawl_delay("NaN"), None);
+        assert_eq!(crawl_delay("-1"), None);
+    }
+
+    #[tokio::test]
+    async fn test_unchanged_pages_are_revalidated() {
+        let site = FakeSite::start(None).await;
+        site.put_page("/search", "<table></table>", "\"v1\"");
+        let url = site.url("/search");
+
+        let first = polite(&config(0, 0));
+        assert_eq!(first.get_text(&url).await.unwrap(), "<table></table>");
+        let cache = first.take_cache();
+        assert_eq!(cache.pages[url.as_str()].etag.as_deref(), Some("\"v1\""));
+
+        let second = polite(&config(0, 0)).with_cache(cache);
+        assert_eq!(second.get_text(&url).await.unwrap(), "<table></table>");
+        assert_eq!(second.take_cache().pages.len(), 1);
+        assert_eq!(
+            site.requests(),
+            vec!["/robots.txt", "/search", "/robots.txt", "/search"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_robots_txt_is_honoured() {
+        let site = FakeSite::start(Some("User-agent: *\nDisallow: /private")).await;
+        site.put_page("/public", "public", "\"p\"");
+        site.put_page("/private", "private", "\"s\"");
+        let client = polite(&config(0, 0));
+
+        assert_eq!(
+            client.get_text(&site.url("/public")).await.unwrap(),
+            "public"
+        );
+        assert!(client.get_text(&site.url("/private")).await.is_err());
+        assert_eq!(site.requests(), vec!["/robots.txt", "/public"]);
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit_and_budget() {
+        let site = FakeSite::start(None).await;
+        site.put_page("/page", "page", "\"p\"");
+        let client = polite(&config(600, 3));
+
+        let started = Instant::now();
+        client.get_text(&site.url("/page")).await.unwrap();
+        client.get_text(&site.url("/page")).await.unwrap();
+        // robots.txt and two pages, 100ms apart
+        assert!(started.elapsed() >= Duration::from_millis(200));
+        assert!(client.budget_exhausted());
+        assert!(client.get_text(&site.url("/page")).await.is_err());
+        assert_eq!(site.requests().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_retries_wait_their_turn_and_count() {
+        let site = FakeSite::start(None).await;
+        site.put_page("/page", "page", "\"p\"");
+        let client = PoliteClient::new(
+            Client::new(),
+            RetryPolicy::from_config(&HttpConfig {
+                base_delay_ms: 1,
+                ..HttpConfig::default()
+            }),
+            &config(600, 4),
+            "scrappybot/1.0",
+        );
+
+        let started = Instant::now();
+        client.get_text(&site.url("/page")).await.unwrap();
+        site.fail_next(StatusCode::SERVICE_UNAVAILABLE);
+        client.get_text(&site.url("/page")).await.unwrap();
+        // robots.txt, the page and both attempts of it again, 100ms apart
+        assert!(started.elapsed() >= Duration::from_millis(300));
+        // and both count against the budget
+        assert!(client.budget_exhausted());
+        assert_eq!(site.requests().len(), 4);
+    }
+}
@@ -1,20 +1,89 @@
+use anyhow::{bail, Result};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
 use std::time::{SystemTime, UNIX_EPOCH};
 
+/// Version of the snapshot layout written by this build.
+pub const SNAPSHOT_VERSION: u64 = 2;
+
+/// `MIGRATIONS[i]` upgrades a snapshot of version `i + 1` to `i + 2`.
+/// Snapshots written before versioning have no `version` and count as 1.
+const MIGRATIONS: &[fn(Value) -> Result<Value>] = &[v1_to_v2];
+
+fn v1_to_v2(mut value: Value) -> Result<Value> {
+    match value.as_object_mut() {
+        Some(object) => {
+            object.insert("version".to_string(), Value::from(2));
+            Ok(value)
+        }
+        None => bail!("snapshot v1 must be a json object"),
+    }
+}
+
+fn migrate(mut value: Value) -> Result<Value> {
+    let mut version = value.get("version").and_then(Value::as_u64).unwrap_or(1);
+    if version == 0 || version > SNAPSHOT_VERSION {
+        bail!(
+            "snapshot version {} is not supported, latest known is {}",
+            version,
+            SNAPSHOT_VERSION
+        );
+    }
+    while version < SNAPSHOT_VERSION {
+        value = MIGRATIONS[(version - 1) as usize](value)?;
+        version += 1;
+    }
+    Ok(value)
+}
+
 pub trait IdChecksum {
     fn id_checksum(&self) -> (String, u64);
 }
 
+impl IdChecksum for (String, u64) {
+    fn id_checksum(&self) -> (String, u64) {
+        self.clone()
+    }
+}
+
+/// Stored snapshots of any version are migrated to the latest layout when
+/// deserialized.
 #[derive(Serialize, Deserialize)]
+#[serde(try_from = "Value")]
 pub struct Snapshot {
+    pub version: u64,
     pub update_timestamp: u64,
     pub state: HashMap<String, u64>,
 }
 
+/// Latest layout, without the migrating `try_from`.
+#[derive(Deserialize)]
+struct LatestSnapshot {
+    version: u64,
+    update_timestamp: u64,
+    state: HashMap<String, u64>,
+}
+
+impl TryFrom<Value> for Snapshot {
+    type Error = anyhow::Error;
+
+    fn try_from(value: Value) -> Result<Self> {
+        let latest: LatestSnapshot = serde_json::from_value(migrate(value)?)?;
+        Ok(Snapshot {
+            version: latest.version,
+            update_timestamp: latest.update_timestamp,
+            state: latest.state,
+        })
+    }
+}
+
 pub struct Diff<T> {
     pub added: Vec<T>,
     pub changed: Vec<T>,
+    /// Ids present in the snapshot but missing from the latest scrape.
+    pub removed: Vec<String>,
 }
 
 impl Snapshot {
@@ -25,6 +94,7 @@ impl Snapshot {
             map.insert(id, checksum);
         }
         Snapshot {
+            version: SNAPSHOT_VERSION,
             update_timestamp: SystemTime::now()
                 .duration_since(UNIX_EPOCH)
                 .expect("System time should be after UNIX EPOCH")
@@ -36,8 +106,10 @@ impl Snapshot {
     pub fn diff<T: IdChecksum>(&self, items: Vec<T>) -> Diff<T> {
         let mut changed: Vec<T> = Vec::new();
         let mut added: Vec<T> = Vec::new();
+        let mut seen = HashSet::new();
         for item in items {
             let (id, checksum) = item.id_checksum();
+            seen.insert(id.clone());
             match self.state.get(&id) {
                 Some(oldchecksum) if *oldchecksum != checksum => changed.push(item),
                 None => added.push(item),
@@ -45,9 +117,75 @@ impl Snapshot {
             }
         }
 
+        let mut removed: Vec<String> = self
+            .state
+            .keys()
+            .filter(|id| !seen.contains(*id))
+            .cloned()
+            .collect();
+        removed.sort();
+
         Diff {
             changed: changed,
             added: added,
+            removed: removed,
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const FIXTURES: &[&str] = &[
+        include_str!("../tests/fixtures/snapshot_v1.json"),
+        include_str!("../tests/fixtures/snapshot_v2.json"),
+    ];
+
+    #[test]
+    fn test_fixture_for_every_version() {
+        assert_eq!(FIXTURES.len() as u64, SNAPSHOT_VERSION);
+        assert_eq!(MIGRATIONS.len() as u64, SNAPSHOT_VERSION - 1);
+    }
+
+    #[test]
+    fn test_load_every_version() {
+        for fixture in FIXTURES.iter() {
+            let snapshot: Snapshot = serde_json::from_str(fixture).unwrap();
+            assert_eq!(snapshot.version, SNAPSHOT_VERSION);
+            assert_eq!(snapshot.state.get("101-123456"), Some(&1234567890123));
+        }
+    }
+
+    #[test]
+    fn test_roundtrip_keeps_latest_version() {
+        let snapshot = Snapshot::new(vec![("a".to_string(), 1)]);
+        let loaded: Snapshot =
+            serde_json::from_str(&serde_json::to_string(&snapshot).unwrap()).unwrap();
+        assert_eq!(loaded.version, SNAPSHOT_VERSION);
+        assert_eq!(loaded.state, snapshot.state);
+    }
+
+    #[test]
+    fn test_unknown_version_fails() {
+        let result =
+            serde_json::from_str::<Snapshot>(r#"{"version":99,"update_timestamp":0,"state":{}}"#);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_diff() {
+        let snapshot = Snapshot::new(vec![("a".to_string(), 1), ("b".to_string(), 2)]);
+        let diff = snapshot.diff(vec![
+            ("a".to_string(), 1),
+            ("b".to_string(), 3),
+            ("c".to_string(), 4),
+        ]);
+        assert_eq!(diff.changed, vec![("b".to_string(), 3)]);
+        assert_eq!(diff.added, vec![("c".to_string(), 4)]);
+        assert!(diff.removed.is_empty());
+
+        let diff = snapshot.diff(vec![("b".to_string(), 2)]);
+        assert_eq!(diff.removed, vec!["a".to_string()]);
+    }
+}
@@ -0,0 +1,305 @@
+use super::BlobStorage;
+use anyhow::{anyhow, bail, Context, Result};
+use async_trait::async_trait;
+use chacha20poly1305::aead::generic_array::GenericArray;
+use chacha20poly1305::aead::{Aead, NewAead, Payload};
+use chacha20poly1305::ChaCha20Poly1305;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use rand::RngCore;
+use serde::Deserialize;
+use std::io::prelude::*;
+
+/// Encoded blobs start with `MAGIC`, a format version, the compression and
+/// encryption used, and a nonce if encrypted. Blobs without the header are
+/// plain JSON written before the codec existed and are read as is. The
+/// header of encrypted blobs is authenticated along with the body, so it
+/// can't be altered to make a blob decode differently.
+const MAGIC: &[u8] = b"SBOT";
+const FORMAT_VERSION: u8 = 2;
+/// Version 1 encrypted the body alone, its blobs are still read.
+const UNAUTHENTICATED_HEADER_VERSION: u8 = 1;
+const NONCE_LEN: usize = 12;
+const KEY_LEN: usize = 32;
+const ZSTD_LEVEL: i32 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Compression::None
+    }
+}
+
+impl Compression {
+    fn tag(self) -> u8 {
+        match self {
+            Compression::None => 0,
+            Compression::Gzip => 1,
+            Compression::Zstd => 2,
+        }
+    }
+
+    fn from_tag(tag: u8) -> Result<Self> {
+        match tag {
+            0 => Ok(Compression::None),
+            1 => Ok(Compression::Gzip),
+            2 => Ok(Compression::Zstd),
+            other => bail!("unknown compression tag {}", other),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct CodecConfig {
+    #[serde(default)]
+    pub compression: Compression,
+    /// Hex-encoded 32 byte key. Blobs are encrypted with ChaCha20-Poly1305
+    /// when it's set. Only blobs are, the tables of the SQLite backend
+    /// would stay readable, so it's refused together with `storage: sqlite`.
+    #[serde(default)]
+    pub encryption_key: Option<String>,
+}
+
+pub struct Codec {
+    compression: Compression,
+    cipher: Option<ChaCha20Poly1305>,
+}
+
+impl Codec {
+    pub fn new(config: &CodecConfig) -> Result<Self> {
+        let cipher = match &config.encryption_key {
+            Some(key) => {
+                let key = hex::decode(key.trim()).context("encryption key must be hex-encoded")?;
+                if key.len() != KEY_LEN {
+                    bail!("encryption key must be {} bytes, got {}", KEY_LEN, key.len());
+                }
+                Some(ChaCha20Poly1305::new(GenericArray::from_slice(&key)))
+            }
+            None => None,
+        };
+        Ok(Codec {
+            compression: config.compression,
+            cipher: cipher,
+        })
+    }
+
+    pub fn encode(&self, data: Vec<u8>) -> Result<Vec<u8>> {
+        if self.compression == Compression::None && self.cipher.is_none() {
+            return Ok(data);
+        }
+
+        let compressed = match self.compression {
+            Compression::None => data,
+            Compression::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(&data)?;
+                encoder.finish()?
+            }
+            Compression::Zstd => zstd::encode_all(&data[..], ZSTD_LEVEL)?,
+        };
+
+        let mut encoded = MAGIC.to_vec();
+        encoded.push(FORMAT_VERSION);
+        encoded.push(self.compression.tag());
+        match &self.cipher {
+            Some(cipher) => {
+                let mut nonce = [0u8; NONCE_LEN];
+                rand::thread_rng().fill_bytes(&mut nonce);
+                encoded.push(1);
+                encoded.extend_from_slice(&nonce);
+                let payload = Payload {
+                    msg: &compressed[..],
+                    aad: &encoded[..],
+                };
+                let encrypted = cipher
+                    .encrypt(GenericArray::from_slice(&nonce), payload)
+                    .map_err(|_| anyhow!("failed to encrypt blob"))?;
+                encoded.extend(encrypted);
+            }
+            None => {
+                encoded.push(0);
+                encoded.extend(compressed);
+            }
+        }
+        Ok(encoded)
+    }
+
+    pub fn decode(&self, data: Vec<u8>) -> Result<Vec<u8>> {
+        if !data.starts_with(MAGIC) {
+            return Ok(data);
+        }
+        let header = &data[MAGIC.len()..];
+        if header.len() < 3 {
+            bail!("truncated blob header");
+        }
+        let version = header[0];
+        if version != FORMAT_VERSION && version != UNAUTHENTICATED_HEADER_VERSION {
+            bail!("unsupported blob format version {}", version);
+        }
+        let compression = Compression::from_tag(header[1])?;
+        let body = &header[3..];
+
+        let compressed = match header[2] {
+            0 => body.to_vec(),
+            1 => {
+                let cipher = self
+                    .cipher
+                    .as_ref()
+                    .context("blob is encrypted but no encryption key is configured")?;
+                if body.len() < NONCE_LEN {
+                    bail!("truncated blob nonce");
+                }
+                let (nonce, encrypted) = body.split_at(NONCE_LEN);
+                let payload = Payload {
+                    msg: encrypted,
+                    aad: match version {
+                        UNAUTHENTICATED_HEADER_VERSION => &[],
+                        _ => &data[..data.len() - encrypted.len()],
+                    },
+                };
+                cipher
+                    .decrypt(GenericArray::from_slice(nonce), payload)
+                    .map_err(|_| anyhow!("failed to decrypt blob, wrong key or corrupted data"))?
+            }
+            other => bail!("unknown encryption tag {}", other),
+        };
+
+        let decoded = match compression {
+            Compression::None => compressed,
+            Compression::Gzip => {
+                let mut decoded = Vec::new();
+                GzDecoder::new(&compressed[..]).read_to_end(&mut decoded)?;
+                decoded
+            }
+            Compression::Zstd => zstd::decode_all(&compressed[..])?,
+        };
+        Ok(decoded)
+    }
+}
+
+/// Compresses and encrypts blobs of the wrapped storage.
+pub struct CodecStorage {
+    inner: Box<dyn BlobStorage>,
+    codec: Codec,
+}
+
+impl CodecStorage {
+    pub fn new(inner: Box<dyn BlobStorage>, codec: Codec) -> Self {
+        CodecStorage {
+            inner: inner,
+            codec: codec,
+        }
+    }
+}
+
+#[async_trait]
+impl BlobStorage for CodecStorage {
+    async fn list(&self) -> Result<Vec<String>> {
+        self.inner.list().await
+    }
+
+    async fn read(&self, name: &str) -> Result<Vec<u8>> {
+        let data = self.inner.read(name).await?;
+        self.codec
+            .decode(data)
+            .with_context(|| format!("failed to decode {}", name))
+    }
+
+    async fn write_next(&self, data: Vec<u8>) -> Result<String> {
+        let encoded = self.codec.encode(data)?;
+        self.inner.write_next(encoded).await
+    }
+
+    async fn remove(&self, name: &str) -> Result<()> {
+        self.inner.remove(name).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const KEY: &'static str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+
+    fn codec(compression: Compression, key: Option<&str>) -> Codec {
+        Codec::new(&CodecConfig {
+            compression: compression,
+            encryption_key: key.map(|k| k.to_string()),
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let data = br#"{"update_timestamp":1,"state":{"a":1}}"#.repeat(10);
+        for compression in [Compression::None, Compression::Gzip, Compression::Zstd].iter() {
+            for key in [None, Some(KEY)].iter() {
+                let codec = codec(*compression, *key);
+                let encoded = codec.encode(data.clone()).unwrap();
+                assert_eq!(codec.decode(encoded).unwrap(), data);
+            }
+        }
+    }
+
+    #[test]
+    fn test_plain_json_is_read_as_is() {
+        let data = br#"{"update_timestamp":1,"state":{}}"#.to_vec();
+        assert_eq!(codec(Compression::None, None).encode(data.clone()).unwrap(), data);
+        assert_eq!(codec(Compression::Zstd, Some(KEY)).decode(data.clone()).unwrap(), data);
+    }
+
+    #[test]
+    fn test_encrypted_needs_the_right_key() {
+        let encoded = codec(Compression::Gzip, Some(KEY))
+            .encode(b"secret".to_vec())
+            .unwrap();
+        assert!(codec(Compression::Gzip, None).decode(encoded.clone()).is_err());
+
+        let other_key = KEY.replace("00", "ff");
+        assert!(codec(Compression::Gzip, Some(&other_key))
+            .decode(encoded.clone())
+            .is_err());
+
+        let mut tampered = encoded;
+        let last = tampered.len() - 1;
+        tampered[last] ^= 1;
+        assert!(codec(Compression::Gzip, Some(KEY)).decode(tampered).is_err());
+    }
+
+    #[test]
+    fn test_encrypted_header_is_authenticated() {
+        let encoded = codec(Compression::Gzip, Some(KEY))
+            .encode(b"secret".to_vec())
+            .unwrap();
+
+        // claims the body isn't compressed
+        let mut tampered = encoded;
+        tampered[MAGIC.len() + 1] = Compression::None.tag();
+        assert!(codec(Compression::Gzip, Some(KEY)).decode(tampered).is_err());
+    }
+
+    #[test]
+    fn test_version_1_blobs_are_still_read() {
+        let codec = codec(Compression::None, Some(KEY));
+        let nonce = [7u8; NONCE_LEN];
+        let encrypted = codec
+            .cipher
+            .as_ref()
+            .unwrap()
+            .encrypt(GenericArray::from_slice(&nonce), &b"secret"[..])
+            .unwrap();
+        let mut blob = MAGIC.to_vec();
+        blob.extend_from_slice(&[UNAUTHENTICATED_HEADER_VERSION, Compression::None.tag(), 1]);
+        blob.extend_from_slice(&nonce);
+        blob.extend(encrypted);
+
+        assert_eq!(codec.decode(blob).unwrap(), b"secret");
+    }
+}
@@ -0,0 +1,175 @@
+use super::{BlobStorage, NamingSchema};
+use anyhow::{bail, Context, Result};
+use async_trait::async_trait;
+use std::fs::{self, File, OpenOptions};
+use std::io::prelude::*;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Lock files older than that are left over from crashed runs.
+const STALE_LOCK: Duration = Duration::from_secs(10 * 60);
+
+pub struct FsSystem {
+    folder: PathBuf,
+    schema: NamingSchema,
+    files_prefix: String,
+}
+
+impl FsSystem {
+    pub fn new(folder: String, files_prefix: String) -> Self {
+        FsSystem {
+            folder: PathBuf::from(folder),
+            schema: NamingSchema::new(files_prefix.clone()),
+            files_prefix: files_prefix,
+        }
+    }
+
+    fn path(&self, name: &str) -> PathBuf {
+        self.folder.join(name)
+    }
+
+    /// Nothing while the folder is missing, it's created by the first write.
+    fn list_files(&self) -> Result<Vec<String>> {
+        let mut result = Vec::new();
+        if !self.folder.exists() {
+            return Ok(result);
+        }
+        for entry in fs::read_dir(&self.folder)
+            .with_context(|| format!("failed to read files from {}", self.folder.display()))?
+        {
+            result.push(entry?.file_name().to_string_lossy().to_string());
+        }
+        Ok(result)
+    }
+
+    /// Holds `.{prefix}.lock` in the folder, so concurrent runs can't pick
+    /// the same sequence number.
+    fn lock(&self) -> Result<LockFile> {
+        let path = self.path(&format!(".{}.lock", self.files_prefix));
+        for _ in 0..2 {
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(mut file) => {
+                    write!(file, "{}", std::process::id())?;
+                    return Ok(LockFile { path: path });
+                }
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+                    let age = fs::metadata(&path)?.modified()?.elapsed().unwrap_or_default();
+                    if age < STALE_LOCK {
+                        bail!("{} is held by another run", path.display());
+                    }
+                    fs::remove_file(&path)?;
+                }
+                Err(e) => return Err(e).with_context(|| format!("failed to create {}", path.display())),
+            }
+        }
+        bail!("failed to acquire {}", path.display())
+    }
+}
+
+struct LockFile {
+    path: PathBuf,
+}
+
+impl Drop for LockFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+#[async_trait]
+impl BlobStorage for FsSystem {
+    async fn list(&self) -> Result<Vec<String>> {
+        Ok(self.schema.sorted(&self.list_files()?))
+    }
+
+    async fn read(&self, name: &str) -> Result<Vec<u8>> {
+        let mut file =
+            File::open(self.path(name)).with_context(|| format!("failed to open {}", name))?;
+        let mut contents = Vec::new();
+        file.read_to_end(&mut contents)?;
+        Ok(contents)
+    }
+
+    /// Writes into a temp file renamed into place once complete, so a
+    /// crash never leaves a truncated latest blob behind.
+    async fn write_next(&self, data: Vec<u8>) -> Result<String> {
+        fs::create_dir_all(&self.folder)
+            .with_context(|| format!("failed to create {}", self.folder.display()))?;
+        let _lock = self.lock()?;
+        let name = self.schema.next(&self.list_files()?);
+
+        let temp = self.path(&format!(".{}.tmp", name));
+        let mut file =
+            File::create(&temp).with_context(|| format!("failed to create {}", temp.display()))?;
+        file.write_all(&data)?;
+        file.sync_all()?;
+        fs::rename(&temp, self.path(&name))
+            .with_context(|| format!("failed to rename {} to {}", temp.display(), name))?;
+
+        Ok(name)
+    }
+
+    async fn remove(&self, name: &str) -> Result<()> {
+        fs::remove_file(self.path(name)).with_context(|| format!("failed to remove {}", name))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn temp_folder(name: &str) -> String {
+        let folder = std::env::temp_dir().join(format!("scrappybot_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&folder);
+        fs::create_dir_all(&folder).unwrap();
+        folder.display().to_string()
+    }
+
+    #[test]
+    fn test_write_next_and_list() {
+        let folder = temp_folder("fs_write");
+        let snapshots = FsSystem::new(folder.clone(), "estate_snapshot".to_string());
+        let runs = FsSystem::new(folder.clone(), "runs".to_string());
+
+        tokio_test::block_on(async {
+            assert_eq!(snapshots.write_next(b"a".to_vec()).await.unwrap(), "estate_snapshot_0");
+            assert_eq!(snapshots.write_next(b"b".to_vec()).await.unwrap(), "estate_snapshot_1");
+            assert_eq!(runs.write_next(b"c".to_vec()).await.unwrap(), "runs_0");
+
+            assert_eq!(
+                snapshots.list().await.unwrap(),
+                vec!["estate_snapshot_0", "estate_snapshot_1"]
+            );
+            assert_eq!(snapshots.read("estate_snapshot_1").await.unwrap(), b"b".to_vec());
+        });
+        fs::remove_dir_all(folder).unwrap();
+    }
+
+    #[test]
+    fn test_lock_is_exclusive() {
+        let folder = temp_folder("fs_lock");
+        let storage = FsSystem::new(folder.clone(), "estate_snapshot".to_string());
+
+        let lock = storage.lock().unwrap();
+        assert!(storage.lock().is_err());
+        assert!(tokio_test::block_on(storage.write_next(b"a".to_vec())).is_err());
+        drop(lock);
+        assert!(tokio_test::block_on(storage.write_next(b"a".to_vec())).is_ok());
+        fs::remove_dir_all(folder).unwrap();
+    }
+
+    #[test]
+    fn test_folder_is_created_by_the_first_write() {
+        let folder = PathBuf::from(temp_folder("fs_missing")).join("snapshots");
+        let storage = FsSystem::new(folder.display().to_string(), "runs".to_string());
+
+        tokio_test::block_on(async {
+            assert!(storage.list().await.unwrap().is_empty());
+            assert!(!folder.exists());
+            assert_eq!(storage.write_next(b"a".to_vec()).await.unwrap(), "runs_0");
+        });
+        fs::remove_dir_all(folder.parent().unwrap()).unwrap();
+    }
+}
@@ -0,0 +1,252 @@
+use crate::api::yandex_disk_api::{DiskClient, ResourceExists, ResourceNotFound};
+use anyhow::Context;
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+pub mod codec;
+pub mod fs;
+pub mod s3;
+pub mod sqlite;
+
+pub use codec::{Codec, CodecConfig, CodecStorage};
+pub use fs::FsSystem;
+pub use s3::{S3Config, S3Storage};
+pub use sqlite::{SqliteDatabase, SqliteStorage};
+
+/// A sequence of blobs, each save creating a new one after the latest.
+#[async_trait]
+pub trait BlobStorage: Send + Sync {
+    /// Names of stored blobs ordered from the oldest to the latest.
+    async fn list(&self) -> Result<Vec<String>>;
+    async fn read(&self, name: &str) -> Result<Vec<u8>>;
+    /// Stores `data` as the new latest blob and returns its name.
+    async fn write_next(&self, data: Vec<u8>) -> Result<String>;
+    async fn remove(&self, name: &str) -> Result<()>;
+}
+
+impl dyn BlobStorage {
+    pub async fn save<T: Serialize>(&self, data: &T) -> Result<()> {
+        let serialized = serde_json::to_vec(data)?;
+        self.write_next(serialized).await?;
+        Ok(())
+    }
+
+    /// Saves `data` and removes every older blob, for series where only the
+    /// latest state matters.
+    pub async fn replace<T: Serialize>(&self, data: &T) -> Result<()> {
+        let name = self.write_next(serde_json::to_vec(data)?).await?;
+        for outdated in self.list().await?.iter().filter(|n| **n != name) {
+            self.remove(outdated).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn load<T: DeserializeOwned>(&self) -> Result<Option<T>> {
+        match self.list().await?.last() {
+            Some(name) => Ok(Some(self.load_named(name).await?)),
+            None => Ok(None),
+        }
+    }
+
+    pub async fn load_named<T: DeserializeOwned>(&self, name: &str) -> Result<T> {
+        let data = self.read(name).await?;
+        let state = serde_json::from_slice::<T>(data.as_slice())
+            .with_context(|| format!("failed to parse {}", name))?;
+        Ok(state)
+    }
+}
+
+pub struct NamingSchema {
+    prefix: String,
+}
+
+impl NamingSchema {
+    pub fn new(prefix: String) -> Self {
+        NamingSchema { prefix: prefix }
+    }
+
+    /// Sequence number of names like `{prefix}_{number}`, `None` for
+    /// anything else found next to them.
+    fn parse_seq_num(&self, item_name: &str) -> Option<u32> {
+        item_name
+            .strip_prefix(&self.prefix)?
+            .strip_prefix("_")?
+            .parse::<u32>()
+            .ok()
+    }
+
+    fn sorted(&self, existing: &[String]) -> Vec<String> {
+        let mut filtered: Vec<(u32, String)> = existing
+            .iter()
+            .filter_map(|s| self.parse_seq_num(s).map(|seq| (seq, s.clone())))
+            .collect();
+        filtered.sort_by(|a, b| a.0.cmp(&b.0));
+        filtered.into_iter().map(|(_, s)| s).collect()
+    }
+
+    fn get_latest(&self, existing: &[String]) -> Option<String> {
+        self.sorted(existing).pop()
+    }
+
+    fn next(&self, existing: &[String]) -> String {
+        match self.get_latest(existing).and_then(|item| self.parse_seq_num(&item)) {
+            Some(seq) => format!("{}_{}", self.prefix, seq + 1),
+            None => format!("{}_0", self.prefix),
+        }
+    }
+}
+
+/// How many taken names `YandexDiskStorage::write_next` skips before giving
+/// up, each one means another run saved at the same time.
+const MAX_WRITE_CONFLICTS: usize = 5;
+
+pub struct YandexDiskStorage {
+    client: DiskClient,
+    schema: NamingSchema,
+    disk_folder: String,
+}
+
+impl YandexDiskStorage {
+    pub fn new(client: DiskClient, disk_folder: String, files_prefix: String) -> Self {
+        YandexDiskStorage {
+            client: client,
+            schema: NamingSchema::new(files_prefix),
+            disk_folder: disk_folder,
+        }
+    }
+
+    /// `None` while the folder is missing, as on a fresh account.
+    async fn list_files(&self) -> Result<Option<Vec<String>>> {
+        let resources = match self.client.list_all_files(&self.disk_folder).await {
+            Err(e) if e.downcast_ref::<ResourceNotFound>().is_some() => return Ok(None),
+            result => result?,
+        };
+        let list = resources.into_iter().map(|item| item.name).collect();
+
+        Ok(Some(list))
+    }
+}
+
+#[async_trait]
+impl BlobStorage for YandexDiskStorage {
+    async fn list(&self) -> Result<Vec<String>> {
+        let existing = self.list_files().await?.unwrap_or_default();
+        Ok(self.schema.sorted(&existing))
+    }
+
+    async fn read(&self, name: &str) -> Result<Vec<u8>> {
+        self.client
+            .read_file(&format!("{}/{}", self.disk_folder, name))
+            .await
+    }
+
+    /// Uploads without overwriting, so when a concurrent run took the
+    /// same name first this moves on to the following sequence number.
+    /// The folder is created by the first write.
+    async fn write_next(&self, data: Vec<u8>) -> Result<String> {
+        let existing = match self.list_files().await? {
+            Some(existing) => existing,
+            None => {
+                self.client.create_folder(&self.disk_folder).await?;
+                Vec::new()
+            }
+        };
+
+        let mut filename = self.schema.next(&existing);
+        for _ in 0..MAX_WRITE_CONFLICTS {
+            let path = format!("{}/{}", self.disk_folder, filename);
+            match self.client.creat_new_file(&path, data.clone()).await {
+                Ok(()) => return Ok(filename),
+                Err(e) if e.downcast_ref::<ResourceExists>().is_some() => {
+                    filename = self.schema.next(&[filename]);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        bail!(
+            "failed to save into {}, {} names in a row were already taken",
+            self.disk_folder,
+            MAX_WRITE_CONFLICTS
+        )
+    }
+
+    async fn remove(&self, name: &str) -> Result<()> {
+        self.client
+            .delete(&format!("{}/{}", self.disk_folder, name))
+            .await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::api::test_support::FakeDisk;
+
+    #[test]
+    fn test_naming_schema_ignores_foreign_names() {
+        let schema = NamingSchema::new("estate_snapshot".to_string());
+        let existing: Vec<String> = vec![
+            "estate_snapshot_10",
+            "estate_snapshot_9",
+            "estate_snapshot.lock",
+            "estate_snapshot_backup",
+            "other_11",
+        ]
+        .into_iter()
+        .map(|s| s.to_string())
+        .collect();
+
+        assert_eq!(
+            schema.sorted(&existing),
+            vec!["estate_snapshot_9", "estate_snapshot_10"]
+        );
+        assert_eq!(schema.next(&existing), "estate_snapshot_11");
+        assert_eq!(schema.next(&[]), "estate_snapshot_0");
+    }
+
+    #[tokio::test]
+    async fn test_yandex_write_next_skips_names_taken_concurrently() {
+        let server = FakeDisk::start("token").await;
+        server.mkdir("/estatebot");
+        server.put_file("/estatebot/estate_snapshot_0", b"first");
+        server.freeze_listing();
+        // another run saves after this one has listed the folder
+        server.put_file("/estatebot/estate_snapshot_1", b"concurrent");
+
+        let client = DiskClient::new("token".to_string(), reqwest::Client::new())
+            .with_api_url(server.api_url());
+        let storage = YandexDiskStorage::new(
+            client,
+            "/estatebot".to_string(),
+            "estate_snapshot".to_string(),
+        );
+
+        let name = storage.write_next(b"second".to_vec()).await.unwrap();
+        assert_eq!(name, "estate_snapshot_2");
+        assert_eq!(server.file("/estatebot/estate_snapshot_1"), Some(b"concurrent".to_vec()));
+        assert_eq!(server.file("/estatebot/estate_snapshot_2"), Some(b"second".to_vec()));
+    }
+
+    #[tokio::test]
+    async fn test_yandex_storage_creates_its_folder_on_write() {
+        let server = FakeDisk::start("token").await;
+        let client = || {
+            DiskClient::new("token".to_string(), reqwest::Client::new())
+                .with_api_url(server.api_url())
+        };
+        let storage = YandexDiskStorage::new(
+            client(),
+            "/apps/estatebot".to_string(),
+            "estate_snapshot".to_string(),
+        );
+
+        assert!(storage.list().await.unwrap().is_empty());
+        assert!(client().get_meta("/apps/estatebot").await.is_err());
+        assert_eq!(
+            storage.write_next(b"first".to_vec()).await.unwrap(),
+            "estate_snapshot_0"
+        );
+        assert_eq!(storage.list().await.unwrap(), vec!["estate_snapshot_0"]);
+    }
+}
@@ -0,0 +1,261 @@
+use super::{BlobStorage, NamingSchema};
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use rusoto_core::credential::{DefaultCredentialsProvider, StaticProvider};
+use rusoto_core::{HttpClient, Region};
+use rusoto_s3::{
+    DeleteObjectRequest, GetObjectRequest, ListObjectsV2Request, PutObjectRequest, S3Client, S3,
+};
+use serde::Deserialize;
+use tokio::io::AsyncReadExt;
+
+fn default_region() -> String {
+    "us-east-1".to_string()
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct S3Config {
+    pub bucket: String,
+    /// Key prefix ("folder") for all objects, e.g. `estatebot/`.
+    #[serde(default)]
+    pub prefix: String,
+    /// Name prefix of the snapshots, what `prefix` is to the other backends.
+    #[serde(default = "crate::config::default_snapshot_prefix")]
+    pub snapshot_prefix: String,
+    #[serde(default = "default_region")]
+    pub region: String,
+    /// Custom endpoint for S3-compatible stores, e.g. `http://localhost:9000`
+    /// for MinIO or `http://localhost:4566` for localstack.
+    #[serde(default)]
+    pub endpoint: Option<String>,
+    /// Static credentials. The default AWS provider chain (env, profile,
+    /// instance/Lambda role) is used when these are not set.
+    #[serde(default)]
+    pub access_key: Option<String>,
+    #[serde(default)]
+    pub secret_key: Option<String>,
+}
+
+pub struct S3Storage {
+    client: S3Client,
+    bucket: String,
+    key_prefix: String,
+    schema: NamingSchema,
+}
+
+/// Client for the configured region or custom endpoint.
+pub(crate) fn client(config: &S3Config) -> Result<S3Client> {
+    let region = match &config.endpoint {
+        Some(endpoint) => Region::Custom {
+            name: config.region.clone(),
+            endpoint: endpoint.clone(),
+        },
+        None => config
+            .region
+            .parse::<Region>()
+            .with_context(|| format!("unknown region {}", config.region))?,
+    };
+
+    let http_client = HttpClient::new()?;
+    let client = match (&config.access_key, &config.secret_key) {
+        (Some(access_key), Some(secret_key)) => S3Client::new_with(
+            http_client,
+            StaticProvider::new_minimal(access_key.clone(), secret_key.clone()),
+            region,
+        ),
+        _ => S3Client::new_with(http_client, DefaultCredentialsProvider::new()?, region),
+    };
+    Ok(client)
+}
+
+impl S3Storage {
+    pub fn new(config: &S3Config, files_prefix: String) -> Result<Self> {
+        Ok(S3Storage {
+            client: client(config)?,
+            bucket: config.bucket.clone(),
+            key_prefix: config.prefix.clone(),
+            schema: NamingSchema::new(files_prefix),
+        })
+    }
+
+    fn key(&self, name: &str) -> String {
+        format!("{}{}", self.key_prefix, name)
+    }
+
+    async fn list_names(&self) -> Result<Vec<String>> {
+        let mut names = Vec::new();
+        let mut continuation_token = None;
+        loop {
+            let output = self
+                .client
+                .list_objects_v2(ListObjectsV2Request {
+                    bucket: self.bucket.clone(),
+                    prefix: Some(self.key(&self.schema.prefix)),
+                    continuation_token: continuation_token,
+                    ..Default::default()
+                })
+                .await
+                .with_context(|| format!("failed to list objects in {}", self.bucket))?;
+
+            for object in output.contents.unwrap_or_default() {
+                if let Some(key) = object.key {
+                    names.push(key[self.key_prefix.len()..].to_string());
+                }
+            }
+
+            match output.next_continuation_token {
+                Some(token) => continuation_token = Some(token),
+                None => return Ok(names),
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl BlobStorage for S3Storage {
+    async fn list(&self) -> Result<Vec<String>> {
+        let existing = self.list_names().await?;
+        Ok(self.schema.sorted(&existing))
+    }
+
+    async fn read(&self, name: &str) -> Result<Vec<u8>> {
+        let output = self
+            .client
+            .get_object(GetObjectRequest {
+                bucket: self.bucket.clone(),
+                key: self.key(name),
+                ..Default::default()
+            })
+            .await
+            .with_context(|| format!("failed to get {}", name))?;
+
+        let mut data = Vec::new();
+        if let Some(body) = output.body {
+            body.into_async_read().read_to_end(&mut data).await?;
+        }
+        Ok(data)
+    }
+
+    async fn write_next(&self, data: Vec<u8>) -> Result<String> {
+        let existing = self.list_names().await?;
+
+        let name = self.schema.next(&existing);
+        self.client
+            .put_object(PutObjectRequest {
+                bucket: self.bucket.clone(),
+                key: self.key(&name),
+                body: Some(data.into()),
+                ..Default::default()
+            })
+            .await
+            .with_context(|| format!("failed to put {}", name))?;
+
+        Ok(name)
+    }
+
+    async fn remove(&self, name: &str) -> Result<()> {
+        self.client
+            .delete_object(DeleteObjectRequest {
+                bucket: self.bucket.clone(),
+                key: self.key(name),
+                ..Default::default()
+            })
+            .await
+            .with_context(|| format!("failed to delete {}", name))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::api::test_support::FakeS3;
+
+    #[tokio::test]
+    async fn test_against_fake_s3() {
+        let s3 = FakeS3::start().await;
+        s3.limit_page_size(2);
+        let config = S3Config {
+            bucket: "scrappybot".to_string(),
+            prefix: "estatebot/".to_string(),
+            snapshot_prefix: crate::config::default_snapshot_prefix(),
+            region: default_region(),
+            endpoint: Some(s3.endpoint()),
+            access_key: Some("access".to_string()),
+            secret_key: Some("secret".to_string()),
+        };
+        let snapshots = S3Storage::new(&config, config.snapshot_prefix.clone()).unwrap();
+        let history = S3Storage::new(&config, "history".to_string()).unwrap();
+
+        for data in &["first", "second", "third"] {
+            snapshots
+                .write_next(data.as_bytes().to_vec())
+                .await
+                .unwrap();
+        }
+        assert_eq!(
+            history.write_next(b"history".to_vec()).await.unwrap(),
+            "history_0"
+        );
+        assert_eq!(
+            snapshots.list().await.unwrap(),
+            vec![
+                "hudhome_snapshot_0",
+                "hudhome_snapshot_1",
+                "hudhome_snapshot_2"
+            ]
+        );
+        assert_eq!(
+            snapshots.read("hudhome_snapshot_1").await.unwrap(),
+            b"second".to_vec()
+        );
+        assert_eq!(
+            s3.object("scrappybot", "estatebot/history_0"),
+            Some(b"history".to_vec())
+        );
+
+        snapshots.remove("hudhome_snapshot_0").await.unwrap();
+        assert_eq!(
+            snapshots.write_next(b"fourth".to_vec()).await.unwrap(),
+            "hudhome_snapshot_3"
+        );
+        assert_eq!(
+            snapshots.list().await.unwrap(),
+            vec![
+                "hudhome_snapshot_1",
+                "hudhome_snapshot_2",
+                "hudhome_snapshot_3"
+            ]
+        );
+        assert!(snapshots.read("hudhome_snapshot_0").await.is_err());
+    }
+
+    // Needs a local S3 stand-in with an existing `scrappybot-test` bucket, e.g.
+    // `docker run -p 9000:9000 -e MINIO_ACCESS_KEY=minio -e MINIO_SECRET_KEY=minio123 minio/minio server /data`
+    #[test]
+    #[ignore]
+    fn test_against_local_s3() {
+        let config = S3Config {
+            bucket: "scrappybot-test".to_string(),
+            prefix: "integration/".to_string(),
+            snapshot_prefix: "test_snapshot".to_string(),
+            region: default_region(),
+            endpoint: Some("http://localhost:9000".to_string()),
+            access_key: Some("minio".to_string()),
+            secret_key: Some("minio123".to_string()),
+        };
+        let storage = S3Storage::new(&config, config.snapshot_prefix.clone()).unwrap();
+
+        tokio_test::block_on(async {
+            for name in storage.list().await.unwrap() {
+                storage.remove(&name).await.unwrap();
+            }
+            let first = storage.write_next(b"first".to_vec()).await.unwrap();
+            let second = storage.write_next(b"second".to_vec()).await.unwrap();
+            assert_eq!(first, "test_snapshot_0");
+            assert_eq!(second, "test_snapshot_1");
+            assert_eq!(storage.list().await.unwrap(), vec![first, second.clone()]);
+            assert_eq!(storage.read(&second).await.unwrap(), b"second".to_vec());
+        });
+    }
+}
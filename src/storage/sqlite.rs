@@ -0,0 +1,585 @@
+use super::BlobStorage;
+use crate::history::{HistoryItem, ListingHistory, ListingTimeline, ListingVersion};
+use crate::runlog::RunRecord;
+use anyhow::{bail, Context, Result};
+use async_trait::async_trait;
+use rusqlite::{params, Connection, OpenFlags, OptionalExtension, NO_PARAMS};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use tokio::task;
+
+/// Every schema change is a new entry, applied in order on open. The
+/// number of applied entries is kept in `PRAGMA user_version`.
+const MIGRATIONS: &[&str] = &[
+    "CREATE TABLE blobs (
+        series TEXT NOT NULL,
+        seq INTEGER NOT NULL,
+        data BLOB NOT NULL,
+        created_at INTEGER NOT NULL,
+        PRIMARY KEY (series, seq)
+    );",
+    "CREATE TABLE listings (
+        id TEXT PRIMARY KEY,
+        first_seen INTEGER NOT NULL,
+        last_seen INTEGER NOT NULL,
+        active INTEGER NOT NULL
+    );
+    CREATE TABLE versions (
+        listing_id TEXT NOT NULL REFERENCES listings(id),
+        seen_at INTEGER NOT NULL,
+        checksum INTEGER NOT NULL,
+        price INTEGER,
+        fields TEXT NOT NULL
+    );
+    CREATE INDEX versions_listing ON versions (listing_id, seen_at);
+    CREATE TABLE listing_events (
+        listing_id TEXT NOT NULL REFERENCES listings(id),
+        kind TEXT NOT NULL,
+        at INTEGER NOT NULL
+    );
+    CREATE INDEX listing_events_listing ON listing_events (listing_id, at);
+    CREATE TABLE runs (
+        source TEXT NOT NULL,
+        started_at INTEGER NOT NULL,
+        finished_at INTEGER NOT NULL,
+        duration_ms INTEGER NOT NULL,
+        added INTEGER NOT NULL,
+        changed INTEGER NOT NULL,
+        removed INTEGER NOT NULL,
+        error TEXT
+    );
+    CREATE TABLE notifications (
+        channel TEXT NOT NULL,
+        listing_id TEXT NOT NULL,
+        checksum INTEGER NOT NULL,
+        sent_at INTEGER NOT NULL
+    );
+    CREATE INDEX notifications_listing ON notifications (listing_id);",
+];
+
+const REMOVED: &'static str = "removed";
+const RELISTED: &'static str = "relisted";
+
+fn schema_version(connection: &Connection) -> Result<usize> {
+    let version: i64 = connection.query_row("PRAGMA user_version", NO_PARAMS, |row| row.get(0))?;
+    Ok(version as usize)
+}
+
+fn migrate(connection: &mut Connection) -> Result<()> {
+    let version = schema_version(connection)?;
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        let tx = connection.transaction()?;
+        tx.execute_batch(migration)
+            .with_context(|| format!("migration {} failed", i + 1))?;
+        tx.pragma_update(None, "user_version", &((i + 1) as i64))?;
+        tx.commit()?;
+    }
+    Ok(())
+}
+
+/// SQLite database holding blob series as well as queryable tables for
+/// listings, their versions, runs and sent notifications. Clones share the
+/// connection, which stays open until the one who opened it drops the last.
+#[derive(Clone)]
+pub struct SqliteDatabase {
+    connection: Arc<Mutex<Connection>>,
+}
+
+impl SqliteDatabase {
+    /// Opens the database at `path`, creating and migrating it as needed.
+    pub async fn open(path: &str) -> Result<Self> {
+        let path = path.to_string();
+        let connection = task::spawn_blocking(move || -> Result<Connection> {
+            let mut connection =
+                Connection::open(&path).with_context(|| format!("failed to open {}", path))?;
+            migrate(&mut connection)?;
+            Ok(connection)
+        })
+        .await??;
+        Ok(SqliteDatabase::from_connection(connection))
+    }
+
+    /// Opens the database at `path` without changing it. A missing one reads
+    /// as empty, a database the current schema needs migrating is refused.
+    pub async fn open_read_only(path: &str) -> Result<Self> {
+        let path = path.to_string();
+        let connection = task::spawn_blocking(move || -> Result<Connection> {
+            if !Path::new(&path).exists() {
+                let mut connection = Connection::open_in_memory()?;
+                migrate(&mut connection)?;
+                return Ok(connection);
+            }
+            let connection = Connection::open_with_flags(&path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+                .with_context(|| format!("failed to open {}", path))?;
+            if schema_version(&connection)? < MIGRATIONS.len() {
+                bail!("{} needs migrating, run once without --dry-run", path);
+            }
+            Ok(connection)
+        })
+        .await??;
+        Ok(SqliteDatabase::from_connection(connection))
+    }
+
+    #[cfg(test)]
+    fn in_memory() -> Result<Self> {
+        let mut connection = Connection::open_in_memory()?;
+        migrate(&mut connection)?;
+        Ok(SqliteDatabase::from_connection(connection))
+    }
+
+    fn from_connection(connection: Connection) -> Self {
+        SqliteDatabase {
+            connection: Arc::new(Mutex::new(connection)),
+        }
+    }
+
+    /// Runs `query` on the connection in the blocking pool, so the async
+    /// runtime isn't held up by disk IO.
+    async fn with_connection<F, R>(&self, query: F) -> Result<R>
+    where
+        F: FnOnce(&mut Connection) -> Result<R> + Send + 'static,
+        R: Send + 'static,
+    {
+        let connection = self.connection.clone();
+        task::spawn_blocking(move || {
+            let mut connection = connection.lock().unwrap();
+            query(&mut *connection)
+        })
+        .await?
+    }
+
+    pub fn blobs(&self, series: &str) -> SqliteStorage {
+        SqliteStorage {
+            database: self.clone(),
+            series: series.to_string(),
+        }
+    }
+
+    /// Records the full result of one scrape, same as `ListingHistory::record`.
+    pub async fn record_scrape<T: HistoryItem>(&self, items: &[T], seen_at: u64) -> Result<()> {
+        let mut rows = Vec::new();
+        for item in items.iter() {
+            let (id, checksum) = item.id_checksum();
+            let fields = serde_json::to_string(&item.fields())?;
+            rows.push((id, checksum as i64, item.price().map(|p| p as i64), fields));
+        }
+        let seen_at = seen_at as i64;
+
+        self.with_connection(move |connection| {
+            let tx = connection.transaction()?;
+            tx.execute("CREATE TEMP TABLE IF NOT EXISTS seen (id TEXT PRIMARY KEY)", NO_PARAMS)?;
+            tx.execute("DELETE FROM seen", NO_PARAMS)?;
+
+            for (id, checksum, price, fields) in rows.iter() {
+                tx.execute("INSERT OR IGNORE INTO seen (id) VALUES (?1)", params![id])?;
+
+                let active: Option<bool> = tx
+                    .query_row(
+                        "SELECT active FROM listings WHERE id = ?1",
+                        params![id],
+                        |row| row.get(0),
+                    )
+                    .optional()?;
+                match active {
+                    None => {
+                        tx.execute(
+                            "INSERT INTO listings (id, first_seen, last_seen, active)
+                             VALUES (?1, ?2, ?2, 1)",
+                            params![id, seen_at],
+                        )?;
+                    }
+                    Some(active) => {
+                        tx.execute(
+                            "UPDATE listings SET last_seen = ?2, active = 1 WHERE id = ?1",
+                            params![id, seen_at],
+                        )?;
+                        if !active {
+                            tx.execute(
+                                "INSERT INTO listing_events (listing_id, kind, at)
+                                 VALUES (?1, ?2, ?3)",
+                                params![id, RELISTED, seen_at],
+                            )?;
+                        }
+                    }
+                }
+
+                let latest: Option<i64> = tx
+                    .query_row(
+                        "SELECT checksum FROM versions WHERE listing_id = ?1
+                         ORDER BY seen_at DESC, rowid DESC LIMIT 1",
+                        params![id],
+                        |row| row.get(0),
+                    )
+                    .optional()?;
+                if latest != Some(*checksum) {
+                    tx.execute(
+                        "INSERT INTO versions (listing_id, seen_at, checksum, price, fields)
+                         VALUES (?1, ?2, ?3, ?4, ?5)",
+                        params![id, seen_at, checksum, price, fields],
+                    )?;
+                }
+            }
+
+            tx.execute(
+                "INSERT INTO listing_events (listing_id, kind, at)
+                 SELECT id, ?1, ?2 FROM listings
+                 WHERE active = 1 AND id NOT IN (SELECT id FROM seen)",
+                params![REMOVED, seen_at],
+            )?;
+            tx.execute(
+                "UPDATE listings SET active = 0 WHERE active = 1 AND id NOT IN (SELECT id FROM seen)",
+                NO_PARAMS,
+            )?;
+            tx.commit()?;
+            Ok(())
+        })
+        .await
+    }
+
+    pub async fn record_run(&self, run: &RunRecord) -> Result<()> {
+        let run = run.clone();
+        self.with_connection(move |connection| {
+            connection.execute(
+                "INSERT INTO runs
+                 (source, started_at, finished_at, duration_ms, added, changed, removed, error)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+                params![
+                    run.source,
+                    run.started_at as i64,
+                    run.finished_at as i64,
+                    run.duration_ms as i64,
+                    run.added as i64,
+                    run.changed as i64,
+                    run.removed as i64,
+                    run.error
+                ],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    pub async fn record_notification(
+        &self,
+        channel: &str,
+        listing_id: &str,
+        checksum: u64,
+        sent_at: u64,
+    ) -> Result<()> {
+        let channel = channel.to_string();
+        let listing_id = listing_id.to_string();
+        self.with_connection(move |connection| {
+            connection.execute(
+                "INSERT INTO notifications (channel, listing_id, checksum, sent_at)
+                 VALUES (?1, ?2, ?3, ?4)",
+                params![channel, listing_id, checksum as i64, sent_at as i64],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// The listing history as `ListingHistory` keeps it, built from the
+    /// tables. Listings without a version are left out.
+    pub async fn history(&self) -> Result<ListingHistory> {
+        self.with_connection(|connection| {
+            let mut history = ListingHistory::default();
+            let mut statement = connection.prepare(
+                "SELECT l.id, l.first_seen, l.last_seen, l.active,
+                        v.seen_at, v.checksum, v.price, v.fields
+                 FROM listings l JOIN versions v ON v.listing_id = l.id
+                 ORDER BY v.seen_at, v.rowid",
+            )?;
+            let rows = statement.query_map(NO_PARAMS, |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, i64>(1)?,
+                    row.get::<_, i64>(2)?,
+                    row.get::<_, bool>(3)?,
+                    row.get::<_, i64>(4)?,
+                    row.get::<_, i64>(5)?,
+                    row.get::<_, Option<i64>>(6)?,
+                    row.get::<_, String>(7)?,
+                ))
+            })?;
+            for row in rows {
+                let (id, first_seen, last_seen, active, seen_at, checksum, price, fields) = row?;
+                history.recorded_until = history.recorded_until.max(last_seen as u64);
+                let timeline = history
+                    .listings
+                    .entry(id)
+                    .or_insert_with(|| ListingTimeline {
+                        first_seen: first_seen as u64,
+                        last_seen: last_seen as u64,
+                        active: active,
+                        versions: Vec::new(),
+                        removed_at: Vec::new(),
+                        relisted_at: Vec::new(),
+                    });
+                timeline.versions.push(ListingVersion {
+                    seen_at: seen_at as u64,
+                    checksum: checksum as u64,
+                    price: price.map(|p| p as u64),
+                    fields: serde_json::from_str(&fields)?,
+                });
+            }
+
+            let mut statement = connection
+                .prepare("SELECT listing_id, kind, at FROM listing_events ORDER BY at, rowid")?;
+            let rows = statement.query_map(NO_PARAMS, |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, i64>(2)?,
+                ))
+            })?;
+            for row in rows {
+                let (id, kind, at) = row?;
+                if let Some(timeline) = history.listings.get_mut(&id) {
+                    match kind.as_str() {
+                        REMOVED => timeline.removed_at.push(at as u64),
+                        _ => timeline.relisted_at.push(at as u64),
+                    }
+                }
+            }
+            Ok(history)
+        })
+        .await
+    }
+
+    pub async fn recent_runs(&self, limit: u32) -> Result<Vec<RunRecord>> {
+        self.with_connection(move |connection| {
+            let mut statement = connection.prepare(
+                "SELECT source, started_at, finished_at, duration_ms, added, changed, removed, error
+                 FROM runs ORDER BY started_at DESC LIMIT ?1",
+            )?;
+            let rows = statement.query_map(params![limit], |row| {
+                Ok(RunRecord {
+                    source: row.get(0)?,
+                    started_at: row.get::<_, i64>(1)? as u64,
+                    finished_at: row.get::<_, i64>(2)? as u64,
+                    duration_ms: row.get::<_, i64>(3)? as u64,
+                    added: row.get::<_, i64>(4)? as usize,
+                    changed: row.get::<_, i64>(5)? as usize,
+                    removed: row.get::<_, i64>(6)? as usize,
+                    error: row.get(7)?,
+                })
+            })?;
+            let mut runs = Vec::new();
+            for row in rows {
+                runs.push(row?);
+            }
+            Ok(runs)
+        })
+        .await
+    }
+}
+
+/// One blob series stored in the `blobs` table.
+pub struct SqliteStorage {
+    database: SqliteDatabase,
+    series: String,
+}
+
+impl SqliteStorage {
+    fn name(&self, seq: i64) -> String {
+        format!("{}_{}", self.series, seq)
+    }
+
+    fn parse_seq(&self, name: &str) -> Result<i64> {
+        name.strip_prefix(&format!("{}_", self.series))
+            .and_then(|seq| seq.parse().ok())
+            .with_context(|| format!("{} doesn't belong to {}", name, self.series))
+    }
+}
+
+#[async_trait]
+impl BlobStorage for SqliteStorage {
+    async fn list(&self) -> Result<Vec<String>> {
+        let series = self.series.clone();
+        let seqs = self
+            .database
+            .with_connection(move |connection| {
+                let mut statement =
+                    connection.prepare("SELECT seq FROM blobs WHERE series = ?1 ORDER BY seq")?;
+                let rows = statement.query_map(params![series], |row| row.get::<_, i64>(0))?;
+                let mut seqs = Vec::new();
+                for seq in rows {
+                    seqs.push(seq?);
+                }
+                Ok(seqs)
+            })
+            .await?;
+        Ok(seqs.into_iter().map(|seq| self.name(seq)).collect())
+    }
+
+    async fn read(&self, name: &str) -> Result<Vec<u8>> {
+        let seq = self.parse_seq(name)?;
+        let series = self.series.clone();
+        let data = self
+            .database
+            .with_connection(move |connection| {
+                let data = connection.query_row(
+                    "SELECT data FROM blobs WHERE series = ?1 AND seq = ?2",
+                    params![series, seq],
+                    |row| row.get(0),
+                )?;
+                Ok(data)
+            })
+            .await
+            .with_context(|| format!("failed to read {}", name))?;
+        Ok(data)
+    }
+
+    async fn write_next(&self, data: Vec<u8>) -> Result<String> {
+        let series = self.series.clone();
+        let seq = self
+            .database
+            .with_connection(move |connection| {
+                let tx = connection.transaction()?;
+                let seq: i64 = tx.query_row(
+                    "SELECT COALESCE(MAX(seq) + 1, 0) FROM blobs WHERE series = ?1",
+                    params![series],
+                    |row| row.get(0),
+                )?;
+                tx.execute(
+                    "INSERT INTO blobs (series, seq, data, created_at)
+                     VALUES (?1, ?2, ?3, strftime('%s','now'))",
+                    params![series, seq, data],
+                )?;
+                tx.commit()?;
+                Ok(seq)
+            })
+            .await?;
+        Ok(self.name(seq))
+    }
+
+    async fn remove(&self, name: &str) -> Result<()> {
+        let seq = self.parse_seq(name)?;
+        let series = self.series.clone();
+        self.database
+            .with_connection(move |connection| {
+                connection.execute(
+                    "DELETE FROM blobs WHERE series = ?1 AND seq = ?2",
+                    params![series, seq],
+                )?;
+                Ok(())
+            })
+            .await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::state::IdChecksum;
+
+    struct Item(&'static str, u64);
+
+    impl IdChecksum for Item {
+        fn id_checksum(&self) -> (String, u64) {
+            (self.0.to_string(), self.1)
+        }
+    }
+
+    impl HistoryItem for Item {
+        fn fields(&self) -> Vec<String> {
+            vec![self.0.to_string()]
+        }
+
+        fn price(&self) -> Option<u64> {
+            Some(self.1)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_blob_series() {
+        let database = SqliteDatabase::in_memory().unwrap();
+        let snapshots = database.blobs("snapshots");
+        let runs = database.blobs("runs");
+
+        assert_eq!(
+            snapshots.write_next(b"first".to_vec()).await.unwrap(),
+            "snapshots_0"
+        );
+        assert_eq!(
+            snapshots.write_next(b"second".to_vec()).await.unwrap(),
+            "snapshots_1"
+        );
+        assert_eq!(runs.write_next(b"run".to_vec()).await.unwrap(), "runs_0");
+
+        snapshots.remove("snapshots_0").await.unwrap();
+        assert_eq!(snapshots.list().await.unwrap(), vec!["snapshots_1"]);
+        assert_eq!(
+            snapshots.read("snapshots_1").await.unwrap(),
+            b"second".to_vec()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_record_scrape() {
+        let database = SqliteDatabase::in_memory().unwrap();
+        database
+            .record_scrape(&[Item("a", 100), Item("b", 5)], 1)
+            .await
+            .unwrap();
+        database.record_scrape(&[Item("a", 100)], 2).await.unwrap();
+        database
+            .record_scrape(&[Item("a", 90), Item("b", 5)], 3)
+            .await
+            .unwrap();
+
+        let history = database.history().await.unwrap();
+        let prices: Vec<Option<u64>> = history
+            .timeline("a")
+            .unwrap()
+            .versions
+            .iter()
+            .map(|v| v.price)
+            .collect();
+        assert_eq!(prices, vec![Some(100), Some(90)]);
+        let b = history.timeline("b").unwrap();
+        assert!(b.active);
+        assert_eq!(b.versions.len(), 1);
+        assert_eq!(b.removed_at, vec![2]);
+        assert_eq!(b.relisted_at, vec![3]);
+        assert_eq!(history.recorded_until, 3);
+    }
+
+    #[test]
+    fn test_migrations_are_applied_once() {
+        let database = SqliteDatabase::in_memory().unwrap();
+        let mut connection = database.connection.lock().unwrap();
+        migrate(&mut connection).unwrap();
+        assert_eq!(schema_version(&connection).unwrap(), MIGRATIONS.len());
+    }
+
+    #[tokio::test]
+    async fn test_read_only_leaves_the_file_alone() {
+        let path = std::env::temp_dir().join(format!("sqlite_read_only_{}.db", std::process::id()));
+        let path = path.to_str().unwrap();
+        let _ = std::fs::remove_file(path);
+
+        let missing = SqliteDatabase::open_read_only(path).await.unwrap();
+        assert!(missing.blobs("snapshots").list().await.unwrap().is_empty());
+        assert!(!Path::new(path).exists());
+
+        let database = SqliteDatabase::open(path).await.unwrap();
+        database
+            .blobs("snapshots")
+            .write_next(b"a".to_vec())
+            .await
+            .unwrap();
+        let read_only = SqliteDatabase::open_read_only(path).await.unwrap();
+        assert_eq!(
+            read_only.blobs("snapshots").list().await.unwrap(),
+            vec!["snapshots_0"]
+        );
+        assert!(read_only
+            .blobs("snapshots")
+            .write_next(b"b".to_vec())
+            .await
+            .is_err());
+        std::fs::remove_file(path).unwrap();
+    }
+}